@@ -0,0 +1,162 @@
+//! Compares the throughput and CPU time of relaying with buffers and with splice(2), through
+//! the tunnel server of a release build pushing bytes from local clients to a local sink.
+//!
+//! Run with `cargo bench --bench relay`, and set `BUBBLE_BENCH_MB` to change how many megabytes
+//! are relayed in each round, 2048 by default.
+
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const CHUNK: usize = 256 * 1024;
+
+fn main() {
+    if !cfg!(target_os = "linux") {
+        println!("splice is only supported on Linux");
+        return;
+    }
+
+    let mb = env::var("BUBBLE_BENCH_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2048u64);
+
+    println!("relaying {mb} MiB per round");
+    for streams in [1, 4] {
+        for splice in [false, true] {
+            let (secs, cpu) = round(splice, streams, mb << 20).expect("round");
+            println!(
+                "{:>8}, {streams} stream(s): {:>8.0} MiB/s, {:>6.3} CPU seconds per GiB",
+                if splice { "splice" } else { "buffered" },
+                mb as f64 / secs,
+                cpu / (mb as f64 / 1024.0),
+            );
+        }
+    }
+}
+
+/// Relays `bytes` over `streams` connections, returning the seconds it took and the CPU seconds
+/// the proxy spent.
+fn round(splice: bool, streams: usize, bytes: u64) -> io::Result<(f64, f64)> {
+    let sink = TcpListener::bind("127.0.0.1:0")?;
+    let sink_addr = sink.local_addr()?;
+    let sinks = thread::spawn(move || -> io::Result<u64> {
+        let mut handles = Vec::new();
+        for _ in 0..streams {
+            let (mut s, _) = sink.accept()?;
+            handles.push(thread::spawn(move || io::copy(&mut s, &mut io::sink())));
+        }
+        handles.into_iter().map(|h| h.join().unwrap()).sum()
+    });
+
+    let proxy = Proxy::start(splice)?;
+    let cpu = proxy.cpu()?;
+    let start = Instant::now();
+
+    let clients = (0..streams)
+        .map(|_| {
+            let addr = proxy.addr;
+            thread::spawn(move || send(addr, sink_addr, bytes / streams as u64))
+        })
+        .collect::<Vec<_>>();
+    for c in clients {
+        c.join().unwrap()?;
+    }
+
+    let received = sinks.join().unwrap()?;
+    let secs = start.elapsed().as_secs_f64();
+    let cpu = proxy.cpu()? - cpu;
+
+    assert_eq!(received, bytes / streams as u64 * streams as u64);
+    Ok((secs, cpu))
+}
+
+/// Sends `bytes` to `dst` through the tunnel server at `proxy`, returning once it's all been
+/// relayed.
+fn send(proxy: SocketAddr, dst: SocketAddr, mut bytes: u64) -> io::Result<()> {
+    let mut s = TcpStream::connect(proxy)?;
+    let dst = dst.to_string();
+    s.write_all(&(dst.len() as u16).to_be_bytes())?;
+    s.write_all(dst.as_bytes())?;
+
+    let buf = vec![0; CHUNK];
+    while bytes > 0 {
+        let n = bytes.min(CHUNK as u64) as usize;
+        s.write_all(&buf[..n])?;
+        bytes -= n as u64;
+    }
+
+    // The tunnel server closes its side once the sink has read everything
+    s.shutdown(Shutdown::Write)?;
+    s.read_to_end(&mut Vec::new())?;
+    Ok(())
+}
+
+/// A tunnel server in a child process.
+struct Proxy {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl Proxy {
+    fn start(splice: bool) -> io::Result<Self> {
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_bubble"));
+        cmd.args(["--tunnel", "--tunnel-ip", "127.0.0.1", "--tunnel-port"])
+            .arg(addr.port().to_string())
+            .arg("--allow-private-dst")
+            .env("RUST_LOG", "error")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if splice {
+            cmd.arg("--splice");
+        }
+
+        let proxy = Proxy {
+            child: cmd.spawn()?,
+            addr,
+        };
+
+        for _ in 0..50 {
+            if TcpStream::connect(addr).is_ok() {
+                return Ok(proxy);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "proxy not listening",
+        ))
+    }
+
+    /// Returns the CPU seconds the proxy has spent so far, in user and kernel mode.
+    fn cpu(&self) -> io::Result<f64> {
+        // https://man7.org/linux/man-pages/man5/proc.5.html, utime and stime are the 14th and
+        // 15th fields, counted in clock ticks
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.child.id()))?;
+        let fields = stat[stat.rfind(')').unwrap() + 2..]
+            .split(' ')
+            .collect::<Vec<_>>();
+        let ticks = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+
+        #[cfg(target_os = "linux")]
+        let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+        #[cfg(not(target_os = "linux"))]
+        let hz = 100.0;
+
+        Ok(ticks as f64 / hz)
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use tracing::error;
+
+use crate::{
+    auth,
+    date::{civil, now},
+    hooks,
+    json::quote,
+    proxy::dial::{self, Client},
+};
+
+/// How each record is written.
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    /// One JSON object per line.
+    Json,
+    /// The Common Log Format, followed by the fields it has no place for, i.e.
+    /// `client - user [time] "command target protocol" status bytes_down "route" "ip" bytes_up duration close`.
+    Combined,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "combined" => Ok(Format::Combined),
+            _ => bail!("invalid format: {s}, expected json or combined"),
+        }
+    }
+}
+
+/// How often the log file is rotated, regardless of its size.
+#[derive(Clone, Copy, Debug)]
+pub enum Rotate {
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotate {
+    /// The number of the period `secs` falls in, in UTC.
+    fn period(self, secs: u64) -> u64 {
+        match self {
+            Rotate::Never => 0,
+            Rotate::Hourly => secs / 3600,
+            Rotate::Daily => secs / 86400,
+        }
+    }
+}
+
+impl FromStr for Rotate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Rotate::Never),
+            "hourly" => Ok(Rotate::Hourly),
+            "daily" => Ok(Rotate::Daily),
+            _ => bail!("invalid rotation: {s}, expected never, hourly or daily"),
+        }
+    }
+}
+
+/// An access log, written to by the servers it's set on.
+pub struct AccessLog(Mutex<Log>);
+
+struct Log {
+    path: PathBuf,
+    format: Format,
+    /// Rotate once the file grows past this many bytes, unless 0.
+    max_size: u64,
+    rotate: Rotate,
+    /// The number of rotated files to keep, named `<path>.1` (the newest) to `<path>.<keep>`.
+    keep: usize,
+    file: File,
+    size: u64,
+    period: u64,
+}
+
+/// Where and how to write the access log.
+#[derive(Clone, Debug)]
+pub struct AccessLogOptions {
+    pub path: PathBuf,
+    pub format: Format,
+    /// Rotate once the file grows past this many bytes, unless 0.
+    pub max_size: u64,
+    pub rotate: Rotate,
+    /// The number of rotated files to keep.
+    pub keep: usize,
+}
+
+impl AccessLogOptions {
+    /// Returns the options of a json log at `path`, rotated daily or past 100 MB, keeping 7
+    /// rotated files.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AccessLogOptions {
+            path: path.into(),
+            format: Format::Json,
+            max_size: 100 * 1024 * 1024,
+            rotate: Rotate::Daily,
+            keep: 7,
+        }
+    }
+}
+
+impl AccessLog {
+    pub fn open(c: &AccessLogOptions) -> anyhow::Result<Self> {
+        let (file, size, modified) = open(&c.path)?;
+        let mut log = Log {
+            path: c.path.clone(),
+            format: c.format,
+            max_size: c.max_size,
+            rotate: c.rotate,
+            keep: c.keep,
+            file,
+            size,
+            period: c.rotate.period(modified),
+        };
+
+        // The file may be left over from an earlier period
+        if log.period != log.rotate.period(now()) {
+            log.rotate_files().context("open: rotate")?;
+        }
+
+        Ok(AccessLog(Mutex::new(log)))
+    }
+}
+
+/// Logs what happened to the connection of `client`, or one of its requests, to the access log
+/// of its server, if it has one. `command` is the method of http requests and `CONNECT`
+/// otherwise, `status` an http status code, which the other protocols map their outcomes to,
+/// see [`status`], and `bytes` the number of bytes sent up to and down from the destination.
+///
+/// Every connection and http request ends up here, so this is also where the hooks learn that
+/// they are over.
+pub fn write(client: &Client, command: &str, status: u16, bytes: (u64, u64)) {
+    hooks::closed(client, status, bytes);
+
+    let Some(AccessLog(log)) = client.settings.access_log.as_deref() else {
+        return;
+    };
+
+    let now = SystemTime::now();
+    let mut log = log.lock().unwrap();
+    let line = match log.format {
+        Format::Json => json(client, command, status, bytes, now),
+        Format::Combined => combined(client, command, status, bytes, now),
+    };
+
+    if let Err(e) = log.write(line.as_bytes()) {
+        error!("failed to write the access log: {e:?}");
+    }
+}
+
+/// Maps the outcome of the connection of `client` to an http status code: 200 once connected
+/// to the destination, 407 if it failed to authenticate, 403 if denied, 429 if over the
+/// connection limits, 502 if the destination couldn't be reached and 400 if the client never
+/// asked for one.
+pub fn status<T>(client: &Client, r: &anyhow::Result<T>) -> u16 {
+    match r {
+        Ok(_) => 200,
+        Err(_) if client.remote.is_some() => 200,
+        Err(e) if auth::is_rejected(e) => 407,
+        Err(e) if dial::is_denied(e) => 403,
+        Err(e) if dial::is_limited(e) => 429,
+        Err(e) if dial::is_timed_out(e) && client.target.is_some() => 504,
+        Err(e) if dial::is_timed_out(e) => 408,
+        Err(_) if client.target.is_some() => 502,
+        Err(_) => 400,
+    }
+}
+
+impl Log {
+    fn write(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        let period = self.rotate.period(now());
+        let full =
+            self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_size;
+
+        if period != self.period || full {
+            self.rotate_files().context("write: rotate")?;
+        }
+
+        self.file.write_all(line).context("write: write_all")?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `<path>.N` to `<path>.N+1`, dropping the oldest, and starts a new file.
+    fn rotate_files(&mut self) -> anyhow::Result<()> {
+        let rotated = |n: usize| {
+            let mut p = self.path.clone().into_os_string();
+            p.push(format!(".{n}"));
+            PathBuf::from(p)
+        };
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path).context("rotate_files: remove_file")?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1)).context("rotate_files: rename")?;
+        }
+
+        (self.file, self.size, _) = open(&self.path)?;
+        self.period = self.rotate.period(now());
+        Ok(())
+    }
+}
+
+/// Opens the file at `path` for appending, returning its size and when it was last modified.
+fn open(path: &Path) -> anyhow::Result<(File, u64, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open: {}", path.display()))?;
+
+    let meta = file.metadata().context("open: metadata")?;
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or_else(now, |d| d.as_secs());
+
+    Ok((file, meta.len(), modified))
+}
+
+fn json(client: &Client, command: &str, status: u16, bytes: (u64, u64), now: SystemTime) -> String {
+    let mut s = String::with_capacity(256);
+    let str_or_null = |v: Option<String>| v.map_or("null".to_string(), |v| quote(&v));
+
+    let _ = writeln!(
+        s,
+        "{{\"time\":\"{}\",\"listener\":{},\"client\":\"{}\",\"user\":{},\"protocol\":{},\
+         \"command\":{},\"target\":{},\"ip\":{},\"route\":{},\"rule\":{},\"status\":{},\
+         \"bytes_up\":{},\"bytes_down\":{},\"duration\":{:.3},\"close\":{}}}",
+        rfc3339(now),
+        quote(client.metrics.name),
+        client.addr,
+        str_or_null(client.user.clone()),
+        quote(client.protocol),
+        quote(command),
+        str_or_null(client.target.as_ref().map(|t| t.to_string())),
+        str_or_null(client.remote.map(|a| a.ip().to_string())),
+        str_or_null(client.route.clone()),
+        str_or_null(client.rule.clone()),
+        status,
+        bytes.0,
+        bytes.1,
+        client.accepted.elapsed().as_secs_f64(),
+        str_or_null(client.close.map(|c| c.to_string())),
+    );
+    s
+}
+
+fn combined(
+    client: &Client,
+    command: &str,
+    status: u16,
+    bytes: (u64, u64),
+    now: SystemTime,
+) -> String {
+    let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+
+    format!(
+        "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} {:.3} {}\n",
+        client.addr.ip(),
+        or_dash(client.user.clone()),
+        clf_time(now),
+        command,
+        or_dash(client.target.as_ref().map(|t| t.to_string())),
+        client.protocol,
+        status,
+        bytes.1,
+        or_dash(client.route.clone()),
+        or_dash(client.remote.map(|a| a.ip().to_string())),
+        bytes.0,
+        client.accepted.elapsed().as_secs_f64(),
+        or_dash(client.close.map(|c| c.to_string())),
+    )
+}
+
+/// Formats `t` like `2023-06-01T12:34:56.789Z`.
+fn rfc3339(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day, secs) = civil(d.as_secs());
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        d.subsec_millis()
+    )
+}
+
+/// Formats `t` like `01/Jun/2023:12:34:56 +0000`.
+fn clf_time(t: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day, secs) = civil(d.as_secs());
+    format!(
+        "{day:02}/{}/{year:04}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,47 @@
+use std::net::IpAddr;
+
+use anyhow::Context;
+
+use crate::cidr::{Cidr, CidrSet};
+
+/// Decides which clients may connect to a listener.
+///
+/// A client is denied if its address is in the deny list, or if the allow list is not empty and
+/// its address is not in it.
+#[derive(Default, Debug)]
+pub struct Acl {
+    allow: CidrSet,
+    deny: CidrSet,
+}
+
+impl Acl {
+    pub fn new(
+        allow: &[Cidr],
+        deny: &[Cidr],
+        allow_files: &[String],
+        deny_files: &[String],
+    ) -> anyhow::Result<Self> {
+        let mut acl = Acl {
+            allow: allow.iter().copied().collect(),
+            deny: deny.iter().copied().collect(),
+        };
+
+        for f in allow_files {
+            acl.allow.load(f).context("Acl::new: allow")?;
+        }
+
+        for f in deny_files {
+            acl.deny.load(f).context("Acl::new: deny")?;
+        }
+
+        Ok(acl)
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.contains(ip) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.contains(ip)
+    }
+}
@@ -0,0 +1,170 @@
+use std::{fmt::Write, net::SocketAddr, sync::atomic::Ordering, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{body, header, server, service::service_fn, Method, Request, Response, StatusCode};
+use tokio::{net::TcpListener, time};
+use tracing::{error, info};
+
+use crate::{json::quote, registry, shutdown::Shutdown};
+
+/// The state the admin endpoints serve.
+struct Admin {
+    token: String,
+    /// The configuration the process was started with, as shown to admins.
+    config: String,
+}
+
+/// Starts the admin HTTP server, which requires every request to carry `token` as a bearer
+/// token:
+///
+/// - `GET /connections` lists the connections being served
+/// - `DELETE /connections/<ID>` closes a connection
+/// - `POST /users/<NAME>/disable` keeps a user from connecting and closes its connections
+/// - `POST /users/<NAME>/enable` lets a disabled user connect again
+/// - `GET /config` shows the configuration
+pub async fn start<A>(addr: A, token: String, config: String, mut shutdown: Shutdown)
+where
+    A: Into<SocketAddr>,
+{
+    let l = TcpListener::bind(addr.into())
+        .await
+        .expect("TcpListener::bind");
+    let admin = Arc::new(Admin { token, config });
+
+    loop {
+        let r = tokio::select! {
+            r = l.accept() => r,
+            _ = shutdown.recv() => break,
+        };
+
+        match r {
+            Err(e) => {
+                error!("An error occurred while calling listener.accept: {}", e);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok((s, _)) => {
+                let admin = admin.clone();
+                tokio::spawn(async move {
+                    let r = server::conn::http1::Builder::new()
+                        .serve_connection(s, service_fn(|req| serve(req, &admin)))
+                        .await;
+                    if let Err(e) = r {
+                        error!("Failed to serve connection: {:?}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    info!("admin server stopped accepting new connections");
+}
+
+async fn serve(
+    req: Request<body::Incoming>,
+    admin: &Admin,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    if !authorized(&req, &admin.token) {
+        let mut resp = response(StatusCode::UNAUTHORIZED, "");
+        resp.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            header::HeaderValue::from_static("Bearer"),
+        );
+        return Ok(resp);
+    }
+
+    let path = req.uri().path().trim_end_matches('/');
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+
+    let resp = match (req.method(), &segments[..]) {
+        (&Method::GET, ["connections"]) => json(connections()),
+
+        (&Method::DELETE, ["connections", id]) => match id.parse() {
+            Ok(id) if registry::close(id) => response(StatusCode::NO_CONTENT, ""),
+            _ => response(StatusCode::NOT_FOUND, "no such connection\n"),
+        },
+
+        (&Method::POST, ["users", user, "disable"]) => {
+            let closed = registry::disable_user(user);
+            info!("disabled user {user}, closed {closed} connection(s)");
+            json(format!("{{\"closed\":{closed}}}\n"))
+        }
+
+        (&Method::POST, ["users", user, "enable"]) => {
+            if registry::enable_user(user) {
+                info!("enabled user {user}");
+                response(StatusCode::NO_CONTENT, "")
+            } else {
+                response(StatusCode::NOT_FOUND, "no such disabled user\n")
+            }
+        }
+
+        (&Method::GET, ["config"]) => response(StatusCode::OK, &admin.config),
+
+        (_, ["connections"] | ["connections", _] | ["users", _, _] | ["config"]) => {
+            response(StatusCode::METHOD_NOT_ALLOWED, "")
+        }
+
+        _ => response(StatusCode::NOT_FOUND, ""),
+    };
+
+    Ok(resp)
+}
+
+/// Checks the bearer token of `req` without giving away how much of it matched.
+fn authorized(req: &Request<body::Incoming>, token: &str) -> bool {
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn connections() -> String {
+    let mut s = String::from("[");
+
+    for (i, c) in registry::list().iter().enumerate() {
+        let info = c.info();
+        let _ = write!(
+            s,
+            "{}\n  {{\"id\":{},\"listener\":{},\"protocol\":{},\"client\":\"{}\",\"user\":{},\
+             \"target\":{},\"age\":{:.3},\"bytes_up\":{},\"bytes_down\":{}}}",
+            if i == 0 { "" } else { "," },
+            c.id,
+            quote(c.listener),
+            quote(info.protocol),
+            c.addr,
+            info.user.as_deref().map_or("null".to_string(), quote),
+            info.target.as_deref().map_or("null".to_string(), quote),
+            c.started.elapsed().as_secs_f64(),
+            c.up.load(Ordering::Relaxed),
+            c.down.load(Ordering::Relaxed),
+        );
+    }
+
+    s.push_str("\n]\n");
+    s
+}
+
+fn json(body: String) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    resp
+}
+
+fn response(status: StatusCode, body: &str) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::copy_from_slice(body.as_bytes())));
+    *resp.status_mut() = status;
+    resp
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashMap, error::Error, fmt, net::SocketAddr, str::FromStr};
+
+use anyhow::anyhow;
+use tracing::warn;
+
+use crate::{hooks, proxy::dial::Client, BoxFuture};
+
+mod command;
+mod htpasswd;
+mod md5;
+
+pub use command::Command;
+pub use htpasswd::Htpasswd;
+
+/// Decides which clients may use the proxy servers, and as which users.
+///
+/// Socks5 clients present their credentials with the username/password method, see
+/// https://www.rfc-editor.org/rfc/rfc1929, http clients in the Proxy-Authorization header of
+/// each request, see https://www.rfc-editor.org/rfc/rfc7617, and tunnel clients ahead of the
+/// address in their handshake.
+pub trait Authenticator: Send + Sync {
+    /// Returns the identity of the user the client at `addr` authenticates as with
+    /// `credentials`, which the routing rules, limits, quotas and logs refer to, or why it may
+    /// not.
+    fn authenticate<'a>(
+        &'a self,
+        addr: SocketAddr,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<String, Rejected>>;
+}
+
+/// A user name and password, parsed from `<USER>:<PASSWORD>`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for Credentials {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (username, password) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid credentials, expected <USER>:<PASSWORD>"))?;
+
+        Ok(Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+/// Why a client was refused.
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "authentication failed: {}", self.0)
+    }
+}
+
+impl Error for Rejected {}
+
+/// Users and their passwords, known in advance.
+pub struct StaticUsers {
+    users: HashMap<String, String>,
+}
+
+impl StaticUsers {
+    pub fn new(users: impl IntoIterator<Item = Credentials>) -> Self {
+        StaticUsers {
+            users: users
+                .into_iter()
+                .map(|c| (c.username, c.password))
+                .collect(),
+        }
+    }
+}
+
+impl Authenticator for StaticUsers {
+    fn authenticate<'a>(
+        &'a self,
+        _addr: SocketAddr,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<String, Rejected>> {
+        Box::pin(async move {
+            match self.users.get(&credentials.username) {
+                Some(p) if eq(p.as_bytes(), credentials.password.as_bytes()) => {
+                    Ok(credentials.username.clone())
+                }
+                _ => Err(Rejected("invalid user name or password".to_string())),
+            }
+        })
+    }
+}
+
+/// Authenticates `client` with `credentials`, accounting the connection to the user it turns
+/// out to be. Failures are counted and come with a [`Rejected`] error.
+pub async fn check(
+    auth: &dyn Authenticator,
+    client: &mut Client,
+    credentials: &Credentials,
+) -> anyhow::Result<()> {
+    match auth.authenticate(client.addr, credentials).await {
+        Ok(user) => {
+            client.set_user(Some(user));
+            hooks::authenticated(client);
+            Ok(())
+        }
+        Err(e) => {
+            client.metrics.auth_failures.inc();
+            warn!("{} - {e}, user: {}", client.addr, credentials.username);
+            Err(e.into())
+        }
+    }
+}
+
+/// Returns a [`Rejected`] error for clients that didn't present credentials at all.
+pub fn missing(client: &Client) -> anyhow::Error {
+    client.metrics.auth_failures.inc();
+    Rejected("no credentials".to_string()).into()
+}
+
+/// Returns whether `e` was caused by a failed authentication.
+pub fn is_rejected(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Rejected>().is_some()
+}
+
+/// Compares `a` and `b` in a time that doesn't depend on where they differ, so as not to reveal
+/// how much of a password was guessed right.
+fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
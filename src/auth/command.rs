@@ -0,0 +1,74 @@
+use std::{net::SocketAddr, process::Stdio};
+
+use tokio::{process, sync::Semaphore};
+use tracing::error;
+
+use super::{Authenticator, BoxFuture, Credentials, Rejected};
+
+/// Authenticates clients by running a command with `sh -c`, given the user name, password and
+/// client address in the `BUBBLE_USER`, `BUBBLE_PASSWORD` and `BUBBLE_CLIENT` environment
+/// variables rather than arguments, which other users could see.
+///
+/// Clients are accepted if it exits with 0, as the user it prints on the first line of its
+/// output, or the one they claimed if it prints nothing.
+///
+/// At most `MAX_RUNNING` run at once, the rest of the clients wait their turn.
+pub struct Command {
+    command: String,
+    running: Semaphore,
+}
+
+/// The maximum number of commands running at once, so that a flood of handshakes can't fork
+/// a process each.
+const MAX_RUNNING: usize = 16;
+
+impl Command {
+    pub fn new(command: String) -> Self {
+        Command {
+            command,
+            running: Semaphore::new(MAX_RUNNING),
+        }
+    }
+}
+
+impl Authenticator for Command {
+    fn authenticate<'a>(
+        &'a self,
+        addr: SocketAddr,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<String, Rejected>> {
+        Box::pin(async move {
+            // Never closed
+            let _permit = self.running.acquire().await.unwrap();
+
+            let output = process::Command::new("sh")
+                .arg("-c")
+                .arg(&self.command)
+                .env("BUBBLE_USER", &credentials.username)
+                .env("BUBBLE_PASSWORD", &credentials.password)
+                .env("BUBBLE_CLIENT", addr.to_string())
+                .stdin(Stdio::null())
+                .stderr(Stdio::inherit())
+                .kill_on_drop(true)
+                .output()
+                .await
+                .map_err(|e| {
+                    error!("failed to run the auth command: {e}");
+                    Rejected("auth command failed".to_string())
+                })?;
+
+            if !output.status.success() {
+                return Err(Rejected(format!(
+                    "auth command exited with {}",
+                    output.status
+                )));
+            }
+
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            match stdout.lines().next().map(str::trim) {
+                Some(user) if !user.is_empty() => Ok(user.to_string()),
+                _ => Ok(credentials.username.clone()),
+            }
+        })
+    }
+}
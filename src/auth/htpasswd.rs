@@ -0,0 +1,224 @@
+use std::{collections::HashMap, fs, net::SocketAddr, path::Path};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::digest;
+use tracing::{info, warn};
+
+use super::{eq, md5, Authenticator, BoxFuture, Credentials, Rejected};
+
+// https://httpd.apache.org/docs/2.4/misc/password_encryptions.html
+
+/// The users in an htpasswd file, read once.
+///
+/// Passwords hashed with MD5 (`$apr1$` and `$1$`, the default of htpasswd), SHA-1 (`{SHA}`) or
+/// not at all are supported, the users with others, e.g. bcrypt, are skipped.
+pub struct Htpasswd {
+    users: HashMap<String, Hash>,
+}
+
+enum Hash {
+    /// The magic, e.g. `$apr1$`, the salt and the whole hash.
+    Md5(&'static str, String, String),
+    Sha1(Vec<u8>),
+    Plain(String),
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Htpasswd::load: {}", path.display()))?;
+
+        let mut users = HashMap::new();
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((user, hash)) = line.split_once(':') else {
+                warn!(
+                    "{}:{} - invalid line, expected <USER>:<HASH>",
+                    path.display(),
+                    n + 1
+                );
+                continue;
+            };
+
+            match Hash::parse(hash) {
+                Some(h) => {
+                    users.insert(user.to_string(), h);
+                }
+                None => warn!(
+                    "{}:{} - unsupported password hash for {user}, skipped",
+                    path.display(),
+                    n + 1
+                ),
+            }
+        }
+
+        info!("loaded {} user(s) from {}", users.len(), path.display());
+        Ok(Htpasswd { users })
+    }
+}
+
+impl Authenticator for Htpasswd {
+    fn authenticate<'a>(
+        &'a self,
+        _addr: SocketAddr,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<String, Rejected>> {
+        Box::pin(async move {
+            match self.users.get(&credentials.username) {
+                Some(h) if h.verify(&credentials.password) => Ok(credentials.username.clone()),
+                _ => Err(Rejected("invalid user name or password".to_string())),
+            }
+        })
+    }
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Option<Self> {
+        for magic in ["$apr1$", "$1$"] {
+            if let Some(rest) = hash.strip_prefix(magic) {
+                let (salt, _) = rest.split_once('$')?;
+                return Some(Hash::Md5(magic, salt.to_string(), hash.to_string()));
+            }
+        }
+
+        if let Some(b64) = hash.strip_prefix("{SHA}") {
+            return STANDARD.decode(b64).ok().map(Hash::Sha1);
+        }
+
+        if hash.starts_with('$') {
+            return None;
+        }
+        Some(Hash::Plain(hash.to_string()))
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Md5(magic, salt, hash) => {
+                eq(md5_crypt(password, magic, salt).as_bytes(), hash.as_bytes())
+            }
+            Hash::Sha1(hash) => {
+                let d = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+                eq(d.as_ref(), hash)
+            }
+            Hash::Plain(p) => eq(p.as_bytes(), password.as_bytes()),
+        }
+    }
+}
+
+/// Hashes `password` like crypt(3) with MD5, which is also what Apache's `$apr1$` does with a
+/// magic of its own, see https://github.com/apache/apr/blob/trunk/crypto/apr_md5.c
+fn md5_crypt(password: &str, magic: &str, salt: &str) -> String {
+    let (pw, salt) = (password.as_bytes(), &salt.as_bytes()[..salt.len().min(8)]);
+
+    let alt = md5::digest(&[pw, salt, pw].concat());
+    let mut ctx = [pw, magic.as_bytes(), salt].concat();
+    for chunk in alt.iter().cycle().take(pw.len()) {
+        ctx.push(*chunk);
+    }
+    let mut i = pw.len();
+    while i > 0 {
+        ctx.push(if i & 1 == 1 {
+            0
+        } else {
+            pw.first().copied().unwrap_or(0)
+        });
+        i >>= 1;
+    }
+    let mut f = md5::digest(&ctx);
+
+    // Slow it down
+    for i in 0..1000 {
+        let mut c = Vec::with_capacity(64);
+        c.extend_from_slice(if i & 1 == 1 { pw } else { &f });
+        if i % 3 != 0 {
+            c.extend_from_slice(salt);
+        }
+        if i % 7 != 0 {
+            c.extend_from_slice(pw);
+        }
+        c.extend_from_slice(if i & 1 == 1 { &f } else { pw });
+        f = md5::digest(&c);
+    }
+
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut out = format!("{magic}{}$", String::from_utf8_lossy(salt));
+    let mut to64 = |mut v: u32, n: usize| {
+        for _ in 0..n {
+            out.push(ITOA64[(v & 0x3f) as usize] as char);
+            v >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        to64((f[a] as u32) << 16 | (f[b] as u32) << 8 | f[c] as u32, 4);
+    }
+    to64(f[11] as u32, 2);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{md5_crypt, Hash};
+
+    #[test]
+    fn md5_crypt_known_answers() {
+        // From `openssl passwd -apr1` and `openssl passwd -1`, the same as `htpasswd -m`
+        let cases = [
+            (
+                "pw1",
+                "$apr1$",
+                "saltsalt",
+                "$apr1$saltsalt$zsSo3wQvAXkIZuoYJGwF11",
+            ),
+            ("pw2", "$1$", "ab", "$1$ab$5HlcSdP/TQDPo188cPRWt0"),
+            ("", "$1$", "abcdefgh", "$1$abcdefgh$M55TzYaaccxVGbptZWaxX/"),
+            (
+                "a very long password that is longer than sixteen bytes",
+                "$apr1$",
+                "x",
+                "$apr1$x$qddZ/ma92FpSKo5A/QYWg/",
+            ),
+        ];
+
+        for (password, magic, salt, expected) in cases {
+            assert_eq!(md5_crypt(password, magic, salt), expected);
+        }
+    }
+
+    #[test]
+    fn salt_is_cut_to_8_chars() {
+        assert_eq!(
+            md5_crypt("pw1", "$apr1$", "saltsaltsalt"),
+            "$apr1$saltsalt$zsSo3wQvAXkIZuoYJGwF11"
+        );
+    }
+
+    #[test]
+    fn verify() {
+        let cases = [
+            ("$apr1$saltsalt$zsSo3wQvAXkIZuoYJGwF11", "pw1"),
+            ("$1$ab$5HlcSdP/TQDPo188cPRWt0", "pw2"),
+            ("{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=", "password"),
+            ("plain", "plain"),
+        ];
+
+        for (hash, password) in cases {
+            let h = Hash::parse(hash).unwrap();
+            assert!(h.verify(password), "{hash} should verify {password}");
+            assert!(!h.verify("wrong"), "{hash} shouldn't verify wrong");
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(
+            Hash::parse("$2y$05$c4WoMPo3SXsafkva.HHa6uXQZWr7oboPiC2bT/r7q1BB8I2s0BRqC").is_none()
+        );
+        assert!(Hash::parse("$apr1$nosalt").is_none());
+        assert!(Hash::parse("{SHA}not base64!").is_none());
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs,
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, ensure, Context};
+
+/// An IPv4 or IPv6 network, e.g. `10.0.0.0/8` or `fd00::/8`. A bare address is treated as a
+/// network containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> anyhow::Result<Self> {
+        let max = max_prefix(&addr);
+        ensure!(prefix <= max, "invalid prefix length: {prefix} > {max}");
+
+        let addr = match addr {
+            IpAddr::V4(a) => IpAddr::V4((u32::from(a) & mask_v4(prefix)).into()),
+            IpAddr::V6(a) => IpAddr::V6((u128::from(a) & mask_v6(prefix)).into()),
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => u32::from(b) & mask_v4(self.prefix) == u32::from(a),
+            (IpAddr::V6(a), IpAddr::V6(b)) => u128::from(b) & mask_v6(self.prefix) == u128::from(a),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .with_context(|| format!("invalid address: {s}"))?;
+
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .with_context(|| format!("invalid prefix length: {s}"))?,
+            None => max_prefix(&addr),
+        };
+
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A set of networks that answers membership queries with one hash lookup per distinct prefix
+/// length, so it stays fast no matter how many networks it holds.
+#[derive(Default, Debug)]
+pub struct CidrSet {
+    v4: BTreeMap<u8, HashSet<u32>>,
+    v6: BTreeMap<u8, HashSet<u128>>,
+}
+
+impl CidrSet {
+    pub fn insert(&mut self, cidr: Cidr) {
+        match cidr.addr {
+            IpAddr::V4(a) => self.v4.entry(cidr.prefix).or_default().insert(a.into()),
+            IpAddr::V6(a) => self.v6.entry(cidr.prefix).or_default().insert(a.into()),
+        };
+    }
+
+    /// Reads one network per line, ignoring blank lines and `#` comments.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)
+            .with_context(|| format!("fs::read_to_string: {}", path.display()))?;
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let cidr = line
+                .parse()
+                .map_err(|e| anyhow!("{}:{}: {e}", path.display(), i + 1))?;
+            self.insert(cidr);
+        }
+
+        Ok(())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match canonical(ip) {
+            IpAddr::V4(a) => {
+                let a = u32::from(a);
+                self.v4
+                    .iter()
+                    .any(|(p, set)| set.contains(&(a & mask_v4(*p))))
+            }
+            IpAddr::V6(a) => {
+                let a = u128::from(a);
+                self.v6
+                    .iter()
+                    .any(|(p, set)| set.contains(&(a & mask_v6(*p))))
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+impl FromIterator<Cidr> for CidrSet {
+    fn from_iter<T: IntoIterator<Item = Cidr>>(iter: T) -> Self {
+        let mut set = CidrSet::default();
+        for cidr in iter {
+            set.insert(cidr);
+        }
+        set
+    }
+}
+
+/// Unwraps IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) so they match IPv4 networks.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(a) => match a.to_ipv4_mapped() {
+            Some(a) => IpAddr::V4(a),
+            None => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn max_prefix(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::IpAddr, process};
+
+    use super::{Cidr, CidrSet};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn set(cidrs: &[&str]) -> CidrSet {
+        cidrs.iter().map(|c| c.parse::<Cidr>().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse() {
+        let cases = [
+            ("10.0.0.0/8", "10.0.0.0/8"),
+            // The host bits are masked off
+            ("10.1.2.3/8", "10.0.0.0/8"),
+            ("192.168.1.1/31", "192.168.1.0/31"),
+            ("192.0.2.7", "192.0.2.7/32"),
+            ("0.0.0.0/0", "0.0.0.0/0"),
+            ("1.2.3.4/0", "0.0.0.0/0"),
+            ("fd00::1/8", "fd00::/8"),
+            ("2001:db8::1", "2001:db8::1/128"),
+            ("::/0", "::/0"),
+        ];
+
+        for (s, expected) in cases {
+            let c = s.parse::<Cidr>().unwrap();
+            assert_eq!(c.to_string(), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in [
+            "",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com/8",
+            "10.0.0.0/8/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let c = "10.0.0.0/8".parse::<Cidr>().unwrap();
+        assert!(c.contains(ip("10.0.0.0")));
+        assert!(c.contains(ip("10.255.255.255")));
+        assert!(!c.contains(ip("11.0.0.0")));
+        assert!(!c.contains(ip("9.255.255.255")));
+        // IPv4-mapped addresses are those of IPv4 networks
+        assert!(c.contains(ip("::ffff:10.1.2.3")));
+        assert!(!c.contains(ip("::a01:203")));
+
+        let c = "fd00::/8".parse::<Cidr>().unwrap();
+        assert!(c.contains(ip("fd12:3456::1")));
+        assert!(!c.contains(ip("fe80::1")));
+        assert!(!c.contains(ip("10.0.0.1")));
+
+        let all = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(all.contains(ip("255.255.255.255")));
+        assert!(!all.contains(ip("::1")));
+
+        let host = "192.0.2.7".parse::<Cidr>().unwrap();
+        assert!(host.contains(ip("192.0.2.7")));
+        assert!(!host.contains(ip("192.0.2.6")));
+    }
+
+    #[test]
+    fn set_contains() {
+        let s = set(&[
+            "10.0.0.0/8",
+            "192.168.1.0/24",
+            "192.0.2.7",
+            "fd00::/8",
+            "::1",
+        ]);
+
+        for a in [
+            "10.20.30.40",
+            "192.168.1.255",
+            "192.0.2.7",
+            "::ffff:192.168.1.1",
+            "fd00::abcd",
+            "::1",
+        ] {
+            assert!(s.contains(ip(a)), "{a}");
+        }
+        for a in [
+            "11.0.0.1",
+            "192.168.2.1",
+            "192.0.2.8",
+            "fe80::1",
+            "::2",
+            "0.0.0.1",
+        ] {
+            assert!(!s.contains(ip(a)), "{a}");
+        }
+
+        assert!(CidrSet::default().is_empty());
+        assert!(!CidrSet::default().contains(ip("10.0.0.1")));
+        assert!(set(&["0.0.0.0/0"]).contains(ip("8.8.8.8")));
+        assert!(!set(&["0.0.0.0/0"]).contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn set_load() {
+        let path = env::temp_dir().join(format!("bubble-cidr-{}", process::id()));
+        fs::write(
+            &path,
+            "# private networks\n10.0.0.0/8\n\n  172.16.0.0/12  # trailing comment\nfd00::/8\n",
+        )
+        .unwrap();
+
+        let mut s = CidrSet::default();
+        let r = s.load(&path);
+        fs::write(&path, "10.0.0.0/8\nnot-a-network\n").unwrap();
+        let err = CidrSet::default().load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        r.unwrap();
+        assert!(s.contains(ip("10.1.1.1")));
+        assert!(s.contains(ip("172.31.255.255")));
+        assert!(!s.contains(ip("172.32.0.0")));
+        assert!(s.contains(ip("fd00::1")));
+        // Errors point at the offending line
+        assert!(
+            err.to_string()
+                .ends_with(":2: invalid address: not-a-network"),
+            "{err}"
+        );
+    }
+}
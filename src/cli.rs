@@ -1,8 +1,20 @@
-
+use std::{fmt, net::IpAddr, path::PathBuf};
 
 use clap::Parser;
 use tracing::debug;
 
+use bubble::{
+    access_log::{Format, Rotate},
+    auth::Credentials,
+    cidr::Cidr,
+    dns::{Prefer, Upstream},
+    limit::{self, Bandwidth},
+    policy::{DomainPattern, PortRange},
+    proxy::tcp::TcpOptions,
+    quota::Reset,
+    route::{Action, Rule},
+};
+
 mod help;
 
 #[derive(Parser, Debug)]
@@ -10,12 +22,59 @@ mod help;
 pub struct Cli {
     #[command(flatten)]
     pub proxy: Proxy,
-    // #[command(flatten)]
-    // pub auth: Auth,
+
+    #[command(flatten)]
+    pub listen: Listen,
+
+    #[command(flatten)]
+    pub policy: Policy,
+
+    #[command(flatten)]
+    pub route: Route,
+
+    #[command(flatten)]
+    pub dns: Dns,
+
+    #[command(flatten)]
+    pub metrics: Metrics,
+
+    #[command(flatten)]
+    pub access_log: AccessLog,
+
+    #[command(flatten)]
+    pub admin: Admin,
+
+    #[command(flatten)]
+    pub limit: Limit,
+
+    #[command(flatten)]
+    pub quota: Quota,
+
+    #[command(flatten)]
+    pub timeout: Timeout,
+
+    #[command(flatten)]
+    pub auth: Auth,
+
+    /// Relay between plain TCP connections with splice(2) on Linux, keeping the bytes of clients
+    /// with no bandwidth limit or quota out of userspace
+    #[arg(long)]
+    pub splice: bool,
+
+    /// Specify how to tune the connections to destinations and tunnel servers, as a comma
+    /// separated list of nodelay, keepalive=<IDLE>[/<INTERVAL>[/<COUNT>]], send-buffer=<SIZE>,
+    /// recv-buffer=<SIZE>, fastopen[=<QUEUE>], user-timeout=<SECS> and congestion=<ALGORITHM>,
+    /// with times in seconds. All but nodelay and the buffer sizes are only supported on Linux
+    #[arg(long, value_name = "OPTIONS")]
+    pub outbound_tcp: Option<TcpOptions>,
+
+    /// Specify the maximum number of seconds to wait for active connections to finish on shutdown
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub shutdown_timeout: u64,
 }
 
 #[derive(clap::Args, Debug)]
-#[group(required = true, args = ["socks5", "http", "tunnel"])]
+#[group(required = true, args = ["socks5", "http", "tunnel", "mixed", "transparent", "dns-server"])]
 pub struct Proxy {
     #[command(flatten)]
     pub socks5: Socks5,
@@ -25,6 +84,15 @@ pub struct Proxy {
 
     #[command(flatten)]
     pub tunnel: Tunnel,
+
+    #[command(flatten)]
+    pub mixed: Mixed,
+
+    #[command(flatten)]
+    pub transparent: Transparent,
+
+    #[command(flatten)]
+    pub dns_server: DnsServer,
 }
 
 #[derive(clap::Args, Debug)]
@@ -40,6 +108,27 @@ pub struct Socks5 {
     /// Specify the port number for the socks5 proxy server to listen on
     #[arg(id = "socks5-port", long, value_name = "PORT", default_value_t = 1080)]
     pub port: u16,
+
+    /// Only allow clients from the given networks to connect to the socks5 proxy server
+    #[arg(id = "socks5-allow", long, value_name = "CIDR", value_delimiter = ',')]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse clients from the given networks when they connect to the socks5 proxy server
+    #[arg(id = "socks5-deny", long, value_name = "CIDR", value_delimiter = ',')]
+    pub deny: Vec<Cidr>,
+
+    /// Load networks to allow for the socks5 proxy server from the given file, one per line
+    #[arg(id = "socks5-allow-file", long, value_name = "FILE")]
+    pub allow_files: Vec<String>,
+
+    /// Load networks to deny for the socks5 proxy server from the given file, one per line
+    #[arg(id = "socks5-deny-file", long, value_name = "FILE")]
+    pub deny_files: Vec<String>,
+
+    /// Specify how to tune the connections to the socks5 proxy server, e.g. nodelay,keepalive=60/10/5,
+    /// see --outbound-tcp
+    #[arg(id = "socks5-tcp", long, value_name = "OPTIONS")]
+    pub tcp: Option<TcpOptions>,
 }
 
 #[derive(clap::Args, Debug)]
@@ -59,6 +148,27 @@ pub struct Http {
     /// Specify the tunnel server address for the http proxy server to forward requests to
     #[arg(id = "http-tunnel-addr", long, value_name = "ADDR")]
     pub tunnel_addr: Option<String>,
+
+    /// Only allow clients from the given networks to connect to the http proxy server
+    #[arg(id = "http-allow", long, value_name = "CIDR", value_delimiter = ',')]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse clients from the given networks when they connect to the http proxy server
+    #[arg(id = "http-deny", long, value_name = "CIDR", value_delimiter = ',')]
+    pub deny: Vec<Cidr>,
+
+    /// Load networks to allow for the http proxy server from the given file, one per line
+    #[arg(id = "http-allow-file", long, value_name = "FILE")]
+    pub allow_files: Vec<String>,
+
+    /// Load networks to deny for the http proxy server from the given file, one per line
+    #[arg(id = "http-deny-file", long, value_name = "FILE")]
+    pub deny_files: Vec<String>,
+
+    /// Specify how to tune the connections to the http proxy server, e.g. nodelay,keepalive=60/10/5,
+    /// see --outbound-tcp
+    #[arg(id = "http-tcp", long, value_name = "OPTIONS")]
+    pub tcp: Option<TcpOptions>,
 }
 
 #[derive(clap::Args, Debug)]
@@ -74,17 +184,516 @@ pub struct Tunnel {
     /// Specify the port number for the tunnel server to listen on
     #[arg(id = "tunnel-port", long, value_name = "PORT", default_value_t = 1082)]
     pub port: u16,
+
+    /// Only allow clients from the given networks to connect to the tunnel server
+    #[arg(id = "tunnel-allow", long, value_name = "CIDR", value_delimiter = ',')]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse clients from the given networks when they connect to the tunnel server
+    #[arg(id = "tunnel-deny", long, value_name = "CIDR", value_delimiter = ',')]
+    pub deny: Vec<Cidr>,
+
+    /// Load networks to allow for the tunnel server from the given file, one per line
+    #[arg(id = "tunnel-allow-file", long, value_name = "FILE")]
+    pub allow_files: Vec<String>,
+
+    /// Load networks to deny for the tunnel server from the given file, one per line
+    #[arg(id = "tunnel-deny-file", long, value_name = "FILE")]
+    pub deny_files: Vec<String>,
+
+    /// Specify how to tune the connections to the tunnel server, e.g. nodelay,keepalive=60/10/5,
+    /// see --outbound-tcp
+    #[arg(id = "tunnel-tcp", long, value_name = "OPTIONS")]
+    pub tcp: Option<TcpOptions>,
 }
 
 #[derive(clap::Args, Debug)]
-pub struct Auth {
-    /// Whether an authentication is required to access this proxy server
-    #[arg(long)]
-    pub auth_required: bool,
+pub struct Mixed {
+    /// Start the mixed (socks4/socks5/http/tls) proxy server on the <mixed-ip>:<mixed-port> address
+    #[arg(id = "mixed", long)]
+    pub enabled: bool,
+
+    /// Specify the IP address for the mixed proxy server to listen on
+    #[arg(id = "mixed-ip", long, value_name = "IP", default_value = "0.0.0.0")]
+    pub ip: String,
+
+    /// Specify the port number for the mixed proxy server to listen on
+    #[arg(id = "mixed-port", long, value_name = "PORT", default_value_t = 1083)]
+    pub port: u16,
+
+    /// Specify the tunnel server address for the mixed proxy server to forward http requests to
+    #[arg(id = "mixed-tunnel-addr", long, value_name = "ADDR")]
+    pub tunnel_addr: Option<String>,
+
+    /// Only allow clients from the given networks to connect to the mixed proxy server
+    #[arg(id = "mixed-allow", long, value_name = "CIDR", value_delimiter = ',')]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse clients from the given networks when they connect to the mixed proxy server
+    #[arg(id = "mixed-deny", long, value_name = "CIDR", value_delimiter = ',')]
+    pub deny: Vec<Cidr>,
+
+    /// Load networks to allow for the mixed proxy server from the given file, one per line
+    #[arg(id = "mixed-allow-file", long, value_name = "FILE")]
+    pub allow_files: Vec<String>,
+
+    /// Load networks to deny for the mixed proxy server from the given file, one per line
+    #[arg(id = "mixed-deny-file", long, value_name = "FILE")]
+    pub deny_files: Vec<String>,
+
+    /// Specify how to tune the connections to the mixed proxy server, e.g. nodelay,keepalive=60/10/5,
+    /// see --outbound-tcp
+    #[arg(id = "mixed-tcp", long, value_name = "OPTIONS")]
+    pub tcp: Option<TcpOptions>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Transparent {
+    /// Start the transparent proxy server on the <transparent-ip>:<transparent-port> address, for the
+    /// connections netfilter redirects to it on Linux, which are routed to where they were headed
+    #[arg(id = "transparent", long)]
+    pub enabled: bool,
+
+    /// Specify the IP address for the transparent proxy server to listen on
+    #[arg(
+        id = "transparent-ip",
+        long,
+        value_name = "IP",
+        default_value = "0.0.0.0"
+    )]
+    pub ip: String,
+
+    /// Specify the port number for the transparent proxy server to listen on
+    #[arg(
+        id = "transparent-port",
+        long,
+        value_name = "PORT",
+        default_value_t = 1084
+    )]
+    pub port: u16,
+
+    /// Accept the connections of an iptables TPROXY rule rather than a REDIRECT one, which keep
+    /// their original destination as their local address. Requires CAP_NET_ADMIN
+    #[arg(id = "transparent-tproxy", long)]
+    pub tproxy: bool,
+
+    /// Only allow clients from the given networks to connect to the transparent proxy server
+    #[arg(
+        id = "transparent-allow",
+        long,
+        value_name = "CIDR",
+        value_delimiter = ','
+    )]
+    pub allow: Vec<Cidr>,
+
+    /// Refuse clients from the given networks when they connect to the transparent proxy server
+    #[arg(
+        id = "transparent-deny",
+        long,
+        value_name = "CIDR",
+        value_delimiter = ','
+    )]
+    pub deny: Vec<Cidr>,
+
+    /// Load networks to allow for the transparent proxy server from the given file, one per line
+    #[arg(id = "transparent-allow-file", long, value_name = "FILE")]
+    pub allow_files: Vec<String>,
+
+    /// Load networks to deny for the transparent proxy server from the given file, one per line
+    #[arg(id = "transparent-deny-file", long, value_name = "FILE")]
+    pub deny_files: Vec<String>,
+
+    /// Specify how to tune the connections to the transparent proxy server, e.g.
+    /// nodelay,keepalive=60/10/5, see --outbound-tcp
+    #[arg(id = "transparent-tcp", long, value_name = "OPTIONS")]
+    pub tcp: Option<TcpOptions>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DnsServer {
+    /// Start the DNS server on the <dns-server-ip>:<dns-server-port> address, over both udp and tcp.
+    /// Queries are routed like connections: rejected domains resolve to NXDOMAIN and tunneled ones are forwarded through the tunnel server
+    #[arg(id = "dns-server", long)]
+    pub enabled: bool,
+
+    /// Specify the IP address for the DNS server to listen on
+    #[arg(
+        id = "dns-server-ip",
+        long,
+        value_name = "IP",
+        default_value = "127.0.0.1"
+    )]
+    pub ip: String,
+
+    /// Specify the port number for the DNS server to listen on
+    #[arg(
+        id = "dns-server-port",
+        long,
+        value_name = "PORT",
+        default_value_t = 53
+    )]
+    pub port: u16,
+
+    /// Only allow clients from the given networks to query the DNS server
+    #[arg(
+        id = "dns-server-allow",
+        long,
+        value_name = "CIDR",
+        value_delimiter = ','
+    )]
+    pub allow: Vec<Cidr>,
 
-    /// The file containing the valid users
+    /// Refuse clients from the given networks when they query the DNS server
+    #[arg(
+        id = "dns-server-deny",
+        long,
+        value_name = "CIDR",
+        value_delimiter = ','
+    )]
+    pub deny: Vec<Cidr>,
+
+    /// Load networks to allow for the DNS server from the given file, one per line
+    #[arg(id = "dns-server-allow-file", long, value_name = "FILE")]
+    pub allow_files: Vec<String>,
+
+    /// Load networks to deny for the DNS server from the given file, one per line
+    #[arg(id = "dns-server-deny-file", long, value_name = "FILE")]
+    pub deny_files: Vec<String>,
+}
+
+#[derive(clap::Args, Debug, Clone, Copy)]
+pub struct Listen {
+    /// Specify the number of accept loops for each proxy server, each on a socket of its own
+    /// bound to the same address with SO_REUSEPORT
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    pub acceptors: u16,
+
+    /// Specify the maximum number of connections waiting to be accepted on each socket
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    pub backlog: u32,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Policy {
+    /// Allow connections to the given destination networks, overriding the deny rules
+    #[arg(long, value_name = "CIDR", value_delimiter = ',')]
+    pub allow_dst: Vec<Cidr>,
+
+    /// Deny connections to the given destination networks
+    #[arg(long, value_name = "CIDR", value_delimiter = ',')]
+    pub deny_dst: Vec<Cidr>,
+
+    /// Only allow connections to the given destination ports or port ranges
+    #[arg(long, value_name = "PORTS", value_delimiter = ',')]
+    pub allow_port: Vec<PortRange>,
+
+    /// Deny connections to the given destination ports or port ranges
+    #[arg(long, value_name = "PORTS", value_delimiter = ',')]
+    pub deny_port: Vec<PortRange>,
+
+    /// Allow connections to the domains matching the given patterns, overriding the deny rules
+    #[arg(long, value_name = "PATTERN", value_delimiter = ',')]
+    pub allow_domain: Vec<DomainPattern>,
+
+    /// Deny connections to the domains matching the given patterns, e.g. '*.internal'
+    #[arg(long, value_name = "PATTERN", value_delimiter = ',')]
+    pub deny_domain: Vec<DomainPattern>,
+
+    /// Allow connections to the loopback, link-local and cloud metadata addresses denied by default
     #[arg(long)]
-    pub auth_users: Option<String>,
+    pub allow_private_dst: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Route {
+    /// Add a routing rule in the form of <CONDITION>[&<CONDITION>...]=<ACTION>, the first matching rule wins.
+    /// Conditions: domain, domain-suffix, domain-keyword, domain-regex, cidr, port, client and user, e.g. 'domain-suffix:example.com'.
+    /// Actions: direct, reject, tunnel:<ADDR>, http:[<USER>:<PASSWORD>@]<ADDR> and socks5:[<USER>:<PASSWORD>@]<ADDR>,
+    /// the last two through upstream proxy servers
+    #[arg(long = "route", value_name = "RULE")]
+    pub rules: Vec<Rule>,
+
+    /// Specify the action for the connections matching no routing rule
+    #[arg(long, value_name = "ACTION", default_value = "direct")]
+    pub default_route: Action,
+
+    /// Load a list of domains and networks from a file, usable in routing rules as 'rule-set:<NAME>'.
+    /// Hosts files, domain lists and CIDR lists are supported
+    #[arg(long = "rule-set", value_name = "NAME=FILE", value_parser = name_path)]
+    pub rule_sets: Vec<(String, PathBuf)>,
+
+    /// Specify how often, in seconds, rule set files are checked for changes
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub rule_set_interval: u64,
+}
+
+fn name_path(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), path.into()))
+        }
+        _ => Err(format!("invalid value: {s}, expected <NAME>=<FILE>")),
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Dns {
+    /// Specify the DNS servers to resolve destinations with, tried in order,
+    /// e.g. '8.8.8.8', 'udp://1.1.1.1:53', 'tcp://[2001:4860:4860::8888]', 'tls://1.1.1.1' or 'https://dns.google/dns-query'.
+    /// Encrypted servers given by name need a bootstrap address from --host, e.g. '--host=dns.google=8.8.8.8'.
+    /// Defaults to the name servers in /etc/resolv.conf
+    #[arg(long = "dns", value_name = "SERVER", value_delimiter = ',')]
+    pub servers: Vec<Upstream>,
+
+    /// Resolve the given host name to the given address, overriding DNS and hosts files
+    #[arg(long = "host", value_name = "NAME=IP", value_parser = name_ip)]
+    pub hosts: Vec<(String, IpAddr)>,
+
+    /// Load static host names from the given hosts file.
+    /// Defaults to /etc/hosts, if it exists
+    #[arg(long, value_name = "FILE")]
+    pub hosts_file: Vec<PathBuf>,
+
+    /// Specify which address families to resolve and connect to: ipv4, ipv6, ipv4-only or ipv6-only
+    #[arg(long, value_name = "PREFER", default_value = "ipv4")]
+    pub dns_prefer: Prefer,
+}
+
+fn name_ip(s: &str) -> Result<(String, IpAddr), String> {
+    match s.split_once('=').map(|(n, ip)| (n, ip.parse())) {
+        Some((name, Ok(ip))) if !name.is_empty() => Ok((name.to_string(), ip)),
+        _ => Err(format!("invalid value: {s}, expected <NAME>=<IP>")),
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Metrics {
+    /// Expose Prometheus metrics at http://<metrics-ip>:<metrics-port>/metrics
+    #[arg(id = "metrics", long)]
+    pub enabled: bool,
+
+    /// Specify the IP address for the metrics server to listen on
+    #[arg(
+        id = "metrics-ip",
+        long,
+        value_name = "IP",
+        default_value = "127.0.0.1"
+    )]
+    pub ip: String,
+
+    /// Specify the port number for the metrics server to listen on
+    #[arg(id = "metrics-port", long, value_name = "PORT", default_value_t = 9091)]
+    pub port: u16,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AccessLog {
+    /// Write a record of every proxied connection and http request to the given file
+    #[arg(id = "access-log", long, value_name = "FILE")]
+    pub path: Option<PathBuf>,
+
+    /// Specify the format of the access log records: json or combined
+    #[arg(
+        id = "access-log-format",
+        long,
+        value_name = "FORMAT",
+        default_value = "json"
+    )]
+    pub format: Format,
+
+    /// Rotate the access log once it grows past the given number of megabytes, 0 for no limit
+    #[arg(
+        id = "access-log-max-size",
+        long,
+        value_name = "MB",
+        default_value_t = 100
+    )]
+    pub max_size: u64,
+
+    /// Specify how often to rotate the access log regardless of its size: never, hourly or daily
+    #[arg(
+        id = "access-log-rotate",
+        long,
+        value_name = "PERIOD",
+        default_value = "daily"
+    )]
+    pub rotate: Rotate,
+
+    /// Specify the number of rotated access log files to keep
+    #[arg(id = "access-log-keep", long, value_name = "N", default_value_t = 7)]
+    pub keep: usize,
+}
+
+#[derive(clap::Args)]
+pub struct Admin {
+    /// Start the admin API server on the <admin-ip>:<admin-port> address, to list and close
+    /// connections and disable users
+    #[arg(id = "admin", long, requires = "admin-token")]
+    pub enabled: bool,
+
+    /// Specify the IP address for the admin API server to listen on
+    #[arg(id = "admin-ip", long, value_name = "IP", default_value = "127.0.0.1")]
+    pub ip: String,
+
+    /// Specify the port number for the admin API server to listen on
+    #[arg(id = "admin-port", long, value_name = "PORT", default_value_t = 9092)]
+    pub port: u16,
+
+    /// Specify the token that requests to the admin API server must carry as
+    /// 'Authorization: Bearer <TOKEN>'
+    #[arg(id = "admin-token", long, value_name = "TOKEN")]
+    pub token: Option<String>,
+}
+
+// Keeps the token out of debug logs and the config shown by the admin API
+impl fmt::Debug for Admin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Admin")
+            .field("enabled", &self.enabled)
+            .field("ip", &self.ip)
+            .field("port", &self.port)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Limit {
+    /// Limit the upload and download rates, in bytes per second with an optional K, M or G suffix,
+    /// of the connections in a scope: global, listener:<NAME>, user (each user) or client (each client IP),
+    /// e.g. 'client=1M/10M'. A single rate applies to both directions, and 0 means no limit
+    #[arg(long, value_name = "SCOPE=UP[/DOWN]")]
+    pub bandwidth: Vec<Bandwidth>,
+
+    /// Specify for how many seconds a connection may go at full speed after being idle, within the
+    /// bandwidth limits
+    #[arg(long, value_name = "SECS", default_value_t = 1.0)]
+    pub bandwidth_burst: f64,
+
+    /// Specify the maximum number of connections served at once, 0 for no limit
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_connections: usize,
+
+    /// Specify the maximum number of connections served at once from each client IP, 0 for no limit
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_connections_per_client: usize,
+
+    /// Specify the maximum number of connections served at once for each user, 0 for no limit
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_connections_per_user: usize,
+
+    /// Specify the maximum number of new connections per second from each client IP, 0 for no limit
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_connection_rate: u64,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Quota {
+    /// Limit the bytes a user may send and receive in each quota period, with an optional K, M, G
+    /// or T suffix, e.g. 'alice=10G'. The user '*' stands for every user not listed
+    #[arg(id = "quota", long, value_name = "USER=BYTES", value_parser = user_bytes)]
+    pub quotas: Vec<(String, u64)>,
+
+    /// Specify when the usage of every user starts over: daily or monthly, at midnight UTC
+    #[arg(
+        id = "quota-reset",
+        long,
+        value_name = "SCHEDULE",
+        default_value = "monthly"
+    )]
+    pub reset: Reset,
+
+    /// Save the usage of every user to the given file, so that it survives restarts
+    #[arg(id = "quota-file", long, value_name = "FILE")]
+    pub file: Option<PathBuf>,
+
+    /// Log a warning once a user has used the given percentages of its quota
+    #[arg(
+        id = "quota-warn",
+        long,
+        value_name = "PERCENT",
+        value_delimiter = ',',
+        default_value = "80,90",
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    pub warn: Vec<u8>,
+
+    /// Close the connections of a user as soon as its quota is exhausted, instead of only refusing
+    /// new ones
+    #[arg(id = "quota-cut", long)]
+    pub cut: bool,
+}
+
+fn user_bytes(s: &str) -> Result<(String, u64), String> {
+    match s.split_once('=') {
+        Some((user, bytes)) if !user.is_empty() => limit::parse_bytes(bytes)
+            .map(|b| (user.to_string(), b))
+            .map_err(|e| e.to_string()),
+        _ => Err(format!("invalid value: {s}, expected <USER>=<BYTES>")),
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Timeout {
+    /// Specify the maximum number of seconds a client may take to ask for a destination, 0 for no
+    /// limit
+    #[arg(
+        id = "handshake-timeout",
+        long,
+        value_name = "SECS",
+        default_value_t = 10
+    )]
+    pub handshake: u64,
+
+    /// Specify the maximum number of seconds to wait for a destination to be resolved, 0 for no
+    /// limit
+    #[arg(id = "dns-timeout", long, value_name = "SECS", default_value_t = 5)]
+    pub dns: u64,
+
+    /// Specify the maximum number of seconds to wait for a connection to a destination, 0 for no
+    /// limit
+    #[arg(
+        id = "connect-timeout",
+        long,
+        value_name = "SECS",
+        default_value_t = 10
+    )]
+    pub connect: u64,
+
+    /// Specify the number of seconds after which a connection with no bytes going either way is
+    /// closed, 0 for never
+    #[arg(id = "idle-timeout", long, value_name = "SECS", default_value_t = 300)]
+    pub idle: u64,
+}
+
+#[derive(clap::Args, Debug)]
+#[group(id = "auth", multiple = false, args = ["auth-user", "auth-htpasswd", "auth-command"])]
+pub struct Auth {
+    /// Require clients to authenticate as one of the given users
+    #[arg(id = "auth-user", long, value_name = "USER:PASSWORD")]
+    pub users: Vec<Credentials>,
+
+    /// Require clients to authenticate as one of the users in the given htpasswd file, with
+    /// passwords hashed with MD5, SHA-1 or not at all
+    #[arg(id = "auth-htpasswd", long, value_name = "FILE")]
+    pub htpasswd: Option<PathBuf>,
+
+    /// Require clients to authenticate through the given command, run with sh -c and given the
+    /// user name, password and client address in the BUBBLE_USER, BUBBLE_PASSWORD and
+    /// BUBBLE_CLIENT environment variables. Clients are accepted if it exits with 0, as the user
+    /// it prints, if any
+    #[arg(id = "auth-command", long, value_name = "COMMAND")]
+    pub command: Option<String>,
+
+    /// Specify the user name and password to authenticate with to the tunnel servers
+    /// connections are routed through
+    #[arg(id = "tunnel-auth", long, value_name = "USER:PASSWORD")]
+    pub tunnel: Option<Credentials>,
 }
 
 pub fn parse() -> Cli {
@@ -26,6 +26,27 @@ pub const AFTER: &str = color_print::cstr!(
 
   Start both the socks5 and http proxy servers simultaneously, listening on their default addresses
 
-    <bold>./bubble --socks5 --http\n</bold>
+    <bold>./bubble --socks5 --http</bold>
+
+  Start the mixed proxy server, accepting socks4, socks5, http and tls clients on '0.0.0.0:1083'
+
+    <bold>./bubble --mixed</bold>
+
+  Start the socks5 proxy server, sending the connections to '*.example.com' through the tunnel server at '10.0.0.1:1082'
+
+    <bold>./bubble --socks5 --route=domain-suffix:example.com=tunnel:10.0.0.1:1082</bold>
+
+  Start the http proxy server, sending all connections through the upstream socks5 proxy at '10.0.0.2:1080' as 'alice'
+
+    <bold>./bubble --http --default-route=socks5:alice:secret@10.0.0.2:1080</bold>
+
+  Start the transparent proxy server for the connections of a container network, redirected by iptables
+
+    <bold>iptables -t nat -A PREROUTING -i docker0 -p tcp -j REDIRECT --to-ports 1084</bold>
+    <bold>./bubble --transparent</bold>
+
+  Start the DNS server on '127.0.0.1:53', resolving through DNS over HTTPS and answering NXDOMAIN for '*.ads.example'
+
+    <bold>./bubble --dns-server --dns=https://dns.google/dns-query --host=dns.google=8.8.8.8 --route=domain-suffix:ads.example=reject\n</bold>
 "
 );
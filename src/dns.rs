@@ -0,0 +1,451 @@
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::net::{self, TcpStream};
+use tracing::{debug, warn};
+
+use crate::metrics;
+use message::{Message, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA};
+pub use upstream::Upstream;
+
+mod message;
+pub mod server;
+mod upstream;
+
+/// How long to cache a negative answer that doesn't carry an SOA record.
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+/// The TTL of the answers served from the hosts table or the system resolver.
+const STATIC_TTL: Duration = Duration::from_secs(60);
+
+/// The maximum number of cached answers.
+const CACHE_SIZE: usize = 10000;
+
+/// Which address families to resolve, and which one to try first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prefer {
+    Ipv4,
+    Ipv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl FromStr for Prefer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4" => Ok(Prefer::Ipv4),
+            "ipv6" => Ok(Prefer::Ipv6),
+            "ipv4-only" => Ok(Prefer::Ipv4Only),
+            "ipv6-only" => Ok(Prefer::Ipv6Only),
+            _ => bail!("invalid preference: {s}, expected ipv4, ipv6, ipv4-only or ipv6-only"),
+        }
+    }
+}
+
+/// Where a [`Resolver`] finds the addresses of the destinations.
+#[derive(Clone, Debug)]
+pub struct ResolverOptions {
+    /// The DNS servers, tried in order, or the ones in /etc/resolv.conf if empty.
+    pub servers: Vec<Upstream>,
+    /// The static addresses of host names, which override DNS and hosts files.
+    pub hosts: Vec<(String, IpAddr)>,
+    /// The hosts files to load static host names from, or `/etc/hosts` if empty, which is
+    /// skipped when missing.
+    pub hosts_files: Vec<PathBuf>,
+    pub prefer: Prefer,
+}
+
+impl Default for ResolverOptions {
+    fn default() -> Self {
+        ResolverOptions {
+            servers: Vec::new(),
+            hosts: Vec::new(),
+            hosts_files: Vec::new(),
+            prefer: Prefer::Ipv4,
+        }
+    }
+}
+
+/// Resolves the destinations of the proxy servers, either through the configured DNS servers,
+/// caching their answers for as long as their TTLs allow, or through the system resolver.
+pub struct Resolver {
+    upstreams: Vec<Upstream>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    prefer: Prefer,
+    cache: Mutex<HashMap<(String, u16), Entry>>,
+}
+
+struct Entry {
+    rcode: u16,
+    /// Empty for negative answers.
+    ips: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// The addresses of a name of one family.
+#[derive(Clone, Debug, Default)]
+pub struct Answer {
+    /// The response code of the DNS server, NXDOMAIN if the name doesn't exist.
+    pub rcode: u16,
+    /// Empty for negative answers.
+    pub ips: Vec<IpAddr>,
+    /// How long the answer may be cached.
+    pub ttl: Duration,
+}
+
+impl Resolver {
+    pub fn new(c: &ResolverOptions) -> anyhow::Result<Self> {
+        let mut hosts = HashMap::new();
+        if c.hosts_files.is_empty() {
+            // Minimal containers and other systems may well have no hosts file
+            let path = Path::new("/etc/hosts");
+            if path.exists() {
+                load_hosts(path, &mut hosts)?;
+            }
+        }
+        for path in &c.hosts_files {
+            load_hosts(path, &mut hosts)?;
+        }
+
+        // Names given explicitly replace those found in hosts files
+        for (name, _) in &c.hosts {
+            hosts.remove(&name.trim_end_matches('.').to_ascii_lowercase());
+        }
+        for (name, ip) in &c.hosts {
+            hosts
+                .entry(name.trim_end_matches('.').to_ascii_lowercase())
+                .or_default()
+                .push(*ip);
+        }
+
+        let mut upstreams = if c.servers.is_empty() {
+            system_upstreams()
+        } else {
+            c.servers.clone()
+        };
+
+        // Encrypted servers known by name are only ever looked up in the static hosts table
+        for u in &mut upstreams {
+            if let Some(name) = u.bootstrap_name() {
+                let ip = hosts.get(name).and_then(|ips| ips.first()).copied();
+                let ip = ip.ok_or_else(|| {
+                    anyhow!("dns server {u}: no bootstrap address, add --host={name}=<IP>")
+                })?;
+                u.bootstrap(ip);
+            }
+        }
+
+        if upstreams.is_empty() {
+            warn!("no dns server configured, falling back to the system resolver");
+        }
+
+        Ok(Resolver {
+            upstreams,
+            hosts,
+            prefer: c.prefer,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Resolves `host`, which may also be an IP address, returning its addresses in order of
+    /// preference.
+    pub async fn lookup(&self, host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+
+        let mut ips = match self.hosts.get(&name) {
+            Some(ips) => ips.clone(),
+            None if self.upstreams.is_empty() => net::lookup_host((name.as_str(), port))
+                .await
+                .context("lookup: lookup_host")?
+                .map(|a| a.ip())
+                .collect(),
+            None => self.resolve(&name).await?,
+        };
+
+        match self.prefer {
+            Prefer::Ipv4 => ips.sort_by_key(|ip| ip.is_ipv6()),
+            Prefer::Ipv6 => ips.sort_by_key(|ip| ip.is_ipv4()),
+            Prefer::Ipv4Only => ips.retain(|ip| ip.is_ipv4()),
+            Prefer::Ipv6Only => ips.retain(|ip| ip.is_ipv6()),
+        }
+
+        if ips.is_empty() {
+            bail!("lookup: no address: {host}");
+        }
+
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Resolves `name` for the DNS server, returning its addresses of the `qtype` family.
+    pub async fn answer(&self, name: &str, qtype: u16) -> anyhow::Result<Answer> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let wanted = !matches!(
+            (self.prefer, qtype),
+            (Prefer::Ipv4Only, TYPE_AAAA) | (Prefer::Ipv6Only, TYPE_A)
+        );
+        let found = |ips, ttl| Answer {
+            rcode: RCODE_NOERROR,
+            ips,
+            ttl,
+        };
+
+        let mut a = match self.hosts.get(&name) {
+            Some(ips) => found(ips.clone(), STATIC_TTL),
+            None if !wanted => found(vec![], NEGATIVE_TTL),
+            None if self.upstreams.is_empty() => found(
+                net::lookup_host((name.as_str(), 0))
+                    .await
+                    .context("answer: lookup_host")?
+                    .map(|a| a.ip())
+                    .collect(),
+                STATIC_TTL,
+            ),
+            None => self.query(&name, qtype).await?,
+        };
+
+        a.ips
+            .retain(|ip| wanted && ip.is_ipv4() == (qtype == TYPE_A));
+        Ok(a)
+    }
+
+    /// Forwards the encoded query `req` as is to the DNS servers in turn, returning the first
+    /// response.
+    pub async fn forward(&self, req: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut last_err = None;
+
+        for u in &self.upstreams {
+            match u.exchange(req).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) => {
+                    warn!("dns forward via {u}: {e:?}");
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("forward: no dns server")))
+    }
+
+    /// Like [`Resolver::forward`], but reaches the DNS servers over the streams returned by
+    /// `connect`, e.g. through a tunnel.
+    pub async fn forward_over<F, Fut>(&self, req: &[u8], connect: F) -> anyhow::Result<Vec<u8>>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = anyhow::Result<TcpStream>>,
+    {
+        let mut last_err = None;
+
+        for u in &self.upstreams {
+            let r = match connect(u.addr()?).await {
+                Ok(s) => u.exchange_over(s, req).await,
+                Err(e) => Err(e),
+            };
+
+            match r {
+                Ok(resp) => return Ok(resp),
+                Err(e) => {
+                    warn!("dns forward via {u}: {e:?}");
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("forward_over: no dns server")))
+    }
+
+    async fn resolve(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let (v4, v6) = match self.prefer {
+            Prefer::Ipv4Only => (self.query(name, TYPE_A).await, Ok(Default::default())),
+            Prefer::Ipv6Only => (Ok(Default::default()), self.query(name, TYPE_AAAA).await),
+            _ => tokio::join!(self.query(name, TYPE_A), self.query(name, TYPE_AAAA)),
+        };
+        let (v4, v6) = (v4.map(|a| a.ips), v6.map(|a| a.ips));
+
+        match (v4, v6) {
+            (Err(e), Err(_)) => Err(e),
+            (v4, v6) => Ok(v4
+                .unwrap_or_default()
+                .into_iter()
+                .chain(v6.unwrap_or_default())
+                .collect()),
+        }
+    }
+
+    /// Returns the addresses of `name`, cached for as much longer as they may be.
+    async fn query(&self, name: &str, qtype: u16) -> anyhow::Result<Answer> {
+        let key = (name.to_string(), qtype);
+
+        if let Some(e) = self.cache.lock().unwrap().get(&key) {
+            let now = Instant::now();
+            if e.expires > now {
+                metrics::DNS_CACHE_HITS.inc();
+                return Ok(Answer {
+                    rcode: e.rcode,
+                    ips: e.ips.clone(),
+                    ttl: e.expires - now,
+                });
+            }
+        }
+        metrics::DNS_CACHE_MISSES.inc();
+
+        let req = Message::query(random_id(), name, qtype);
+        let mut last_err = None;
+
+        for u in &self.upstreams {
+            let resp = match u.query(&req).await {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!("dns query {name} via {u}: {e:?}");
+                    last_err = Some(e);
+                    continue;
+                }
+            };
+
+            let rcode = resp.rcode();
+            if rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN {
+                last_err = Some(anyhow!("query: {name} via {u}: rcode {rcode}"));
+                continue;
+            }
+
+            let a = answer(&resp, qtype);
+            debug!("dns query {name} via {u}: {a:?}");
+            self.insert(key, &a);
+            return Ok(a);
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("query: no dns server")))
+    }
+
+    fn insert(&self, key: (String, u16), a: &Answer) {
+        if a.ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.len() >= CACHE_SIZE {
+            cache.retain(|_, e| e.expires > now);
+        }
+        // Still full of live answers, so make room for a while by dropping the tenth of them
+        // that expires first
+        if cache.len() >= CACHE_SIZE {
+            let mut expires = cache.values().map(|e| e.expires).collect::<Vec<_>>();
+            let (_, &mut cutoff, _) = expires.select_nth_unstable(CACHE_SIZE / 10);
+            cache.retain(|_, e| e.expires > cutoff);
+        }
+
+        cache.insert(
+            key,
+            Entry {
+                rcode: a.rcode,
+                ips: a.ips.clone(),
+                expires: now + a.ttl,
+            },
+        );
+    }
+}
+
+/// Returns the addresses in `resp` and how long they may be cached.
+fn answer(resp: &Message, qtype: u16) -> Answer {
+    let ips = resp
+        .answers
+        .iter()
+        .filter_map(|r| match (r.rtype, r.data.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                Some(IpAddr::from(<[u8; 4]>::try_from(&r.data[..]).unwrap()))
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                Some(IpAddr::from(<[u8; 16]>::try_from(&r.data[..]).unwrap()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let ttl = if ips.is_empty() {
+        // https://www.rfc-editor.org/rfc/rfc2308#section-5
+        resp.authorities
+            .iter()
+            .find_map(|r| r.soa_minimum().map(|m| m.min(r.ttl)))
+            .map_or(NEGATIVE_TTL, |t| Duration::from_secs(t as u64))
+    } else {
+        // The answer may include a CNAME chain, which expires with its shortest lived record
+        let ttl = resp.answers.iter().map(|r| r.ttl).min().unwrap_or_default();
+        Duration::from_secs(ttl as u64)
+    };
+
+    Answer {
+        rcode: resp.rcode(),
+        ips,
+        ttl,
+    }
+}
+
+/// Reads a hosts file, e.g. `/etc/hosts`, into a table mapping names to their addresses.
+fn load_hosts(path: &Path, hosts: &mut HashMap<String, Vec<IpAddr>>) -> anyhow::Result<()> {
+    let s = fs::read_to_string(path)
+        .with_context(|| format!("fs::read_to_string: {}", path.display()))?;
+
+    for line in s.lines() {
+        let mut fields = line
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace();
+
+        // Like the system resolver, skip the lines it can't make sense of, e.g. scoped addresses
+        let ip = match fields.next().map(|ip| ip.parse::<IpAddr>()) {
+            Some(Ok(ip)) => ip,
+            _ => continue,
+        };
+
+        for name in fields {
+            hosts
+                .entry(name.trim_end_matches('.').to_ascii_lowercase())
+                .or_default()
+                .push(ip);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the name servers listed in `/etc/resolv.conf`, if any.
+fn system_upstreams() -> Vec<Upstream> {
+    fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.strip_prefix("nameserver"))
+        .filter_map(|a| a.trim().parse().ok())
+        .collect()
+}
+
+/// Returns an unpredictable query id, so that off-path attackers can't easily forge responses,
+/// https://www.rfc-editor.org/rfc/rfc5452#section-4.3
+fn random_id() -> u16 {
+    let mut id = [0; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .expect("SystemRandom::fill");
+    u16::from_be_bytes(id)
+}
@@ -0,0 +1,352 @@
+use anyhow::{bail, ensure, Context};
+
+// https://www.rfc-editor.org/rfc/rfc1035#section-4
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+pub const FLAG_QR: u16 = 0x8000;
+pub const FLAG_OPCODE: u16 = 0x7800;
+pub const FLAG_TC: u16 = 0x0200;
+pub const FLAG_RD: u16 = 0x0100;
+pub const FLAG_RA: u16 = 0x0080;
+
+pub const RCODE_NOERROR: u16 = 0;
+pub const RCODE_FORMERR: u16 = 1;
+pub const RCODE_SERVFAIL: u16 = 2;
+pub const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A resource record. Its RDATA is kept as is, so names inside it may still be compressed.
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn query(id: u16, name: &str, qtype: u16) -> Self {
+        Message {
+            id,
+            flags: FLAG_RD,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Returns a response to this query with the given rcode and no records.
+    pub fn reply(&self, rcode: u16) -> Self {
+        Message {
+            id: self.id,
+            flags: FLAG_QR | self.flags & (FLAG_OPCODE | FLAG_RD) | FLAG_RA | rcode,
+            questions: self.questions.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn rcode(&self) -> u16 {
+        self.flags & 0x000f
+    }
+
+    // +---------------------+
+    // |        Header       |
+    // +---------------------+
+    // |       Question      | the question for the name server
+    // +---------------------+
+    // |        Answer       | RRs answering the question
+    // +---------------------+
+    // |      Authority      | RRs pointing toward an authority
+    // +---------------------+
+    // |      Additional     | RRs holding additional information
+    // +---------------------+
+
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut b = Vec::with_capacity(512);
+
+        b.extend_from_slice(&self.id.to_be_bytes());
+        b.extend_from_slice(&self.flags.to_be_bytes());
+        b.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        b.extend_from_slice(&(self.answers.len() as u16).to_be_bytes());
+        b.extend_from_slice(&(self.authorities.len() as u16).to_be_bytes());
+        b.extend_from_slice(&0u16.to_be_bytes());
+
+        for q in &self.questions {
+            encode_name(&mut b, &q.name)?;
+            b.extend_from_slice(&q.qtype.to_be_bytes());
+            b.extend_from_slice(&q.qclass.to_be_bytes());
+        }
+
+        for r in self.answers.iter().chain(&self.authorities) {
+            encode_name(&mut b, &r.name)?;
+            b.extend_from_slice(&r.rtype.to_be_bytes());
+            b.extend_from_slice(&r.class.to_be_bytes());
+            b.extend_from_slice(&r.ttl.to_be_bytes());
+            b.extend_from_slice(&(r.data.len() as u16).to_be_bytes());
+            b.extend_from_slice(&r.data);
+        }
+
+        Ok(b)
+    }
+
+    /// Decodes a message, skipping its additional section.
+    pub fn decode(b: &[u8]) -> anyhow::Result<Self> {
+        ensure!(b.len() >= 12, "decode: truncated header");
+
+        let u16_at = |i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+        let (qdcount, ancount, nscount) = (u16_at(4), u16_at(6), u16_at(8));
+
+        let mut m = Message {
+            id: u16_at(0),
+            flags: u16_at(2),
+            ..Default::default()
+        };
+
+        let mut pos = 12;
+        for _ in 0..qdcount {
+            let name = decode_name(b, &mut pos).context("decode: question name")?;
+            let f = take(b, &mut pos, 4).context("decode: question")?;
+            m.questions.push(Question {
+                name,
+                qtype: u16::from_be_bytes([f[0], f[1]]),
+                qclass: u16::from_be_bytes([f[2], f[3]]),
+            });
+        }
+
+        for i in 0..ancount as usize + nscount as usize {
+            let name = decode_name(b, &mut pos).context("decode: record name")?;
+            let f = take(b, &mut pos, 10).context("decode: record")?;
+            let len = u16::from_be_bytes([f[8], f[9]]) as usize;
+            let data = take(b, &mut pos, len).context("decode: record data")?;
+
+            let r = Record {
+                name,
+                rtype: u16::from_be_bytes([f[0], f[1]]),
+                class: u16::from_be_bytes([f[2], f[3]]),
+                ttl: u32::from_be_bytes([f[4], f[5], f[6], f[7]]),
+                data: data.to_vec(),
+            };
+
+            if i < ancount as usize {
+                m.answers.push(r);
+            } else {
+                m.authorities.push(r);
+            }
+        }
+
+        Ok(m)
+    }
+}
+
+impl Record {
+    /// The MINIMUM field of an SOA record, which is the last field of its RDATA.
+    pub fn soa_minimum(&self) -> Option<u32> {
+        if self.rtype != TYPE_SOA || self.data.len() < 4 {
+            return None;
+        }
+
+        let d = &self.data[self.data.len() - 4..];
+        Some(u32::from_be_bytes([d[0], d[1], d[2], d[3]]))
+    }
+}
+
+fn encode_name(b: &mut Vec<u8>, name: &str) -> anyhow::Result<()> {
+    for label in name
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+    {
+        ensure!(label.len() < 64, "encode_name: label too long: {name}");
+        b.push(label.len() as u8);
+        b.extend_from_slice(label.as_bytes());
+    }
+    b.push(0);
+    Ok(())
+}
+
+fn decode_name(b: &[u8], pos: &mut usize) -> anyhow::Result<String> {
+    let mut name = String::new();
+    let mut p = *pos;
+    let mut jumped = false;
+
+    // Every pointer must go backwards, which also rules out loops
+    let mut limit = p;
+
+    loop {
+        let len = *b.get(p).context("decode_name: truncated")? as usize;
+
+        match len & 0xc0 {
+            0x00 if len == 0 => {
+                if !jumped {
+                    *pos = p + 1;
+                }
+                return Ok(name);
+            }
+            0x00 => {
+                let label = b
+                    .get(p + 1..p + 1 + len)
+                    .context("decode_name: truncated label")?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                p += 1 + len;
+            }
+            0xc0 => {
+                let lo = *b.get(p + 1).context("decode_name: truncated pointer")? as usize;
+                let target = (len & 0x3f) << 8 | lo;
+                ensure!(target < limit, "decode_name: invalid pointer");
+
+                if !jumped {
+                    *pos = p + 2;
+                    jumped = true;
+                }
+                limit = target;
+                p = target;
+            }
+            _ => bail!("decode_name: invalid label type"),
+        }
+    }
+}
+
+fn take<'a>(b: &'a [u8], pos: &mut usize, n: usize) -> anyhow::Result<&'a [u8]> {
+    let v = b.get(*pos..*pos + n).context("truncated")?;
+    *pos += n;
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_name, Message, Record, FLAG_QR, RCODE_NXDOMAIN, TYPE_A, TYPE_SOA};
+
+    #[test]
+    fn name() {
+        let cases: [(&[u8], usize, &str, usize); 5] = [
+            (b"\x07example\x03com\x00", 0, "example.com", 13),
+            (b"\x00", 0, "", 1),
+            (
+                b"\xff\x03www\x07example\x03com\x00\xff",
+                1,
+                "www.example.com",
+                18,
+            ),
+            // https://www.rfc-editor.org/rfc/rfc1035#section-4.1.4, the position is left
+            // right after the first pointer
+            (
+                b"\x07example\x03com\x00\x03www\xc0\x00\xff",
+                13,
+                "www.example.com",
+                19,
+            ),
+            (
+                b"\x03com\x00\x07example\xc0\x00\x03www\xc0\x05",
+                15,
+                "www.example.com",
+                21,
+            ),
+        ];
+
+        for (b, start, expected, end) in cases {
+            let mut pos = start;
+            assert_eq!(decode_name(b, &mut pos).unwrap(), expected, "{b:?}");
+            assert_eq!(pos, end, "{b:?}");
+        }
+    }
+
+    #[test]
+    fn name_invalid() {
+        let cases: [(&[u8], usize); 10] = [
+            (b"", 0),
+            // Truncated
+            (b"\x07example\x03com", 0),
+            (b"\x07exam", 0),
+            (b"\x03www\xc0", 0),
+            // Pointers to themselves, forwards, or in a loop
+            (b"\xc0\x00", 0),
+            (b"\xc0\x02\x00", 0),
+            (b"\x03com\xc0\x06\x03www\xc0\x00", 6),
+            // Pointing past the end
+            (b"\x03www\xc0\xff", 0),
+            // The reserved label types, 0x40 and 0x80
+            (b"\x40example\x00", 0),
+            (b"\x80example\x00", 0),
+        ];
+
+        for (b, start) in cases {
+            let mut pos = start;
+            assert!(decode_name(b, &mut pos).is_err(), "{b:?}");
+        }
+    }
+
+    #[test]
+    fn message() {
+        let q = Message::query(0x1234, "Example.com.", TYPE_A);
+        let b = q.encode().unwrap();
+        assert_eq!(
+            b,
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+              \x07Example\x03com\x00\x00\x01\x00\x01"
+        );
+
+        let mut r = q.reply(RCODE_NXDOMAIN);
+        r.authorities.push(Record {
+            name: "com".to_string(),
+            rtype: TYPE_SOA,
+            class: 1,
+            ttl: 900,
+            data: [&[0xc0, 0x0c][..], &[0; 16], &300u32.to_be_bytes()].concat(),
+        });
+
+        let d = Message::decode(&r.encode().unwrap()).unwrap();
+        assert_eq!(d.id, 0x1234);
+        assert_ne!(d.flags & FLAG_QR, 0);
+        assert_eq!(d.rcode(), RCODE_NXDOMAIN);
+        assert_eq!(d.questions[0].name, "Example.com");
+        assert_eq!(d.questions[0].qtype, TYPE_A);
+        assert!(d.answers.is_empty());
+        assert_eq!(d.authorities[0].name, "com");
+        assert_eq!(d.authorities[0].ttl, 900);
+        assert_eq!(d.authorities[0].soa_minimum(), Some(300));
+
+        // Names in answers may point back into the question
+        let mut b = q.encode().unwrap();
+        b[7] = 1;
+        b.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x5d\xb8\xd8\x22");
+        let d = Message::decode(&b).unwrap();
+        assert_eq!(d.answers[0].name, "Example.com");
+        assert_eq!(d.answers[0].data, [93, 184, 216, 34]);
+    }
+
+    #[test]
+    fn message_invalid() {
+        let b = Message::query(1, "example.com", TYPE_A).encode().unwrap();
+
+        for n in 0..b.len() {
+            assert!(Message::decode(&b[..n]).is_err(), "{n}");
+        }
+        assert!(Message::query(1, &"a".repeat(64), TYPE_A).encode().is_err());
+    }
+}
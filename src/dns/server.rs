@@ -0,0 +1,292 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    time,
+};
+use tracing::{debug, error, info, warn};
+
+use super::{
+    message::{
+        Message, Record, CLASS_IN, FLAG_QR, FLAG_TC, RCODE_FORMERR, RCODE_NOERROR, RCODE_NXDOMAIN,
+        RCODE_SERVFAIL, TYPE_A, TYPE_AAAA,
+    },
+    upstream::{read_msg, write_msg},
+};
+use crate::{
+    acl::Acl,
+    metrics,
+    proxy::dial::{Client, Dialer, Target},
+    registry,
+    route::Action,
+    server::Settings,
+    shutdown::Shutdown,
+    timeout::Timeouts,
+};
+
+/// How long to keep an idle tcp connection open, https://www.rfc-editor.org/rfc/rfc7766#section-6.2.3
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest response sent over udp to clients that don't use EDNS.
+const UDP_SIZE: usize = 512;
+
+/// The largest response sent over udp to clients that do, https://www.dnsflagday.net/2020/
+const EDNS_UDP_SIZE: usize = 1232;
+
+/// Starts the DNS server on both udp and tcp.
+///
+/// Queries are routed like connections to the queried domain: rejected domains don't exist,
+/// tunneled ones are forwarded through the tunnel server, and the others are answered by the
+/// resolver, within `timeouts`.
+pub async fn start<A>(
+    addr: A,
+    acl: Acl,
+    dialer: Arc<Dialer>,
+    timeouts: Timeouts,
+    shutdown: Shutdown,
+) where
+    A: Into<SocketAddr>,
+{
+    let addr = addr.into();
+    let acl = Arc::new(acl);
+    let settings = Arc::new(Settings {
+        timeouts,
+        ..Settings::default()
+    });
+
+    tokio::join!(
+        serve_udp(
+            addr,
+            acl.clone(),
+            dialer.clone(),
+            settings.clone(),
+            shutdown.clone()
+        ),
+        serve_tcp(addr, acl, dialer, settings, shutdown),
+    );
+
+    info!("dns server stopped accepting new queries");
+}
+
+async fn serve_udp(
+    addr: SocketAddr,
+    acl: Arc<Acl>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    mut shutdown: Shutdown,
+) {
+    let socket = Arc::new(UdpSocket::bind(addr).await.expect("UdpSocket::bind"));
+    let mut buf = vec![0; 4096];
+
+    loop {
+        let r = tokio::select! {
+            r = socket.recv_from(&mut buf) => r,
+            _ = shutdown.recv() => break,
+        };
+
+        let (n, peer) = match r {
+            Ok(r) => r,
+            Err(e) => {
+                error!("An error occurred while calling socket.recv_from: {}", e);
+                continue;
+            }
+        };
+
+        if !acl.is_allowed(peer.ip()) {
+            warn!("{peer} - denied by acl");
+            continue;
+        }
+
+        let req = buf[..n].to_vec();
+        let guard = shutdown.track();
+        let socket = socket.clone();
+        let dialer = dialer.clone();
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            // Without EDNS the client can't take more than 512 bytes, and with it we don't
+            // bother reading how much more it can take
+            let max = if req.get(10..12) == Some(&[0, 0][..]) {
+                UDP_SIZE
+            } else {
+                EDNS_UDP_SIZE
+            };
+
+            if let Some(resp) = handle(&req, peer, &dialer, &settings, max).await {
+                if let Err(e) = socket.send_to(&resp, peer).await {
+                    debug!("{peer} - socket.send_to: {e}");
+                }
+            }
+            drop(guard);
+        });
+    }
+}
+
+async fn serve_tcp(
+    addr: SocketAddr,
+    acl: Arc<Acl>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    mut shutdown: Shutdown,
+) {
+    let l = TcpListener::bind(addr).await.expect("TcpListener::bind");
+
+    loop {
+        let r = tokio::select! {
+            r = l.accept() => r,
+            _ = shutdown.recv() => break,
+        };
+
+        match r {
+            Err(e) => {
+                error!("An error occurred while calling listener.accept: {}", e);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok((s, peer)) => {
+                if !acl.is_allowed(peer.ip()) {
+                    warn!("{peer} - denied by acl");
+                    continue;
+                }
+
+                let guard = shutdown.track();
+                let dialer = dialer.clone();
+                let settings = settings.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    handle_socket(s, peer, &dialer, &settings, shutdown).await;
+                    drop(guard);
+                });
+            }
+        }
+    }
+}
+
+/// Answers the queries sent over `s` one after another until the client goes idle.
+async fn handle_socket(
+    mut s: TcpStream,
+    peer: SocketAddr,
+    dialer: &Dialer,
+    settings: &Arc<Settings>,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let req = tokio::select! {
+            r = time::timeout(IDLE_TIMEOUT, read_msg(&mut s)) => match r {
+                Ok(Ok(req)) => req,
+                _ => return,
+            },
+            _ = shutdown.recv() => return,
+        };
+
+        if let Some(resp) = handle(&req, peer, dialer, settings, u16::MAX as usize).await {
+            if write_msg(&mut s, &resp).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Returns the response to the encoded query `b`, truncated to `max` bytes, or nothing if `b`
+/// isn't a query.
+async fn handle(
+    b: &[u8],
+    peer: SocketAddr,
+    dialer: &Dialer,
+    settings: &Arc<Settings>,
+    max: usize,
+) -> Option<Vec<u8>> {
+    let m = metrics::listener("dns");
+    m.accepted.inc();
+
+    let req = match Message::decode(b) {
+        Ok(m) if m.flags & FLAG_QR == 0 => m,
+        _ => {
+            m.failed.inc();
+            debug!("{peer} - invalid dns query");
+            return None;
+        }
+    };
+
+    let resp = match resolve(b, &req, peer, dialer, settings).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            m.failed.inc();
+            let name = req.questions.first().map_or("", |q| q.name.as_str());
+            warn!("{peer} - dns query {name}: {e:?}");
+            req.reply(RCODE_SERVFAIL).encode().ok()?
+        }
+    };
+
+    if resp.len() <= max {
+        return Some(resp);
+    }
+
+    // Have the client retry over tcp
+    let mut truncated = req.reply(RCODE_NOERROR);
+    truncated.flags |= FLAG_TC;
+    truncated.encode().ok()
+}
+
+async fn resolve(
+    b: &[u8],
+    req: &Message,
+    peer: SocketAddr,
+    dialer: &Dialer,
+    settings: &Arc<Settings>,
+) -> anyhow::Result<Vec<u8>> {
+    let q = match &req.questions[..] {
+        [q] => q,
+        _ => return req.reply(RCODE_FORMERR).encode(),
+    };
+
+    let name = q.name.trim_end_matches('.').to_ascii_lowercase();
+    let target = Target::Domain(name.clone(), 0);
+    let m = metrics::listener("dns");
+    let (_, action, _) = dialer
+        .route_for(&target, peer, None, settings.timeouts, m)
+        .await?;
+
+    match action {
+        Action::Reject => req.reply(RCODE_NXDOMAIN).encode(),
+
+        Action::Direct if q.qtype == TYPE_A || q.qtype == TYPE_AAAA => {
+            let a = dialer.resolver().answer(&name, q.qtype).await?;
+
+            let mut resp = req.reply(a.rcode);
+            resp.answers = a
+                .ips
+                .into_iter()
+                .map(|ip| Record {
+                    name: q.name.clone(),
+                    rtype: q.qtype,
+                    class: CLASS_IN,
+                    ttl: a.ttl.as_secs() as u32,
+                    data: match ip {
+                        IpAddr::V4(a) => a.octets().to_vec(),
+                        IpAddr::V6(a) => a.octets().to_vec(),
+                    },
+                })
+                .collect();
+            resp.encode()
+        }
+
+        Action::Direct => dialer.resolver().forward(b).await,
+
+        // Over TCP through the tunnel server or upstream proxy, which may resolve differently,
+        // over connections of their own
+        _ => {
+            let conn = registry::register(m.name, "dns", peer);
+            let client = Client::new(conn, "dns", m, settings.clone());
+            dialer
+                .resolver()
+                .forward_over(b, |a| {
+                    let client = &client;
+                    async move { dialer.connect_via(action, &Target::Addr(a), client).await }
+                })
+                .await
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{client, header, Request, StatusCode, Uri};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use tracing::error;
+
+use super::message::{Message, FLAG_TC};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A DNS server to send queries to, e.g. `8.8.8.8`, `udp://8.8.8.8:53`,
+/// `tcp://[2001:4860:4860::8888]`, `tls://dns.google` or `https://dns.google/dns-query`.
+#[derive(Clone, Debug)]
+pub enum Upstream {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    /// DNS over TLS, https://www.rfc-editor.org/rfc/rfc7858
+    Tls(Server),
+    /// DNS over HTTPS, https://www.rfc-editor.org/rfc/rfc8484
+    Https(Server),
+}
+
+/// A DNS server reached over TLS, which is known by the name its certificate is issued for.
+#[derive(Clone, Debug)]
+pub struct Server {
+    host: String,
+    port: u16,
+    path: String,
+    /// The address to connect to, unless `host` is an address itself it must be configured
+    /// explicitly, as resolving it would leak a plaintext query.
+    ip: Option<IpAddr>,
+}
+
+impl Upstream {
+    /// Returns the name of the server if its address still has to be bootstrapped.
+    pub fn bootstrap_name(&self) -> Option<&str> {
+        match self {
+            Upstream::Tls(s) | Upstream::Https(s) if s.ip.is_none() => Some(&s.host),
+            _ => None,
+        }
+    }
+
+    pub fn bootstrap(&mut self, ip: IpAddr) {
+        if let Upstream::Tls(s) | Upstream::Https(s) = self {
+            s.ip = Some(ip);
+        }
+    }
+
+    /// The address of the server.
+    pub fn addr(&self) -> anyhow::Result<SocketAddr> {
+        match self {
+            Upstream::Udp(a) | Upstream::Tcp(a) => Ok(*a),
+            Upstream::Tls(s) | Upstream::Https(s) => {
+                s.ip.map(|ip| SocketAddr::new(ip, s.port))
+                    .ok_or_else(|| anyhow!("addr: no bootstrap address: {}", s.host))
+            }
+        }
+    }
+
+    pub async fn query(&self, req: &Message) -> anyhow::Result<Message> {
+        let resp = Message::decode(&self.exchange(&req.encode()?).await?)?;
+        ensure!(resp.id == req.id, "query: mismatched id: {self}");
+        Ok(resp)
+    }
+
+    /// Sends the encoded query `req` and returns the encoded response as received.
+    pub async fn exchange(&self, req: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let f = async {
+            let addr = self.addr()?;
+
+            if let Upstream::Udp(_) = self {
+                let resp = query_udp(addr, req).await?;
+
+                // Retry over tcp unless the answer fit in a datagram
+                if resp.len() < 4 || u16::from_be_bytes([resp[2], resp[3]]) & FLAG_TC == 0 {
+                    return Ok(resp);
+                }
+            }
+
+            let s = TcpStream::connect(addr)
+                .await
+                .context("exchange: connect")?;
+            self.exchange_stream(s, req).await
+        };
+
+        time::timeout(TIMEOUT, f)
+            .await
+            .map_err(|_| anyhow!("exchange: timed out: {self}"))?
+    }
+
+    /// Like [`Upstream::exchange`], but over `s`, which is already connected to the server,
+    /// e.g. through a tunnel. Plain DNS servers are queried over tcp.
+    pub async fn exchange_over(&self, s: TcpStream, req: &[u8]) -> anyhow::Result<Vec<u8>> {
+        time::timeout(TIMEOUT, self.exchange_stream(s, req))
+            .await
+            .map_err(|_| anyhow!("exchange_over: timed out: {self}"))?
+    }
+
+    async fn exchange_stream(&self, mut s: TcpStream, req: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Upstream::Udp(_) | Upstream::Tcp(_) => {
+                write_msg(&mut s, req).await?;
+                read_msg(&mut s).await
+            }
+            Upstream::Tls(server) => {
+                let mut s = connect_tls(server, s).await?;
+                write_msg(&mut s, req).await?;
+                read_msg(&mut s).await
+            }
+            Upstream::Https(server) => {
+                query_https(server, connect_tls(server, s).await?, req).await
+            }
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, addr) = s.split_once("://").unwrap_or(("udp", s));
+
+        match scheme {
+            "udp" | "tcp" => {
+                let addr =
+                    parse_addr(addr, 53).with_context(|| format!("invalid dns server: {s}"))?;
+                Ok(if scheme == "udp" {
+                    Upstream::Udp(addr)
+                } else {
+                    Upstream::Tcp(addr)
+                })
+            }
+            "tls" | "https" => {
+                let uri = s
+                    .parse::<Uri>()
+                    .with_context(|| format!("invalid dns server: {s}"))?;
+                let host = uri
+                    .host()
+                    .ok_or_else(|| anyhow!("invalid dns server: {s}, missing host"))?
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_ascii_lowercase();
+
+                let server = Server {
+                    ip: host.parse().ok(),
+                    host,
+                    port: uri
+                        .port_u16()
+                        .unwrap_or(if scheme == "tls" { 853 } else { 443 }),
+                    // The well-known path of RFC 8484 unless another is given
+                    path: match uri.path() {
+                        "" | "/" => "/dns-query".to_string(),
+                        p => p.to_string(),
+                    },
+                };
+
+                Ok(if scheme == "tls" {
+                    Upstream::Tls(server)
+                } else {
+                    Upstream::Https(server)
+                })
+            }
+            _ => bail!("invalid dns server: {s}, unsupported scheme: {scheme}"),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Udp(a) => write!(f, "udp://{a}"),
+            Upstream::Tcp(a) => write!(f, "tcp://{a}"),
+            Upstream::Tls(s) => write!(f, "tls://{}:{}", s.host, s.port),
+            Upstream::Https(s) => write!(f, "https://{}:{}{}", s.host, s.port, s.path),
+        }
+    }
+}
+
+/// Parses `ip`, `ip:port`, `[ipv6]` or `[ipv6]:port`.
+pub fn parse_addr(s: &str, default_port: u16) -> anyhow::Result<SocketAddr> {
+    if let Ok(a) = s.parse() {
+        return Ok(a);
+    }
+
+    let ip = s.trim_start_matches('[').trim_end_matches(']');
+    Ok(SocketAddr::new(ip.parse()?, default_port))
+}
+
+async fn query_udp(addr: SocketAddr, req: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+
+    let socket = UdpSocket::bind(bind).await.context("query_udp: bind")?;
+    socket.connect(addr).await.context("query_udp: connect")?;
+    socket.send(req).await.context("query_udp: send")?;
+
+    let mut buf = vec![0; 4096];
+    loop {
+        let n = socket.recv(&mut buf).await.context("query_udp: recv")?;
+
+        // Ignore datagrams that aren't answers to this query
+        if n >= 12 && buf[..2] == req[..2] {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+    }
+}
+
+async fn query_https(
+    server: &Server,
+    s: TlsStream<TcpStream>,
+    req: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let (mut sender, conn) = client::conn::http1::handshake(s)
+        .await
+        .context("query_https: handshake")?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            error!("dns over https connection failed: {:?}", e);
+        }
+    });
+
+    let host = match server.port {
+        443 => server.host.clone(),
+        port => format!("{}:{}", server.host, port),
+    };
+
+    let http_req = Request::post(&server.path)
+        .header(header::HOST, host)
+        .header(header::CONTENT_TYPE, "application/dns-message")
+        .header(header::ACCEPT, "application/dns-message")
+        .body(Full::new(Bytes::copy_from_slice(req)))
+        .context("query_https: request")?;
+
+    let resp = sender
+        .send_request(http_req)
+        .await
+        .context("query_https: send_request")?;
+    ensure!(
+        resp.status() == StatusCode::OK,
+        "query_https: unexpected status: {}",
+        resp.status()
+    );
+
+    let body = resp
+        .into_body()
+        .collect()
+        .await
+        .context("query_https: read body")?
+        .to_bytes();
+    Ok(body.to_vec())
+}
+
+async fn connect_tls(server: &Server, s: TcpStream) -> anyhow::Result<TlsStream<TcpStream>> {
+    let name = ServerName::try_from(server.host.as_str()).context("connect_tls: server name")?;
+    TlsConnector::from(tls_config())
+        .connect(name, s)
+        .await
+        .context("connect_tls: handshake")
+}
+
+/// Verifies servers against the Mozilla root certificates, which works the same on any host.
+fn tls_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    CONFIG
+        .get_or_init(|| {
+            let mut roots = RootCertStore::empty();
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+
+            Arc::new(
+                ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            )
+        })
+        .clone()
+}
+
+/// Reads a message from a stream transport, where each message is prefixed with its length.
+pub async fn read_msg<S>(s: &mut S) -> anyhow::Result<Vec<u8>>
+where
+    S: AsyncReadExt + Unpin,
+{
+    let len = s.read_u16().await.context("read_msg: read length")? as usize;
+    let mut b = vec![0; len];
+    s.read_exact(&mut b)
+        .await
+        .context("read_msg: read message")?;
+    Ok(b)
+}
+
+/// Writes a message to a stream transport, prefixed with its length.
+pub async fn write_msg<S>(s: &mut S, b: &[u8]) -> anyhow::Result<()>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let mut buf = Vec::with_capacity(2 + b.len());
+    buf.extend_from_slice(&(b.len() as u16).to_be_bytes());
+    buf.extend_from_slice(b);
+    s.write_all(&buf).await.context("write_msg")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Upstream;
+
+    #[test]
+    fn parse() {
+        let cases = [
+            ("8.8.8.8", "udp://8.8.8.8:53"),
+            ("udp://8.8.8.8:5353", "udp://8.8.8.8:5353"),
+            (
+                "tcp://[2001:4860:4860::8888]",
+                "tcp://[2001:4860:4860::8888]:53",
+            ),
+            ("tls://dns.google", "tls://dns.google:853"),
+            ("tls://1.1.1.1:8853", "tls://1.1.1.1:8853"),
+            ("https://dns.google", "https://dns.google:443/dns-query"),
+            ("https://dns.google/", "https://dns.google:443/dns-query"),
+            (
+                "https://DNS.Google/dns-query",
+                "https://dns.google:443/dns-query",
+            ),
+            (
+                "https://dns.example:8443/resolve",
+                "https://dns.example:8443/resolve",
+            ),
+        ];
+
+        for (s, expected) in cases {
+            let u = s.parse::<Upstream>().unwrap();
+            assert_eq!(u.to_string(), expected, "{s}");
+        }
+
+        let bootstrap = |s: &str| {
+            s.parse::<Upstream>()
+                .unwrap()
+                .bootstrap_name()
+                .map(String::from)
+        };
+        assert_eq!(
+            bootstrap("https://dns.google").as_deref(),
+            Some("dns.google")
+        );
+        assert_eq!(bootstrap("tls://[2606:4700:4700::1111]"), None);
+        assert_eq!(bootstrap("8.8.8.8"), None);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in [
+            "",
+            "dns.google",
+            "udp://dns.google",
+            "tcp://8.8.8.8:x",
+            "quic://dns.google",
+            "https://",
+        ] {
+            assert!(s.parse::<Upstream>().is_err(), "{s}");
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{
+    policy::Denied,
+    proxy::dial::{Client, Target},
+    route::Action,
+    server::Settings,
+};
+
+/// Lets programs embedding the proxy servers observe the connections they proxy, and the http
+/// requests, and intercept them along the way. Every hook does nothing by default.
+///
+/// The hooks are called from the tasks of the connections, so they shouldn't block.
+pub trait Hooks: Send + Sync {
+    /// Called once the server `listener` accepts a connection from `peer` its acl allows,
+    /// which is closed right away if denied.
+    fn on_accept(&self, _listener: &str, _peer: SocketAddr) -> Result<(), Denied> {
+        Ok(())
+    }
+
+    /// Called once `client` authenticates, as `client.user`.
+    fn on_authenticated(&self, _client: &Client) {}
+
+    /// Called once the route of `client` to `client.target` is decided to take `action`,
+    /// either to connect to another target the same way, or to deny the connection.
+    fn on_route_decided(
+        &self,
+        _client: &Client,
+        _action: &Action,
+    ) -> Result<Option<Target>, Denied> {
+        Ok(None)
+    }
+
+    /// Called once `client` is connected to its destination, through `client.remote`.
+    fn on_connected(&self, _client: &Client) {}
+
+    /// Called once the connection of `client`, or one of its http requests, is over, whether it
+    /// got anywhere or not.
+    fn on_closed(&self, _client: &Client, _stats: &Stats) {}
+}
+
+/// What became of a connection, or an http request.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    /// The http status code of the outcome, see [`crate::access_log::status`].
+    pub status: u16,
+    /// The number of bytes sent up to and down from the destination.
+    pub up: u64,
+    pub down: u64,
+    pub duration: Duration,
+}
+
+pub(crate) fn accept(settings: &Settings, listener: &str, peer: SocketAddr) -> Result<(), Denied> {
+    settings
+        .hooks
+        .as_ref()
+        .map_or(Ok(()), |h| h.on_accept(listener, peer))
+}
+
+pub(crate) fn authenticated(client: &Client) {
+    if let Some(h) = &client.settings.hooks {
+        h.on_authenticated(client);
+    }
+}
+
+pub(crate) fn route_decided(client: &Client, action: &Action) -> Result<Option<Target>, Denied> {
+    client
+        .settings
+        .hooks
+        .as_ref()
+        .map_or(Ok(None), |h| h.on_route_decided(client, action))
+}
+
+pub(crate) fn connected(client: &Client) {
+    if let Some(h) = &client.settings.hooks {
+        h.on_connected(client);
+    }
+}
+
+pub(crate) fn closed(client: &Client, status: u16, (up, down): (u64, u64)) {
+    if let Some(h) = &client.settings.hooks {
+        let stats = Stats {
+            status,
+            up,
+            down,
+            duration: client.accepted.elapsed(),
+        };
+        h.on_closed(client, &stats);
+    }
+}
@@ -0,0 +1,38 @@
+//! A proxy server for SOCKS5, SOCKS4, HTTP and TLS clients, usable as a library through the
+//! [`Socks5Server`], [`HttpProxyServer`], [`TunnelServer`] and [`MixedServer`] builders, which
+//! share a [`Dialer`] to connect to the destinations their clients ask for.
+
+pub mod access_log;
+pub mod acl;
+pub mod admin;
+pub mod auth;
+pub mod cidr;
+mod date;
+pub mod dns;
+pub mod hooks;
+mod json;
+pub mod limit;
+pub mod metrics;
+pub mod policy;
+pub mod proxy;
+pub mod quota;
+mod registry;
+pub mod route;
+mod ruleset;
+mod server;
+pub mod shutdown;
+pub mod timeout;
+
+use std::{future::Future, pin::Pin};
+
+pub use proxy::dial::Dialer;
+pub use server::{
+    Http, HttpProxyServer, Mixed, MixedServer, Server, Socks5, Socks5Server, Tunnel, TunnelServer,
+};
+#[cfg(target_os = "linux")]
+pub use server::{Transparent, TransparentServer};
+pub use shutdown::Shutdown;
+
+/// The futures returned by the trait objects that plug into the servers, e.g.
+/// [`auth::Authenticator`] and [`proxy::connect::Connector`].
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
@@ -0,0 +1,503 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, Weak},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Sleep},
+};
+
+use crate::{
+    metrics,
+    proxy::dial::Client,
+    registry::{self, Conn},
+    server::Settings,
+};
+
+/// The error of connections over the connection limits.
+#[derive(Debug)]
+pub struct OverLimit(pub String);
+
+impl fmt::Display for OverLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for OverLimit {}
+
+/// The bandwidth and connection limits, of which there are none by default.
+///
+/// The connection limits count the connections of all the servers of the process, whichever
+/// servers they are set on.
+#[derive(Clone, Debug)]
+pub struct LimitOptions {
+    pub bandwidth: Vec<Bandwidth>,
+    /// For how many seconds a connection may go at full speed after being idle.
+    pub bandwidth_burst: f64,
+    /// The maximum numbers of connections served at once, 0 for no limit.
+    pub max_connections: usize,
+    pub max_connections_per_client: usize,
+    pub max_connections_per_user: usize,
+    /// The maximum number of new connections per second from each client address, 0 for no
+    /// limit.
+    pub max_connection_rate: u64,
+}
+
+impl Default for LimitOptions {
+    fn default() -> Self {
+        LimitOptions {
+            bandwidth: Vec::new(),
+            bandwidth_burst: 1.0,
+            max_connections: 0,
+            max_connections_per_client: 0,
+            max_connections_per_user: 0,
+            max_connection_rate: 0,
+        }
+    }
+}
+
+/// A bandwidth limit on the connections in its scope, e.g. `client=1M/10M`.
+#[derive(Clone, Debug)]
+pub struct Bandwidth {
+    scope: Scope,
+    /// In bytes per second, `None` for no limit.
+    up: Option<u64>,
+    down: Option<u64>,
+}
+
+/// Which connections share a bandwidth limit.
+#[derive(Clone, Debug)]
+enum Scope {
+    /// All the connections.
+    Global,
+    /// The connections accepted by the named listener, e.g. `socks5`.
+    Listener(String),
+    /// The connections of each authenticated user.
+    User,
+    /// The connections from each client IP address.
+    Client,
+}
+
+/// The limits servers enforce on the connections they serve, see [`LimitOptions`].
+pub struct Limits {
+    global: Option<Arc<Pair>>,
+    listeners: HashMap<String, Arc<Pair>>,
+    user: Option<Rates>,
+    client: Option<Rates>,
+    /// Only live as long as their connections.
+    users: Mutex<HashMap<String, Weak<Pair>>>,
+    clients: Mutex<HashMap<IpAddr, Weak<Pair>>>,
+    burst: f64,
+    /// The maximum numbers of connections, 0 for no limit.
+    max_connections: usize,
+    max_connections_per_client: usize,
+    max_connections_per_user: usize,
+    /// The buckets of new connections per second from each client address.
+    connection_rate: u64,
+    connection_rates: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Clone, Copy)]
+struct Rates {
+    up: Option<u64>,
+    down: Option<u64>,
+}
+
+/// The upload and download buckets of a scope.
+struct Pair {
+    up: Option<Bucket>,
+    down: Option<Bucket>,
+}
+
+/// A token bucket, https://en.wikipedia.org/wiki/Token_bucket
+///
+/// It goes into debt rather than splitting reads and writes, which then wait it out.
+struct Bucket {
+    /// In bytes per second.
+    rate: f64,
+    /// The bytes that may be sent at once after an idle period.
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+/// Limits the bandwidth of `s`, a stream from `client`, to the limits in every scope `client`
+/// belongs to. Reading from `s` counts as upload and writing to it as download.
+pub fn limit<S>(s: S, client: &Client) -> Limited<S> {
+    let pairs = client
+        .settings
+        .limits
+        .as_ref()
+        .map(|l| l.pairs(client))
+        .unwrap_or_default();
+    Limited {
+        inner: s,
+        pairs,
+        read_wait: None,
+        write_wait: None,
+    }
+}
+
+/// Returns whether the bandwidth of `client` is limited in any scope.
+pub fn applies(client: &Client) -> bool {
+    client.settings.limits.as_ref().is_some_and(|l| {
+        l.global.is_some()
+            || l.listeners.contains_key(client.metrics.name)
+            || l.user.is_some() && client.user.is_some()
+            || l.client.is_some()
+    })
+}
+
+/// Registers a new connection from `peer` on the listener of `m`, before it does any
+/// handshake, unless it would go over the total, per client or rate limits.
+///
+/// Called from the accept loops, so that a burst of connections can't all get past the limits
+/// before the tasks serving them have registered any.
+pub(crate) fn accept(
+    settings: &Settings,
+    m: &'static metrics::Listener,
+    peer: SocketAddr,
+) -> Result<Arc<Conn>, OverLimit> {
+    let Some(l) = &settings.limits else {
+        return Ok(registry::register(m.name, m.name, peer));
+    };
+
+    let r = registry::register_if(m.name, m.name, peer, |total, from_ip| {
+        l.accept(peer.ip(), total, from_ip)
+    });
+    if r.is_err() {
+        m.limited.inc();
+    }
+    r
+}
+
+/// Fails if taking on a new connection to a destination for `client` would go over the limit
+/// of its user, counting the connection of `client` as already open.
+pub fn admit(client: &Client) -> Result<(), OverLimit> {
+    let Some(l) = &client.settings.limits else {
+        return Ok(());
+    };
+
+    let r = l.admit(client);
+    if r.is_err() {
+        client.metrics.limited.inc();
+    }
+    r
+}
+
+impl Limits {
+    pub fn new(c: &LimitOptions) -> Self {
+        let mut limits = Limits {
+            global: None,
+            listeners: HashMap::new(),
+            user: None,
+            client: None,
+            users: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+            burst: c.bandwidth_burst,
+            max_connections: c.max_connections,
+            max_connections_per_client: c.max_connections_per_client,
+            max_connections_per_user: c.max_connections_per_user,
+            connection_rate: c.max_connection_rate,
+            connection_rates: Mutex::new(HashMap::new()),
+        };
+
+        for b in &c.bandwidth {
+            let rates = Rates {
+                up: b.up,
+                down: b.down,
+            };
+            match &b.scope {
+                Scope::Global => limits.global = Some(Arc::new(limits.pair(rates))),
+                Scope::Listener(name) => {
+                    let pair = Arc::new(limits.pair(rates));
+                    limits.listeners.insert(name.clone(), pair);
+                }
+                Scope::User => limits.user = Some(rates),
+                Scope::Client => limits.client = Some(rates),
+            }
+        }
+
+        limits
+    }
+
+    /// Given the numbers of connections in total and from `ip`, not counting the new one.
+    fn accept(&self, ip: IpAddr, total: usize, from_ip: usize) -> Result<(), OverLimit> {
+        let over = |n: usize, max: usize| max > 0 && n >= max;
+
+        if over(total, self.max_connections) {
+            return Err(OverLimit(format!("too many connections: {total}")));
+        }
+        if over(from_ip, self.max_connections_per_client) {
+            return Err(OverLimit(format!(
+                "too many connections from {ip}: {from_ip}"
+            )));
+        }
+
+        if self.connection_rate > 0 {
+            let mut rates = self.connection_rates.lock().unwrap();
+
+            // Forget the clients that have been quiet for long enough once in a while
+            if rates.len() >= 1024 && rates.len().is_power_of_two() {
+                rates.retain(|_, b| !b.is_full());
+            }
+
+            let rate = self.connection_rate;
+            let b = rates.entry(ip).or_insert_with(|| Bucket::new(rate, 1.0));
+            if !b.try_take(1) {
+                return Err(OverLimit(format!("too many new connections from {ip}")));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn admit(&self, client: &Client) -> Result<(), OverLimit> {
+        let Some(user) = client.user.as_deref() else {
+            return Ok(());
+        };
+
+        let (_, _, of_user) = registry::count(client.addr.ip(), Some(user));
+        if self.max_connections_per_user > 0 && of_user > self.max_connections_per_user {
+            return Err(OverLimit(format!(
+                "too many connections of {user}: {of_user}"
+            )));
+        }
+        Ok(())
+    }
+
+    fn pair(&self, rates: Rates) -> Pair {
+        Pair {
+            up: rates.up.map(|r| Bucket::new(r, self.burst)),
+            down: rates.down.map(|r| Bucket::new(r, self.burst)),
+        }
+    }
+
+    fn pairs(&self, client: &Client) -> Vec<Arc<Pair>> {
+        let mut pairs = Vec::new();
+        pairs.extend(self.global.clone());
+        pairs.extend(self.listeners.get(client.metrics.name).cloned());
+
+        if let (Some(rates), Some(user)) = (self.user, &client.user) {
+            pairs.push(self.shared(&self.users, user.clone(), rates));
+        }
+        if let Some(rates) = self.client {
+            pairs.push(self.shared(&self.clients, client.addr.ip(), rates));
+        }
+
+        pairs
+    }
+
+    /// Returns the pair of `key`, shared by all of its connections.
+    fn shared<K>(&self, m: &Mutex<HashMap<K, Weak<Pair>>>, key: K, rates: Rates) -> Arc<Pair>
+    where
+        K: std::hash::Hash + Eq,
+    {
+        let mut m = m.lock().unwrap();
+        if let Some(p) = m.get(&key).and_then(Weak::upgrade) {
+            return p;
+        }
+
+        // Forget the keys whose connections are all gone once in a while
+        if m.len() >= 1024 && m.len().is_power_of_two() {
+            m.retain(|_, p| p.strong_count() > 0);
+        }
+
+        let p = Arc::new(self.pair(rates));
+        m.insert(key, Arc::downgrade(&p));
+        p
+    }
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: f64) -> Self {
+        let rate = rate as f64;
+        let capacity = rate * burst;
+        Bucket {
+            rate,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Takes `n` tokens, returning how long to wait for the bucket to get out of debt.
+    fn take(&self, n: usize) -> Duration {
+        let mut s = self.state.lock().unwrap();
+        let tokens = self.refill(&mut s) - n as f64;
+        s.0 = tokens;
+
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+
+    /// Takes `n` tokens unless that would put the bucket into debt.
+    fn try_take(&self, n: usize) -> bool {
+        let mut s = self.state.lock().unwrap();
+        let tokens = self.refill(&mut s);
+        if tokens < n as f64 {
+            return false;
+        }
+
+        s.0 = tokens - n as f64;
+        true
+    }
+
+    fn is_full(&self) -> bool {
+        let mut s = self.state.lock().unwrap();
+        self.refill(&mut s) >= self.capacity
+    }
+
+    /// Adds the tokens earned since the last refill and returns how many there are.
+    fn refill(&self, (tokens, last): &mut (f64, Instant)) -> f64 {
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.capacity);
+        *last = now;
+        *tokens
+    }
+}
+
+/// A stream whose bandwidth is limited, see [`limit`].
+pub struct Limited<S> {
+    inner: S,
+    pairs: Vec<Arc<Pair>>,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Limited<S> {
+    /// Takes `n` tokens from the chosen bucket of every pair, returning how long to wait.
+    fn take(&self, n: usize, bucket: fn(&Pair) -> Option<&Bucket>) -> Option<Pin<Box<Sleep>>> {
+        let wait = self
+            .pairs
+            .iter()
+            .filter_map(|p| bucket(p))
+            .map(|b| b.take(n))
+            .max()
+            .unwrap_or_default();
+
+        (!wait.is_zero()).then(|| Box::pin(time::sleep(wait)))
+    }
+}
+
+fn wait(cx: &mut Context<'_>, w: &mut Option<Pin<Box<Sleep>>>) -> Poll<()> {
+    if let Some(sleep) = w {
+        ready!(sleep.as_mut().poll(cx));
+        *w = None;
+    }
+    Poll::Ready(())
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Limited<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(wait(cx, &mut self.read_wait));
+
+        let n = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - n;
+
+        if read > 0 {
+            self.read_wait = self.take(read, |p| p.up.as_ref());
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Limited<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(wait(cx, &mut self.write_wait));
+
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if n > 0 {
+            self.write_wait = self.take(n, |p| p.down.as_ref());
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl FromStr for Bandwidth {
+    type Err = anyhow::Error;
+
+    /// Parses `<SCOPE>=<UP>[/<DOWN>]`, where a rate of 0 means no limit.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scope, rates) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid bandwidth limit: {s}, expected <SCOPE>=<RATE>"))?;
+
+        let scope = match scope.split_once(':') {
+            None if scope == "global" => Scope::Global,
+            None if scope == "user" => Scope::User,
+            None if scope == "client" => Scope::Client,
+            Some(("listener", name)) if !name.is_empty() => Scope::Listener(name.to_string()),
+            _ => bail!(
+                "invalid bandwidth limit: {s}, expected a scope of global, listener:<NAME>, user \
+                 or client"
+            ),
+        };
+
+        let (up, down) = rates.split_once('/').unwrap_or((rates, rates));
+        let rate = |r: &str| {
+            parse_bytes(r)
+                .map(|r| (r > 0).then_some(r))
+                .with_context(|| format!("invalid bandwidth limit: {s}"))
+        };
+
+        Ok(Bandwidth {
+            scope,
+            up: rate(up)?,
+            down: rate(down)?,
+        })
+    }
+}
+
+/// Parses a number of bytes with an optional K, M, G or T suffix, in powers of 1024.
+pub fn parse_bytes(s: &str) -> anyhow::Result<u64> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+
+    let n = n
+        .parse::<u64>()
+        .with_context(|| format!("invalid size: {s}"))?;
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => bail!("invalid size: {s}, expected a K, M, G or T suffix"),
+    };
+
+    n.checked_mul(unit)
+        .ok_or_else(|| anyhow!("invalid size: {s}, too large"))
+}
@@ -1,39 +1,334 @@
-use std::net::IpAddr;
-use tokio::signal;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use bubble::{
+    access_log::{AccessLog, AccessLogOptions},
+    acl::Acl,
+    admin,
+    auth::{self, Authenticator},
+    dns::{self, Resolver, ResolverOptions},
+    limit::{LimitOptions, Limits},
+    metrics,
+    policy::{Policy, PolicyOptions},
+    proxy::{self, ListenOptions},
+    quota::{self, QuotaOptions, Quotas},
+    route::Router,
+    shutdown,
+    timeout::Timeouts,
+    Dialer, HttpProxyServer, MixedServer, Server, Shutdown, Socks5Server, TunnelServer,
+};
+use tracing::{info, warn};
 
 mod cli;
 mod init;
-mod proxy;
 
 #[tokio::main]
 async fn main() {
     init::init();
 
     let cli = cli::parse();
+    let shutdown = Shutdown::new();
+    let shared = Shared::new(&cli);
+
+    if cli.splice {
+        #[cfg(target_os = "linux")]
+        proxy::splice::enable();
+        #[cfg(not(target_os = "linux"))]
+        warn!("splice is only supported on Linux, relaying with buffers");
+    }
+
+    if let Some(q) = &shared.quotas {
+        tokio::spawn(quota::run(q.clone(), shutdown.clone()));
+    }
+
+    let router = Router::new(
+        cli.route.rules.clone(),
+        cli.route.default_route.clone(),
+        &cli.route.rule_sets,
+    )
+    .expect("route");
+    tokio::spawn(router.watch(Duration::from_secs(cli.route.rule_set_interval)));
+
+    let dialer = Arc::new(Dialer::new(
+        Policy::new(&policy_options(&cli.policy)),
+        router,
+        Resolver::new(&resolver_options(&cli.dns)).expect("dns"),
+        cli.outbound_tcp.clone().unwrap_or_default(),
+        cli.auth.tunnel.clone(),
+    ));
+    let auth = authenticator(&cli.auth).expect("auth");
+    let listen = listen_options(&cli.listen);
 
     if cli.proxy.socks5.enabled {
-        tokio::spawn(proxy::socks5::start((
-            cli.proxy.socks5.ip.parse::<IpAddr>().expect("socks5-ip"),
-            cli.proxy.socks5.port,
-        )));
+        let c = &cli.proxy.socks5;
+        let mut server = Socks5Server::new(
+            (c.ip.parse::<IpAddr>().expect("socks5-ip"), c.port),
+            dialer.clone(),
+        )
+        .listen(listen)
+        .tcp(c.tcp.clone().unwrap_or_default())
+        .acl(Acl::new(&c.allow, &c.deny, &c.allow_files, &c.deny_files).expect("socks5 acl"))
+        .shutdown(shutdown.clone());
+        if let Some(a) = &auth {
+            server = server.auth(a.clone());
+        }
+        server = shared.apply(server);
+        tokio::spawn(server.serve());
     }
 
     if cli.proxy.http.enabled {
-        tokio::spawn(proxy::http::start(
-            (
-                cli.proxy.http.ip.parse::<IpAddr>().expect("http-ip"),
-                cli.proxy.http.port,
-            ),
-            cli.proxy.http.tunnel_addr,
-        ));
+        let c = &cli.proxy.http;
+        let mut server = HttpProxyServer::new(
+            (c.ip.parse::<IpAddr>().expect("http-ip"), c.port),
+            dialer.clone(),
+        )
+        .listen(listen)
+        .tcp(c.tcp.clone().unwrap_or_default())
+        .acl(Acl::new(&c.allow, &c.deny, &c.allow_files, &c.deny_files).expect("http acl"))
+        .shutdown(shutdown.clone());
+        if let Some(a) = &auth {
+            server = server.auth(a.clone());
+        }
+        server = shared.apply(server);
+        if let Some(a) = &c.tunnel_addr {
+            server = server.tunnel_addr(a);
+        }
+        tokio::spawn(server.serve());
     }
 
     if cli.proxy.tunnel.enabled {
-        tokio::spawn(proxy::tunnel::start((
-            cli.proxy.tunnel.ip.parse::<IpAddr>().expect("tunnel-ip"),
-            cli.proxy.tunnel.port,
-        )));
+        let c = &cli.proxy.tunnel;
+        let mut server = TunnelServer::new(
+            (c.ip.parse::<IpAddr>().expect("tunnel-ip"), c.port),
+            dialer.clone(),
+        )
+        .listen(listen)
+        .tcp(c.tcp.clone().unwrap_or_default())
+        .acl(Acl::new(&c.allow, &c.deny, &c.allow_files, &c.deny_files).expect("tunnel acl"))
+        .shutdown(shutdown.clone());
+        if let Some(a) = &auth {
+            server = server.auth(a.clone());
+        }
+        server = shared.apply(server);
+        tokio::spawn(server.serve());
+    }
+
+    if cli.proxy.mixed.enabled {
+        let c = &cli.proxy.mixed;
+        let mut server = MixedServer::new(
+            (c.ip.parse::<IpAddr>().expect("mixed-ip"), c.port),
+            dialer.clone(),
+        )
+        .listen(listen)
+        .tcp(c.tcp.clone().unwrap_or_default())
+        .acl(Acl::new(&c.allow, &c.deny, &c.allow_files, &c.deny_files).expect("mixed acl"))
+        .shutdown(shutdown.clone());
+        if let Some(a) = &auth {
+            server = server.auth(a.clone());
+        }
+        server = shared.apply(server);
+        if let Some(a) = &c.tunnel_addr {
+            server = server.tunnel_addr(a);
+        }
+        tokio::spawn(server.serve());
+    }
+
+    if cli.proxy.transparent.enabled {
+        #[cfg(target_os = "linux")]
+        {
+            let c = &cli.proxy.transparent;
+            let server = bubble::TransparentServer::new(
+                (c.ip.parse::<IpAddr>().expect("transparent-ip"), c.port),
+                dialer.clone(),
+            )
+            .listen(listen)
+            .tcp(c.tcp.clone().unwrap_or_default())
+            .tproxy(c.tproxy)
+            .acl(
+                Acl::new(&c.allow, &c.deny, &c.allow_files, &c.deny_files)
+                    .expect("transparent acl"),
+            )
+            .shutdown(shutdown.clone());
+            tokio::spawn(shared.apply(server).serve());
+        }
+        #[cfg(not(target_os = "linux"))]
+        warn!("the transparent proxy server is only supported on Linux");
+    }
+
+    if cli.proxy.dns_server.enabled {
+        let c = &cli.proxy.dns_server;
+        tokio::spawn(dns::server::start(
+            (c.ip.parse::<IpAddr>().expect("dns-server-ip"), c.port),
+            Acl::new(&c.allow, &c.deny, &c.allow_files, &c.deny_files).expect("dns-server acl"),
+            dialer.clone(),
+            shared.timeouts,
+            shutdown.clone(),
+        ));
     }
 
-    signal::ctrl_c().await.expect("signal::ctrl_c");
+    if cli.metrics.enabled {
+        let c = &cli.metrics;
+        tokio::spawn(metrics::start(
+            (c.ip.parse::<IpAddr>().expect("metrics-ip"), c.port),
+            shutdown.clone(),
+        ));
+    }
+
+    if cli.admin.enabled {
+        let c = &cli.admin;
+        tokio::spawn(admin::start(
+            (c.ip.parse::<IpAddr>().expect("admin-ip"), c.port),
+            c.token.clone().expect("admin-token"),
+            format!("{cli:#?}\n"),
+            shutdown.clone(),
+        ));
+    }
+
+    shutdown::signal().await;
+
+    info!(
+        "shutting down, waiting up to {}s for {} active connection(s) to finish",
+        cli.shutdown_timeout,
+        shutdown.active()
+    );
+    shutdown.trigger();
+
+    tokio::select! {
+        n = shutdown.drain(Duration::from_secs(cli.shutdown_timeout)) => {
+            if n > 0 {
+                warn!("drain timeout elapsed, force-closing {n} connection(s)");
+            } else {
+                info!("all connections finished");
+            }
+        }
+        _ = shutdown::signal() => {
+            warn!("received a second signal, force-closing {} connection(s)", shutdown.active());
+        }
+    }
+
+    if let Some(q) = &shared.quotas {
+        q.save();
+    }
+}
+
+/// The limits, quotas, timeouts and access log given on the command line, which all the servers
+/// share.
+struct Shared {
+    limits: Arc<Limits>,
+    quotas: Option<Arc<Quotas>>,
+    timeouts: Timeouts,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl Shared {
+    fn new(cli: &cli::Cli) -> Self {
+        let c = &cli.quota;
+        // Without quotas or a file to save the usage to, users aren't accounted
+        let quotas = (!c.quotas.is_empty() || c.file.is_some())
+            .then(|| Quotas::new(&quota_options(c)).expect("quota"));
+        let access_log =
+            access_log_options(&cli.access_log).map(|c| AccessLog::open(&c).expect("access-log"));
+
+        Shared {
+            limits: Arc::new(Limits::new(&limit_options(&cli.limit))),
+            quotas: quotas.map(Arc::new),
+            timeouts: timeouts(&cli.timeout),
+            access_log: access_log.map(Arc::new),
+        }
+    }
+
+    fn apply<P>(&self, mut server: Server<P>) -> Server<P> {
+        server = server.limits(self.limits.clone()).timeouts(self.timeouts);
+        if let Some(q) = &self.quotas {
+            server = server.quotas(q.clone());
+        }
+        if let Some(l) = &self.access_log {
+            server = server.access_log(l.clone());
+        }
+        server
+    }
+}
+
+fn listen_options(c: &cli::Listen) -> ListenOptions {
+    ListenOptions {
+        acceptors: c.acceptors,
+        backlog: c.backlog,
+    }
+}
+
+fn policy_options(c: &cli::Policy) -> PolicyOptions {
+    PolicyOptions {
+        allow_dst: c.allow_dst.clone(),
+        deny_dst: c.deny_dst.clone(),
+        allow_ports: c.allow_port.clone(),
+        deny_ports: c.deny_port.clone(),
+        allow_domains: c.allow_domain.clone(),
+        deny_domains: c.deny_domain.clone(),
+        allow_private_dst: c.allow_private_dst,
+    }
+}
+
+fn resolver_options(c: &cli::Dns) -> ResolverOptions {
+    ResolverOptions {
+        servers: c.servers.clone(),
+        hosts: c.hosts.clone(),
+        hosts_files: c.hosts_file.clone(),
+        prefer: c.dns_prefer,
+    }
+}
+
+fn access_log_options(c: &cli::AccessLog) -> Option<AccessLogOptions> {
+    Some(AccessLogOptions {
+        path: c.path.clone()?,
+        format: c.format,
+        max_size: c.max_size * 1024 * 1024,
+        rotate: c.rotate,
+        keep: c.keep,
+    })
+}
+
+fn limit_options(c: &cli::Limit) -> LimitOptions {
+    LimitOptions {
+        bandwidth: c.bandwidth.clone(),
+        bandwidth_burst: c.bandwidth_burst,
+        max_connections: c.max_connections,
+        max_connections_per_client: c.max_connections_per_client,
+        max_connections_per_user: c.max_connections_per_user,
+        max_connection_rate: c.max_connection_rate,
+    }
+}
+
+fn quota_options(c: &cli::Quota) -> QuotaOptions {
+    QuotaOptions {
+        quotas: c.quotas.clone(),
+        reset: c.reset,
+        file: c.file.clone(),
+        warn: c.warn.clone(),
+        cut: c.cut,
+    }
+}
+
+fn timeouts(c: &cli::Timeout) -> Timeouts {
+    let secs = |s: u64| (s > 0).then(|| Duration::from_secs(s));
+    Timeouts {
+        handshake: secs(c.handshake),
+        dns: secs(c.dns),
+        connect: secs(c.connect),
+        idle: secs(c.idle),
+    }
+}
+
+/// Returns the authenticator given on the command line, if any.
+fn authenticator(c: &cli::Auth) -> anyhow::Result<Option<Arc<dyn Authenticator>>> {
+    if !c.users.is_empty() {
+        return Ok(Some(Arc::new(auth::StaticUsers::new(
+            c.users.iter().cloned(),
+        ))));
+    }
+    if let Some(path) = &c.htpasswd {
+        return Ok(Some(Arc::new(auth::Htpasswd::load(path)?)));
+    }
+    if let Some(command) = &c.command {
+        return Ok(Some(Arc::new(auth::Command::new(command.clone()))));
+    }
+    Ok(None)
 }
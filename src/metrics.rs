@@ -0,0 +1,386 @@
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{body, server, service::service_fn, Method, Request, Response, StatusCode};
+use tokio::{net::TcpListener, time};
+use tracing::{error, info};
+
+use crate::{shutdown::Shutdown, timeout::Stage};
+
+/// The upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    listeners: Vec::new(),
+    tunnels: Vec::new(),
+});
+
+/// The answers of the DNS resolver, served from its cache or not.
+pub static DNS_CACHE_HITS: Counter = Counter::new();
+pub static DNS_CACHE_MISSES: Counter = Counter::new();
+
+struct Registry {
+    listeners: Vec<(&'static str, &'static Listener)>,
+    tunnels: Vec<(String, &'static Tunnel)>,
+}
+
+/// The metrics of a listener, shared by all of its connections.
+#[derive(Default)]
+pub struct Listener {
+    pub name: &'static str,
+    pub accepted: Counter,
+    pub active: Gauge,
+    pub failed: Counter,
+    pub auth_failures: Counter,
+    /// Connections rejected by the connection limits.
+    pub limited: Counter,
+    /// Connections closed as a stage of theirs took too long, see [`Stage`].
+    pub handshake_timeouts: Counter,
+    pub dns_timeouts: Counter,
+    pub connect_timeouts: Counter,
+    pub idle_timeouts: Counter,
+    pub bytes_up: Counter,
+    pub bytes_down: Counter,
+    /// From accepting a connection to learning its destination.
+    pub handshake: Histogram,
+    /// From starting to connect to a destination to being connected.
+    pub connect: Histogram,
+}
+
+/// The metrics of the connections to a tunnel server.
+#[derive(Default)]
+pub struct Tunnel {
+    pub connected: Counter,
+    pub failed: Counter,
+    /// Connections open to the tunnel server.
+    pub open: Gauge,
+}
+
+/// Returns the metrics of the listener named `name`, e.g. `socks5`.
+pub fn listener(name: &'static str) -> &'static Listener {
+    let mut r = REGISTRY.lock().unwrap();
+    if let Some((_, l)) = r.listeners.iter().find(|(n, _)| *n == name) {
+        return l;
+    }
+
+    let l = Box::leak(Box::new(Listener {
+        name,
+        ..Default::default()
+    }));
+    r.listeners.push((name, l));
+    l
+}
+
+/// Returns the metrics of the tunnel server at `addr`.
+pub fn tunnel(addr: &str) -> &'static Tunnel {
+    let mut r = REGISTRY.lock().unwrap();
+    if let Some((_, t)) = r.tunnels.iter().find(|(a, _)| a == addr) {
+        return t;
+    }
+
+    let t = Box::leak(Box::default());
+    r.tunnels.push((addr.to_string(), t));
+    t
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counter to add to directly, e.g. as the relays copy bytes.
+    pub(crate) fn as_atomic(&self) -> &AtomicU64 {
+        &self.0
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn track(&self) -> GaugeGuard<'_> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self)
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct GaugeGuard<'a>(&'a Gauge);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    /// In microseconds.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        let secs = d.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Starts the HTTP server exposing the metrics at `/metrics` in the Prometheus text format.
+pub async fn start<A>(addr: A, mut shutdown: Shutdown)
+where
+    A: Into<SocketAddr>,
+{
+    let l = TcpListener::bind(addr.into())
+        .await
+        .expect("TcpListener::bind");
+
+    loop {
+        let r = tokio::select! {
+            r = l.accept() => r,
+            _ = shutdown.recv() => break,
+        };
+
+        match r {
+            Err(e) => {
+                error!("An error occurred while calling listener.accept: {}", e);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok((s, _)) => {
+                tokio::spawn(async move {
+                    let r = server::conn::http1::Builder::new()
+                        .serve_connection(s, service_fn(serve))
+                        .await;
+                    if let Err(e) = r {
+                        error!("Failed to serve connection: {:?}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    info!("metrics server stopped accepting new connections");
+}
+
+async fn serve(req: Request<body::Incoming>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let mut resp = Response::new(Full::default());
+
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    }
+
+    *resp.body_mut() = Full::new(Bytes::from(render()));
+    resp.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok(resp)
+}
+
+/// The name and help of a metric, and how to get it from the metrics it's part of.
+type Metric<M, T> = (&'static str, &'static str, fn(&M) -> &T);
+
+// https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+fn render() -> String {
+    let r = REGISTRY.lock().unwrap();
+    let mut s = String::new();
+
+    let counters: [Metric<Listener, Counter>; 6] = [
+        ("connections_accepted_total", "Connections accepted", |l| {
+            &l.accepted
+        }),
+        (
+            "connections_failed_total",
+            "Connections closed on an error",
+            |l| &l.failed,
+        ),
+        (
+            "connections_limited_total",
+            "Connections rejected by the connection limits",
+            |l| &l.limited,
+        ),
+        (
+            "auth_failures_total",
+            "Clients that failed to authenticate",
+            |l| &l.auth_failures,
+        ),
+        (
+            "bytes_up_total",
+            "Bytes sent from clients to destinations",
+            |l| &l.bytes_up,
+        ),
+        (
+            "bytes_down_total",
+            "Bytes sent from destinations to clients",
+            |l| &l.bytes_down,
+        ),
+    ];
+    for (name, help, f) in counters {
+        header(&mut s, name, help, "counter");
+        for (l, m) in &r.listeners {
+            let _ = writeln!(s, "bubble_{name}{{listener=\"{l}\"}} {}", f(m).get());
+        }
+    }
+
+    header(
+        &mut s,
+        "timeouts_total",
+        "Connections closed as a stage of theirs timed out",
+        "counter",
+    );
+    for (l, m) in &r.listeners {
+        for stage in Stage::ALL {
+            let _ = writeln!(
+                s,
+                "bubble_timeouts_total{{listener=\"{l}\",stage=\"{stage}\"}} {}",
+                stage.counter(m).get()
+            );
+        }
+    }
+
+    header(
+        &mut s,
+        "connections_active",
+        "Connections being served",
+        "gauge",
+    );
+    for (l, m) in &r.listeners {
+        let _ = writeln!(
+            s,
+            "bubble_connections_active{{listener=\"{l}\"}} {}",
+            m.active.get()
+        );
+    }
+
+    let histograms: [Metric<Listener, Histogram>; 2] = [
+        (
+            "handshake_duration_seconds",
+            "Time from accepting a connection to learning its destination",
+            |l| &l.handshake,
+        ),
+        (
+            "connect_duration_seconds",
+            "Time taken to connect to destinations",
+            |l| &l.connect,
+        ),
+    ];
+    for (name, help, f) in histograms {
+        header(&mut s, name, help, "histogram");
+        for (l, m) in &r.listeners {
+            histogram(&mut s, name, &format!("listener=\"{l}\""), f(m));
+        }
+    }
+
+    header(
+        &mut s,
+        "dns_cache_hits_total",
+        "DNS answers served from the cache",
+        "counter",
+    );
+    let _ = writeln!(s, "bubble_dns_cache_hits_total {}", DNS_CACHE_HITS.get());
+    header(
+        &mut s,
+        "dns_cache_misses_total",
+        "DNS answers queried from the DNS servers",
+        "counter",
+    );
+    let _ = writeln!(
+        s,
+        "bubble_dns_cache_misses_total {}",
+        DNS_CACHE_MISSES.get()
+    );
+
+    let tunnels: [Metric<Tunnel, Counter>; 2] = [
+        (
+            "tunnel_connections_total",
+            "Connections opened to tunnel servers",
+            |t| &t.connected,
+        ),
+        (
+            "tunnel_connections_failed_total",
+            "Connections that failed to open to tunnel servers",
+            |t| &t.failed,
+        ),
+    ];
+    for (name, help, f) in tunnels {
+        header(&mut s, name, help, "counter");
+        for (t, m) in &r.tunnels {
+            let _ = writeln!(s, "bubble_{name}{{tunnel=\"{t}\"}} {}", f(m).get());
+        }
+    }
+
+    header(
+        &mut s,
+        "tunnel_connections_open",
+        "Connections open to tunnel servers",
+        "gauge",
+    );
+    for (t, m) in &r.tunnels {
+        let _ = writeln!(
+            s,
+            "bubble_tunnel_connections_open{{tunnel=\"{t}\"}} {}",
+            m.open.get()
+        );
+    }
+
+    s
+}
+
+fn header(s: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(s, "# HELP bubble_{name} {help}.");
+    let _ = writeln!(s, "# TYPE bubble_{name} {kind}");
+}
+
+fn histogram(s: &mut String, name: &str, labels: &str, h: &Histogram) {
+    let mut cumulative = 0;
+    for (b, n) in BUCKETS.iter().zip(&h.buckets) {
+        cumulative += n.load(Ordering::Relaxed);
+        let _ = writeln!(
+            s,
+            "bubble_{name}_bucket{{{labels},le=\"{b}\"}} {cumulative}"
+        );
+    }
+
+    let count = h.count.load(Ordering::Relaxed);
+    let sum = h.sum.load(Ordering::Relaxed) as f64 / 1e6;
+    let _ = writeln!(s, "bubble_{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+    let _ = writeln!(s, "bubble_{name}_sum{{{labels}}} {sum}");
+    let _ = writeln!(s, "bubble_{name}_count{{{labels}}} {count}");
+}
@@ -0,0 +1,188 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use anyhow::{anyhow, ensure, Context};
+
+use crate::cidr::{Cidr, CidrSet};
+
+/// Destinations that are denied unless explicitly allowed, so that the proxy servers can't be
+/// used to reach the host they run on or its cloud metadata service.
+const PRIVATE: &[&str] = &[
+    "0.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16", // includes 169.254.169.254
+    "::/128",
+    "::1/128",
+    "fe80::/10",
+    "fd00:ec2::254/128",
+];
+
+/// Decides which destinations the proxy servers may connect to.
+///
+/// A destination is denied when its port is not in the allowed ports (if any) or is in the denied
+/// ports. Otherwise it is denied when its address or domain matches a deny rule, or one of the
+/// built-in private networks, and no address or domain allow rule.
+#[derive(Debug)]
+pub struct Policy {
+    allow_dst: CidrSet,
+    deny_dst: CidrSet,
+    allow_ports: Vec<PortRange>,
+    deny_ports: Vec<PortRange>,
+    allow_domains: Vec<DomainPattern>,
+    deny_domains: Vec<DomainPattern>,
+}
+
+/// The rules of a [`Policy`], which denies nothing but the private networks by default.
+#[derive(Clone, Debug, Default)]
+pub struct PolicyOptions {
+    /// The destination networks allowed, overriding the deny rules.
+    pub allow_dst: Vec<Cidr>,
+    pub deny_dst: Vec<Cidr>,
+    /// If not empty, the only destination ports allowed.
+    pub allow_ports: Vec<PortRange>,
+    pub deny_ports: Vec<PortRange>,
+    /// The destination domains allowed, overriding the deny rules.
+    pub allow_domains: Vec<DomainPattern>,
+    pub deny_domains: Vec<DomainPattern>,
+    /// Whether to allow the loopback, link-local and cloud metadata addresses.
+    pub allow_private_dst: bool,
+}
+
+/// Returned, wrapped in an `anyhow::Error`, when a destination is denied by the policy or
+/// rejected by a route.
+#[derive(Debug)]
+pub struct Denied(pub String);
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+impl Policy {
+    pub fn new(c: &PolicyOptions) -> Self {
+        let mut deny_dst: CidrSet = c.deny_dst.iter().copied().collect();
+        if !c.allow_private_dst {
+            for c in PRIVATE {
+                deny_dst.insert(c.parse().expect("PRIVATE"));
+            }
+        }
+
+        Policy {
+            allow_dst: c.allow_dst.iter().copied().collect(),
+            deny_dst,
+            allow_ports: c.allow_ports.clone(),
+            deny_ports: c.deny_ports.clone(),
+            allow_domains: c.allow_domains.clone(),
+            deny_domains: c.deny_domains.clone(),
+        }
+    }
+
+    /// Checks the resolved address `ip` of a destination, along with the domain name it was
+    /// resolved from, if any.
+    pub fn check(&self, domain: Option<&str>, ip: IpAddr, port: u16) -> Result<(), Denied> {
+        if !self.allow_ports.is_empty() && !self.allow_ports.iter().any(|r| r.contains(port))
+            || self.deny_ports.iter().any(|r| r.contains(port))
+        {
+            return Err(Denied(format!("denied by policy: port {port}")));
+        }
+
+        let allowed = self.allow_dst.contains(ip)
+            || domain.is_some_and(|d| self.allow_domains.iter().any(|p| p.matches(d)));
+
+        if allowed {
+            return Ok(());
+        }
+
+        if self.deny_dst.contains(ip) {
+            return Err(Denied(format!("denied by policy: address {ip}")));
+        }
+
+        if let Some(d) = domain {
+            if self.deny_domains.iter().any(|p| p.matches(d)) {
+                return Err(Denied(format!("denied by policy: domain {d}")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An inclusive range of ports, e.g. `25` or `6000-6063`.
+#[derive(Clone, Copy, Debug)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start = start
+            .parse()
+            .with_context(|| format!("invalid port range: {s}"))?;
+        let end = end
+            .parse()
+            .with_context(|| format!("invalid port range: {s}"))?;
+
+        ensure!(start <= end, "invalid port range: {s}");
+        Ok(PortRange { start, end })
+    }
+}
+
+/// A case-insensitive domain name pattern in which `*` matches any sequence of characters, e.g.
+/// `example.com` or `*.example.com`.
+#[derive(Clone, Debug)]
+pub struct DomainPattern(String);
+
+impl DomainPattern {
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        glob(self.0.as_bytes(), domain.as_bytes())
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_end_matches('.').to_ascii_lowercase();
+        if s.is_empty() {
+            return Err(anyhow!("empty domain pattern"));
+        }
+        Ok(DomainPattern(s))
+    }
+}
+
+fn glob(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    let mut star = None;
+
+    while i < s.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, i));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == s[i] {
+            p += 1;
+            i += 1;
+        } else if let Some((sp, si)) = star {
+            // let the last `*` swallow one more character and retry
+            p = sp + 1;
+            i = si + 1;
+            star = Some((sp, si + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
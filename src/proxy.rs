@@ -1,3 +1,16 @@
+pub mod connect;
+pub mod dial;
 pub mod http;
+mod listen;
+pub mod mixed;
+pub mod relay;
 pub mod socks5;
+#[cfg(target_os = "linux")]
+pub mod splice;
+pub mod tcp;
+pub mod tls;
+#[cfg(target_os = "linux")]
+pub mod transparent;
 pub mod tunnel;
+
+pub use listen::ListenOptions;
@@ -0,0 +1,212 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpSocket, TcpStream},
+};
+
+use super::{
+    dial::{Client, Target},
+    tcp::TcpOptions,
+};
+use crate::{auth::Credentials, dns::Resolver, metrics, policy::Policy, timeout::Stage, BoxFuture};
+
+mod http;
+mod socks5;
+
+pub use self::http::HttpUpstream;
+pub use self::socks5::Socks5Upstream;
+
+/// Opens the connections to the destinations the clients of the proxy servers ask for, once
+/// they have been routed, one way or another.
+///
+/// Whatever the way, the connections are plain TCP streams, so that relaying them can still use
+/// splice(2).
+pub trait Connector: Send + Sync {
+    /// Connects to `target` on behalf of `client`.
+    fn connect<'a>(
+        &'a self,
+        target: &'a Target,
+        client: &'a Client,
+    ) -> BoxFuture<'a, anyhow::Result<TcpStream>>;
+}
+
+/// Connects to destinations directly, to the most preferred of their addresses the policy
+/// allows.
+pub struct Direct {
+    policy: Policy,
+    resolver: Arc<Resolver>,
+    tcp: TcpOptions,
+}
+
+impl Direct {
+    pub fn new(policy: Policy, resolver: Arc<Resolver>, tcp: TcpOptions) -> Self {
+        Direct {
+            policy,
+            resolver,
+            tcp,
+        }
+    }
+
+    pub fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    /// Resolves `host` for `client`, within the dns timeout.
+    pub async fn lookup(
+        &self,
+        host: &str,
+        port: u16,
+        client: &Client,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        client
+            .timeouts()
+            .within(Stage::Dns, client.metrics, self.resolver.lookup(host, port))
+            .await
+    }
+
+    /// Connects to the server at `addr`, e.g. a tunnel server or an upstream proxy, which the
+    /// policy doesn't apply to as it was configured rather than asked for by a client.
+    pub async fn connect_server(&self, addr: &str, client: &Client) -> anyhow::Result<TcpStream> {
+        let addr = match addr.parse::<Target>().context("connect_server: parse")? {
+            Target::Addr(a) => a,
+            Target::Domain(host, port) => self.lookup(&host, port, client).await?[0],
+        };
+        self.dial(addr, client).await
+    }
+
+    /// Returns the most preferred address among those allowed by the policy.
+    fn select(&self, domain: Option<&str>, addrs: Vec<SocketAddr>) -> anyhow::Result<SocketAddr> {
+        let mut denied = None;
+        let addrs = addrs
+            .into_iter()
+            .filter(|a| match self.policy.check(domain, a.ip(), a.port()) {
+                Ok(()) => true,
+                Err(e) => {
+                    denied = Some(e);
+                    false
+                }
+            })
+            .collect::<Vec<_>>();
+
+        match (addrs.first(), denied) {
+            (Some(a), _) => Ok(*a),
+            (None, Some(e)) => Err(e.into()),
+            (None, None) => Err(anyhow!("select: no address")),
+        }
+    }
+
+    /// Connects to `dst_addr` for `client`, within the connect timeout.
+    async fn dial(&self, dst_addr: SocketAddr, client: &Client) -> anyhow::Result<TcpStream> {
+        let socket = match dst_addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4().context("dial: TcpSocket::new_v4")?,
+            SocketAddr::V6(_) => TcpSocket::new_v6().context("dial: TcpSocket::new_v6")?,
+        };
+
+        self.tcp
+            .apply_connecting(&socket)
+            .context("dial: apply_connecting")?;
+
+        {
+            // TODO
+            // let mut local_addr = socket.local_addr().context("connect: socket.local_addr")?;
+            // if dst_addr.is_ipv4() && local_addr.is_ipv4() || dst_addr.is_ipv6() && local_addr.is_ipv6()
+            // {
+            //     local_addr.set_port(0);
+            //     socket2.bind(local_addr).context("connect: socket2.bind")?;
+            // }
+        }
+
+        let s = client
+            .timeouts()
+            .within(Stage::Connect, client.metrics, async {
+                socket
+                    .connect(dst_addr)
+                    .await
+                    .context("dial: socket.connect")
+            })
+            .await?;
+
+        self.tcp
+            .apply_connected(&s)
+            .context("dial: apply_connected")?;
+        Ok(s)
+    }
+}
+
+impl Connector for Direct {
+    fn connect<'a>(
+        &'a self,
+        target: &'a Target,
+        client: &'a Client,
+    ) -> BoxFuture<'a, anyhow::Result<TcpStream>> {
+        Box::pin(async move {
+            // Reuse the addresses resolved to route the connection, so that it goes where the
+            // rules expect
+            let (domain, addrs) = match (target, &client.addrs) {
+                (Target::Addr(a), _) => (None, vec![*a]),
+                (Target::Domain(host, _), Some(a)) => (Some(host.as_str()), a.clone()),
+                (Target::Domain(host, port), None) => {
+                    (Some(host.as_str()), self.lookup(host, *port, client).await?)
+                }
+            };
+            self.dial(self.select(domain, addrs)?, client).await
+        })
+    }
+}
+
+/// Connects to destinations through the tunnel server at `addr`, which is sent their address
+/// prefixed with its length, and with `<USER>:<PASSWORD>@` if it requires credentials.
+pub struct Tunnel {
+    direct: Arc<Direct>,
+    addr: String,
+    credentials: Option<Credentials>,
+}
+
+impl Tunnel {
+    pub fn new(direct: Arc<Direct>, addr: String, credentials: Option<Credentials>) -> Self {
+        Tunnel {
+            direct,
+            addr,
+            credentials,
+        }
+    }
+
+    async fn open(&self, target: &Target, client: &Client) -> anyhow::Result<TcpStream> {
+        let mut s = self
+            .direct
+            .connect_server(&self.addr, client)
+            .await
+            .context("open_tunnel: connect")?;
+        let addr = match &self.credentials {
+            Some(c) => format!("{}:{}@{target}", c.username, c.password),
+            None => target.to_string(),
+        };
+        s.write_u16(addr.len() as u16)
+            .await
+            .context("open_tunnel: write_u16")?;
+        s.write_all(addr.as_bytes())
+            .await
+            .context("open_tunnel: write_all")?;
+        Ok(s)
+    }
+}
+
+impl Connector for Tunnel {
+    fn connect<'a>(
+        &'a self,
+        target: &'a Target,
+        client: &'a Client,
+    ) -> BoxFuture<'a, anyhow::Result<TcpStream>> {
+        Box::pin(async move {
+            let m = metrics::tunnel(&self.addr);
+            let r = self.open(target, client).await;
+            match r {
+                Ok(_) => m.connected.inc(),
+                Err(_) => m.failed.inc(),
+            }
+            r
+        })
+    }
+}
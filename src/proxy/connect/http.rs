@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{Connector, Direct};
+use crate::{
+    policy::Denied,
+    proxy::dial::{Client, Target},
+    route::Upstream,
+    timeout::Stage,
+    BoxFuture,
+};
+
+// https://www.rfc-editor.org/rfc/rfc9110#section-9.3.6
+
+/// The maximum size of the response head of the upstream proxy server.
+const MAX_HEAD: usize = 8192;
+
+/// Connects to destinations through an upstream http proxy server, asking it to CONNECT to
+/// them.
+pub struct HttpUpstream {
+    direct: Arc<Direct>,
+    upstream: Upstream,
+}
+
+impl HttpUpstream {
+    pub fn new(direct: Arc<Direct>, upstream: Upstream) -> Self {
+        HttpUpstream { direct, upstream }
+    }
+
+    async fn handshake(&self, s: &mut TcpStream, target: &Target) -> anyhow::Result<()> {
+        let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some(c) = &self.upstream.credentials {
+            let token = STANDARD.encode(format!("{}:{}", c.username, c.password));
+            req += &format!("Proxy-Authorization: Basic {token}\r\n");
+        }
+        req += "\r\n";
+        s.write_all(req.as_bytes())
+            .await
+            .context("handshake: write request")?;
+
+        // A byte at a time, so as not to read past the head into what the destination sent
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            ensure!(head.len() < MAX_HEAD, "handshake: response head too large");
+            head.push(s.read_u8().await.context("handshake: read response")?);
+        }
+
+        let status = std::str::from_utf8(&head)
+            .ok()
+            .and_then(|h| h.split_whitespace().nth(1))
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("handshake: invalid response"))?;
+
+        match status {
+            200..=299 => Ok(()),
+            403 => Err(Denied(format!("denied by upstream proxy: {}", self.upstream)).into()),
+            407 => bail!(
+                "handshake: upstream proxy {} requires authentication",
+                self.upstream
+            ),
+            _ => bail!(
+                "handshake: upstream proxy {} replied {status}",
+                self.upstream
+            ),
+        }
+    }
+}
+
+impl Connector for HttpUpstream {
+    fn connect<'a>(
+        &'a self,
+        target: &'a Target,
+        client: &'a Client,
+    ) -> BoxFuture<'a, anyhow::Result<TcpStream>> {
+        Box::pin(async move {
+            let mut s = self
+                .direct
+                .connect_server(&self.upstream.addr, client)
+                .await
+                .context("HttpUpstream: connect")?;
+            client
+                .timeouts()
+                .within(
+                    Stage::Connect,
+                    client.metrics,
+                    self.handshake(&mut s, target),
+                )
+                .await?;
+            Ok(s)
+        })
+    }
+}
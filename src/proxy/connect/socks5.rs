@@ -0,0 +1,216 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{bail, ensure, Context};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{Connector, Direct};
+use crate::{
+    auth::Credentials,
+    policy::Denied,
+    proxy::dial::{Client, Target},
+    route::Upstream,
+    timeout::Stage,
+    BoxFuture,
+};
+
+// https://www.rfc-editor.org/rfc/rfc1928
+// https://www.rfc-editor.org/rfc/rfc1929
+
+const VERSION: u8 = 0x05;
+
+const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IP_V4_ADDR: u8 = 0x01;
+const ATYP_DOMAINNAME: u8 = 0x03;
+const ATYP_IP_V6_ADDR: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_NOT_ALLOWED: u8 = 0x02;
+
+/// Connects to destinations through an upstream socks5 proxy server, authenticating with the
+/// username/password method if it has credentials.
+pub struct Socks5Upstream {
+    direct: Arc<Direct>,
+    upstream: Upstream,
+}
+
+impl Socks5Upstream {
+    pub fn new(direct: Arc<Direct>, upstream: Upstream) -> Self {
+        Socks5Upstream { direct, upstream }
+    }
+
+    async fn handshake(&self, s: &mut TcpStream, target: &Target) -> anyhow::Result<()> {
+        // +----+----------+----------+
+        // |VER | NMETHODS | METHODS  |
+        // +----+----------+----------+
+        // | 1  |    1     | 1 to 255 |
+        // +----+----------+----------+
+        let greeting: &[u8] = match self.upstream.credentials {
+            Some(_) => &[VERSION, 2, NO_AUTHENTICATION_REQUIRED, USERNAME_PASSWORD],
+            None => &[VERSION, 1, NO_AUTHENTICATION_REQUIRED],
+        };
+        s.write_all(greeting)
+            .await
+            .context("handshake: write methods")?;
+
+        let mut buf = [0; 2];
+        s.read_exact(&mut buf)
+            .await
+            .context("handshake: read method")?;
+        ensure!(buf[0] == VERSION, "handshake: invalid VERSION: {}", buf[0]);
+
+        match (buf[1], &self.upstream.credentials) {
+            (NO_AUTHENTICATION_REQUIRED, _) => {}
+            (USERNAME_PASSWORD, Some(c)) => authenticate(s, c).await?,
+            (NO_ACCEPTABLE_METHODS, None) => bail!(
+                "handshake: upstream proxy {} requires authentication",
+                self.upstream
+            ),
+            (m, _) => bail!("handshake: unsupported METHOD: {m}"),
+        }
+
+        // +----+-----+-------+------+----------+----------+
+        // |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+        // +----+-----+-------+------+----------+----------+
+        // | 1  |  1  | X'00' |  1   | Variable |    2     |
+        // +----+-----+-------+------+----------+----------+
+        let mut req = vec![VERSION, CMD_CONNECT, 0x00];
+        let port = match target {
+            Target::Addr(SocketAddr::V4(a)) => {
+                req.push(ATYP_IP_V4_ADDR);
+                req.extend_from_slice(&a.ip().octets());
+                a.port()
+            }
+            Target::Addr(SocketAddr::V6(a)) => {
+                req.push(ATYP_IP_V6_ADDR);
+                req.extend_from_slice(&a.ip().octets());
+                a.port()
+            }
+            Target::Domain(host, port) => {
+                ensure!(host.len() <= 255, "handshake: domain too long: {host}");
+                req.push(ATYP_DOMAINNAME);
+                req.push(host.len() as u8);
+                req.extend_from_slice(host.as_bytes());
+                *port
+            }
+        };
+        req.extend_from_slice(&port.to_be_bytes());
+        s.write_all(&req)
+            .await
+            .context("handshake: write request")?;
+
+        // +----+-----+-------+------+----------+----------+
+        // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+        // +----+-----+-------+------+----------+----------+
+        // | 1  |  1  | X'00' |  1   | Variable |    2     |
+        // +----+-----+-------+------+----------+----------+
+        let mut buf = [0; 4];
+        s.read_exact(&mut buf)
+            .await
+            .context("handshake: read reply")?;
+        ensure!(buf[0] == VERSION, "handshake: invalid VERSION: {}", buf[0]);
+
+        match buf[1] {
+            REP_SUCCEEDED => {}
+            REP_NOT_ALLOWED => {
+                return Err(Denied(format!("denied by upstream proxy: {}", self.upstream)).into())
+            }
+            rep => bail!(
+                "handshake: upstream proxy {} replied {rep}: {}",
+                self.upstream,
+                reply_message(rep)
+            ),
+        }
+
+        // The bound address is of no use, but has to be read past
+        let len = match buf[3] {
+            ATYP_IP_V4_ADDR => 4,
+            ATYP_IP_V6_ADDR => 16,
+            ATYP_DOMAINNAME => s.read_u8().await.context("handshake: read BND.ADDR len")? as usize,
+            atyp => bail!("handshake: invalid ATYP: {atyp}"),
+        };
+        let mut bnd = vec![0; len + 2];
+        s.read_exact(&mut bnd)
+            .await
+            .context("handshake: read BND.ADDR")?;
+        Ok(())
+    }
+}
+
+impl Connector for Socks5Upstream {
+    fn connect<'a>(
+        &'a self,
+        target: &'a Target,
+        client: &'a Client,
+    ) -> BoxFuture<'a, anyhow::Result<TcpStream>> {
+        Box::pin(async move {
+            let mut s = self
+                .direct
+                .connect_server(&self.upstream.addr, client)
+                .await
+                .context("Socks5Upstream: connect")?;
+            client
+                .timeouts()
+                .within(
+                    Stage::Connect,
+                    client.metrics,
+                    self.handshake(&mut s, target),
+                )
+                .await?;
+            Ok(s)
+        })
+    }
+}
+
+async fn authenticate(s: &mut TcpStream, c: &Credentials) -> anyhow::Result<()> {
+    const VER: u8 = 0x01;
+
+    // +----+------+----------+------+----------+
+    // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    // +----+------+----------+------+----------+
+    // | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    // +----+------+----------+------+----------+
+    ensure!(
+        c.username.len() <= 255 && c.password.len() <= 255,
+        "authenticate: user name or password too long"
+    );
+    let mut req = vec![VER, c.username.len() as u8];
+    req.extend_from_slice(c.username.as_bytes());
+    req.push(c.password.len() as u8);
+    req.extend_from_slice(c.password.as_bytes());
+    s.write_all(&req)
+        .await
+        .context("authenticate: write request")?;
+
+    let mut buf = [0; 2];
+    s.read_exact(&mut buf)
+        .await
+        .context("authenticate: read reply")?;
+    ensure!(buf[0] == VER, "authenticate: invalid VER: {}", buf[0]);
+    ensure!(
+        buf[1] == 0x00,
+        "authenticate: rejected by the upstream proxy, STATUS: {}",
+        buf[1]
+    );
+    Ok(())
+}
+
+fn reply_message(rep: u8) -> &'static str {
+    match rep {
+        0x01 => "general SOCKS server failure",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unassigned",
+    }
+}
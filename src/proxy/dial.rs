@@ -0,0 +1,393 @@
+use std::{collections::HashMap, fmt, net::SocketAddr, str::FromStr, sync::Arc, time::Instant};
+
+use anyhow::{anyhow, Context};
+
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::{
+    auth::Credentials,
+    dns::Resolver,
+    hooks, limit,
+    metrics::{self, GaugeGuard},
+    policy::{Denied, Policy},
+    quota,
+    registry::{self, Conn},
+    route::{Action, Connection, Router, Rule},
+    server::Settings,
+    timeout::{Stage, TimedOut, Timeouts},
+};
+
+use super::{
+    connect::{Connector, Direct, HttpUpstream, Socks5Upstream, Tunnel},
+    relay::Close,
+    tcp::TcpOptions,
+};
+
+/// The destination a client asked a proxy server to connect to.
+#[derive(Clone, Debug)]
+pub enum Target {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    /// Parses `host:port`, where host is an IPv4 address, a bracketed IPv6 address or a domain
+    /// name.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(a) = s.parse() {
+            return Ok(Target::Addr(a));
+        }
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("missing port: {s}"))?;
+        let port = port.parse().with_context(|| format!("invalid port: {s}"))?;
+
+        Ok(Target::Domain(host.to_string(), port))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Addr(a) => write!(f, "{a}"),
+            Target::Domain(host, port) => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+/// The client on whose behalf a proxy server connects to a destination, and what became of
+/// its connection so far.
+#[derive(Clone)]
+pub struct Client {
+    pub addr: SocketAddr,
+    pub user: Option<String>,
+    /// The protocol the client speaks, e.g. `socks5` or `http`.
+    pub protocol: &'static str,
+    /// The metrics of the listener the client connected to.
+    pub metrics: &'static metrics::Listener,
+    pub accepted: Instant,
+    /// The destination the client asked for.
+    pub target: Option<Target>,
+    /// The action taken to reach the destination, and the rule that decided it, if any.
+    pub route: Option<String>,
+    pub rule: Option<String>,
+    /// The addresses of the destination, if they had to be resolved to route it.
+    pub addrs: Option<Vec<SocketAddr>>,
+    /// The address connected to, which is the tunnel server's or upstream proxy's for the
+    /// connections through them.
+    pub remote: Option<SocketAddr>,
+    /// The connection in the registry, shared by the clones of the client.
+    pub(crate) conn: Arc<Conn>,
+    /// What the server shares with the connections it serves.
+    pub(crate) settings: Arc<Settings>,
+    /// Why the relay to the destination ended, once it has.
+    pub close: Option<Close>,
+    /// Counts the connection among those open to its tunnel server, if it goes through one,
+    /// until the client and its clones are gone.
+    pub(crate) tunnel: Option<Arc<GaugeGuard<'static>>>,
+}
+
+impl Client {
+    /// Returns the client of `conn`, registered once accepted, see [`crate::limit::accept`].
+    pub(crate) fn new(
+        conn: Arc<Conn>,
+        protocol: &'static str,
+        metrics: &'static metrics::Listener,
+        settings: Arc<Settings>,
+    ) -> Self {
+        conn.update(|i| i.protocol = protocol);
+        Client {
+            addr: conn.addr,
+            user: None,
+            protocol,
+            metrics,
+            accepted: Instant::now(),
+            target: None,
+            route: None,
+            rule: None,
+            addrs: None,
+            remote: None,
+            conn,
+            settings,
+            close: None,
+            tunnel: None,
+        }
+    }
+
+    pub fn set_protocol(&mut self, protocol: &'static str) {
+        self.protocol = protocol;
+        self.conn.update(|i| i.protocol = protocol);
+    }
+
+    pub fn set_user(&mut self, user: Option<String>) {
+        self.conn.set_user(user.clone());
+        self.user = user;
+    }
+
+    pub fn set_target(&mut self, target: Option<Target>) {
+        self.conn
+            .update(|i| i.target = target.as_ref().map(|t| t.to_string()));
+        self.target = target;
+        self.addrs = None;
+    }
+
+    /// Fails if the client may not connect to a destination, as its user is disabled or it is
+    /// over the connection limits.
+    pub fn admit(&self) -> anyhow::Result<()> {
+        registry::check_user(self.user.as_deref())?;
+        limit::admit(self)?;
+        quota::check(self)?;
+        Ok(())
+    }
+
+    /// Returns the timeouts of the server the client connected to.
+    pub fn timeouts(&self) -> Timeouts {
+        self.settings.timeouts
+    }
+
+    /// Records that the client finished its handshake, having asked for a destination.
+    pub fn handshake_done(&self) {
+        self.metrics.handshake.observe(self.accepted.elapsed());
+    }
+}
+
+/// Connects the proxy servers to the destinations their clients asked for, through the
+/// connector of the action their route takes.
+pub struct Dialer {
+    router: Router,
+    direct: Arc<Direct>,
+    /// The credentials to present to tunnel servers, if they require any.
+    tunnel_auth: Option<Credentials>,
+    connectors: HashMap<Action, Arc<dyn Connector>>,
+}
+
+impl Dialer {
+    pub fn new(
+        policy: Policy,
+        router: Router,
+        resolver: Resolver,
+        tcp: TcpOptions,
+        tunnel_auth: Option<Credentials>,
+    ) -> Self {
+        let mut d = Dialer {
+            router,
+            direct: Arc::new(Direct::new(policy, Arc::new(resolver), tcp)),
+            tunnel_auth,
+            connectors: HashMap::new(),
+        };
+
+        let actions = d
+            .router
+            .rules()
+            .iter()
+            .map(|r| r.action())
+            .chain([d.router.default_action(), &Action::Direct])
+            .cloned()
+            .collect::<Vec<_>>();
+        for a in actions {
+            if let Some(c) = d.build(&a) {
+                d.connectors.insert(a, c);
+            }
+        }
+        d
+    }
+
+    /// Makes the connections taking `action` go through `connector` instead, e.g. to connect
+    /// directly from another network.
+    pub fn connector(mut self, action: Action, connector: Arc<dyn Connector>) -> Self {
+        self.connectors.insert(action, connector);
+        self
+    }
+
+    pub fn resolver(&self) -> &Resolver {
+        self.direct.resolver()
+    }
+
+    /// Routes `target` and connects to it directly, through a tunnel server or an upstream
+    /// proxy, or not at all.
+    ///
+    /// Destinations rejected by a route or denied by the policy fail with a [`Denied`] error.
+    pub async fn connect(&self, target: &Target, client: &mut Client) -> anyhow::Result<TcpStream> {
+        let start = Instant::now();
+        client.set_target(Some(target.clone()));
+        client.admit()?;
+
+        let (rule, action, addrs) = self.route(target, client).await?;
+        client.route = Some(action.to_string());
+        client.rule = rule.map(|r| r.to_string());
+        client.addrs = addrs;
+        let target = decided(client, action, target)?;
+
+        let s = match action {
+            Action::Reject => Err(Denied(match rule {
+                Some(r) => format!("rejected by route: {r}"),
+                None => "rejected by default route".to_string(),
+            })
+            .into()),
+            _ => self.connect_via(action, &target, client).await,
+        }?;
+
+        connected(client, action, &s, start);
+        Ok(s)
+    }
+
+    /// Returns the rule matching `target`, if any, and the action to take, along with the
+    /// addresses of `target` if they had to be resolved to decide.
+    pub async fn route(
+        &self,
+        target: &Target,
+        client: &Client,
+    ) -> anyhow::Result<(Option<&Rule>, &Action, Option<Vec<SocketAddr>>)> {
+        let (addr, user) = (client.addr, client.user.as_deref());
+        self.route_for(target, addr, user, client.timeouts(), client.metrics)
+            .await
+    }
+
+    /// Like [`Dialer::route`], for `user` connecting from `addr` to the listener of `m`, e.g.
+    /// for the queries of the DNS server, which aren't connections of their own.
+    pub(crate) async fn route_for(
+        &self,
+        target: &Target,
+        addr: SocketAddr,
+        user: Option<&str>,
+        timeouts: Timeouts,
+        m: &metrics::Listener,
+    ) -> anyhow::Result<(Option<&Rule>, &Action, Option<Vec<SocketAddr>>)> {
+        let (domain, port, mut addrs) = match target {
+            Target::Addr(a) => (None, a.port(), Some(vec![*a])),
+            Target::Domain(host, port) => (Some(host.as_str()), *port, None),
+        };
+
+        let name = domain.map(|d| d.trim_end_matches('.').to_ascii_lowercase());
+        let mut action = self.router.default_action();
+        let mut rule = None;
+
+        let conn = |addrs: Option<&[SocketAddr]>| Connection {
+            domain: name.as_deref(),
+            ip: addrs.and_then(|a| a.first()).map(|a| a.ip()),
+            port,
+            client: addr,
+            user,
+        };
+
+        for r in self.router.rules() {
+            let mut matched = r.matches(&conn(addrs.as_deref()));
+
+            // Only resolve the domain once a rule can't be decided without its address
+            if !matched && r.needs_ip() && addrs.is_none() {
+                let lookup = self.resolver().lookup(domain.unwrap_or_default(), port);
+                addrs = Some(timeouts.within(Stage::Dns, m, lookup).await?);
+                matched = r.matches(&conn(addrs.as_deref()));
+            }
+
+            if matched {
+                action = r.action();
+                rule = Some(r);
+                break;
+            }
+        }
+
+        debug!(
+            "{} - route {} via {}",
+            addr,
+            target,
+            rule.map_or("default".to_string(), |r| r.to_string())
+        );
+
+        Ok((rule, action, addrs))
+    }
+
+    /// Connects to `target` the way `action` says, bypassing the routing rules but not the
+    /// limits of `client`, nor the policy if directly.
+    pub async fn connect_with(
+        &self,
+        action: &Action,
+        target: &Target,
+        client: &mut Client,
+    ) -> anyhow::Result<TcpStream> {
+        let start = Instant::now();
+        client.set_target(Some(target.clone()));
+        client.route = Some(action.to_string());
+        client.admit()?;
+        let target = decided(client, action, target)?;
+
+        let s = self.connect_via(action, &target, client).await?;
+        connected(client, action, &s, start);
+        Ok(s)
+    }
+
+    /// Connects to `target` the way `action` says, bypassing the routing rules and the limits
+    /// of `client`.
+    pub async fn connect_via(
+        &self,
+        action: &Action,
+        target: &Target,
+        client: &Client,
+    ) -> anyhow::Result<TcpStream> {
+        let connector = match self.connectors.get(action) {
+            Some(c) => c.clone(),
+            None => self
+                .build(action)
+                .ok_or_else(|| Denied(format!("rejected: {target}")))?,
+        };
+        connector.connect(target, client).await
+    }
+
+    /// Returns the built-in connector of `action`, none for reject.
+    fn build(&self, action: &Action) -> Option<Arc<dyn Connector>> {
+        let direct = self.direct.clone();
+        match action {
+            Action::Direct => Some(direct),
+            Action::Tunnel(addr) => Some(Arc::new(Tunnel::new(
+                direct,
+                addr.clone(),
+                self.tunnel_auth.clone(),
+            ))),
+            Action::Http(u) => Some(Arc::new(HttpUpstream::new(direct, u.clone()))),
+            Action::Socks5(u) => Some(Arc::new(Socks5Upstream::new(direct, u.clone()))),
+            Action::Reject => None,
+        }
+    }
+}
+
+/// Lets the hooks know the route `client` takes, returning the target to connect to, which
+/// they may have changed.
+fn decided(client: &mut Client, action: &Action, target: &Target) -> anyhow::Result<Target> {
+    match hooks::route_decided(client, action)? {
+        Some(t) => {
+            client.set_target(Some(t.clone()));
+            Ok(t)
+        }
+        None => Ok(target.clone()),
+    }
+}
+
+/// Records that `client`, having started to connect at `start`, is connected through `s` the
+/// way `action` says.
+fn connected(client: &mut Client, action: &Action, s: &TcpStream, start: Instant) {
+    client.metrics.connect.observe(start.elapsed());
+    client.remote = s.peer_addr().ok();
+    if let Action::Tunnel(addr) = action {
+        client.tunnel = Some(Arc::new(metrics::tunnel(addr).open.track()));
+    }
+    hooks::connected(client);
+}
+
+/// Returns whether `e` was caused by the destination policy.
+pub fn is_denied(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Denied>().is_some()
+}
+
+/// Returns whether `e` was caused by the connection limits.
+pub fn is_limited(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<limit::OverLimit>().is_some()
+}
+
+/// Returns whether `e` was caused by a timeout.
+pub fn is_timed_out(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<TimedOut>().is_some()
+}
@@ -1,81 +1,286 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    future::pending,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use bytes::Bytes;
+use http::{
+    header::{PROXY_AUTHENTICATE, PROXY_AUTHORIZATION},
+    HeaderValue,
+};
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::service::service_fn;
-use hyper::upgrade::Upgraded;
 use hyper::{body, client, server};
 use hyper::{Method, Request, Response};
-use tokio::io::AsyncWriteExt;
-use tokio::{
-    net::{TcpListener, TcpStream},
-    time,
+use tokio::{net::TcpStream, time};
+use tracing::{debug, error, info, warn};
+
+use super::{
+    dial::{self, Client, Dialer, Target},
+    listen::{self, ListenOptions, Listener},
+    relay::relay_streams,
+    tcp::TcpOptions,
 };
-use tracing::{debug, error};
+use crate::{
+    access_log,
+    acl::Acl,
+    auth::{self, Authenticator, Credentials},
+    hooks, limit, metrics, quota,
+    registry::{Conn, Counted},
+    route::Action,
+    server::Settings,
+    shutdown::Shutdown,
+    timeout::Stage,
+};
+
+const FORBIDDEN: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+pub(crate) const TOO_MANY_REQUESTS: &[u8] =
+    b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
 
-pub async fn start<A>(addr: A, tunnel_addr: Option<String>)
-where
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn start<A>(
+    addr: A,
+    opts: ListenOptions,
+    tcp: TcpOptions,
+    tunnel_addr: Option<String>,
+    acl: Acl,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    shutdown: Shutdown,
+) where
     A: Into<SocketAddr>,
 {
-    let l = TcpListener::bind(addr.into())
-        .await
-        .expect("TcpListener::bind");
+    let acl = Arc::new(acl);
+    listen::run(addr.into(), &opts, tcp, |l| {
+        accept(
+            l,
+            tunnel_addr.clone(),
+            acl.clone(),
+            auth.clone(),
+            dialer.clone(),
+            settings.clone(),
+            shutdown.clone(),
+        )
+    })
+    .await;
+
+    info!("http proxy server stopped accepting new connections");
+}
+
+async fn accept(
+    l: Listener,
+    tunnel_addr: Option<String>,
+    acl: Arc<Acl>,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    mut shutdown: Shutdown,
+) {
+    let m = metrics::listener("http");
 
     loop {
-        match l.accept().await {
+        let r = tokio::select! {
+            r = l.accept() => r,
+            _ = shutdown.recv() => break,
+        };
+
+        match r {
             Err(e) => {
                 error!("An error occurred while calling listener.accept: {}", e);
                 time::sleep(Duration::from_secs(1)).await;
             }
-            Ok((s, _)) => {
-                tokio::spawn(handle_socket(s, tunnel_addr.clone()));
+            Ok((s, peer)) => {
+                if !acl.is_allowed(peer.ip()) {
+                    warn!("{peer} - denied by acl");
+                    let _ = s.try_write(FORBIDDEN);
+                    continue;
+                }
+                let conn = match limit::accept(&settings, m, peer) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("{peer} - {e}");
+                        let _ = s.try_write(TOO_MANY_REQUESTS);
+                        continue;
+                    }
+                };
+                if let Err(e) = hooks::accept(&settings, m.name, peer) {
+                    warn!("{peer} - {e}");
+                    let _ = s.try_write(FORBIDDEN);
+                    continue;
+                }
+
+                m.accepted.inc();
+                let guard = shutdown.track();
+                let tunnel_addr = tunnel_addr.clone();
+                let auth = auth.clone();
+                let dialer = dialer.clone();
+                let settings = settings.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    let _active = m.active.track();
+                    handle_socket(s, conn, tunnel_addr, auth, dialer, settings, shutdown, m).await;
+                    drop(guard);
+                });
             }
         }
     }
 }
 
-async fn handle_socket(s: TcpStream, tunnel_addr: Option<String>) {
-    if let Err(err) = server::conn::http1::Builder::new()
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handle_socket(
+    s: TcpStream,
+    conn: Arc<Conn>,
+    tunnel_addr: Option<String>,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    shutdown: Shutdown,
+    m: &'static metrics::Listener,
+) {
+    let timeouts = settings.timeouts;
+    let client = Client::new(conn, "http", m, settings);
+
+    let mut stop = shutdown.clone();
+    let requested = AtomicBool::new(false);
+    let authorized = Mutex::new(None);
+    let conn = server::conn::http1::Builder::new()
         .preserve_header_case(true)
         .title_case_headers(true)
         .serve_connection(
-            s,
-            service_fn(|req: Request<body::Incoming>| async {
-                proxy(req, tunnel_addr.clone()).await
+            Counted::new(s, client.conn.clone()),
+            service_fn(|req: Request<body::Incoming>| {
+                requested.store(true, Ordering::Relaxed);
+                async {
+                    proxy(
+                        req,
+                        tunnel_addr.clone(),
+                        auth.as_deref().map(|a| (a, &authorized)),
+                        client.clone(),
+                        dialer.clone(),
+                        shutdown.clone(),
+                    )
+                    .await
+                }
             }),
         )
-        .with_upgrades()
-        .await
-    {
+        .with_upgrades();
+    tokio::pin!(conn);
+
+    // The handshake is over once the headers of the first request have been read
+    let handshake = async {
+        let e = timeouts.elapsed(Stage::Handshake).await;
+        if requested.load(Ordering::Relaxed) {
+            pending::<()>().await;
+        }
+        e
+    };
+
+    let r = tokio::select! {
+        r = conn.as_mut() => r,
+        _ = stop.recv() => {
+            // Let the in-flight request finish, but don't wait for the next one.
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+        _ = client.conn.closed() => Ok(()),
+        e = handshake => {
+            e.stage.counter(m).inc();
+            warn!("{} - error: {e}", client.addr);
+            Ok(())
+        }
+        e = timeouts.idle(&client.conn, m) => {
+            warn!("{} - error: {e}", client.addr);
+            Ok(())
+        }
+    };
+
+    if let Err(err) = r {
         error!("Failed to serve connection: {:?}", err);
     }
 }
 
 async fn proxy(
-    req: Request<body::Incoming>,
+    mut req: Request<body::Incoming>,
     tunnel_addr: Option<String>,
+    auth: Option<(&dyn Authenticator, &Authorized)>,
+    mut client: Client,
+    dialer: Arc<Dialer>,
+    shutdown: Shutdown,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     debug!("req: {:?}", req);
 
+    // Each request on the connection is timed and logged on its own
+    client.accepted = Instant::now();
+    let method = req.method().to_string();
+
+    if let Some((auth, authorized)) = auth {
+        if let Err(e) = authorize(&req, auth, authorized, &mut client).await {
+            debug!("{} - {e}", client.addr);
+            access_log::write(&client, &method, 407, (0, 0));
+            return Ok(proxy_auth_required());
+        }
+    }
+    // It's meant for the proxy alone
+    req.headers_mut().remove(PROXY_AUTHORIZATION);
+
     if req.method() == Method::CONNECT {
         match req.uri().authority().map(|auth| auth.to_string()) {
             None => {
                 error!("missing authority: {}", req.uri());
                 let mut resp = Response::new(full("missing authority"));
                 *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+                access_log::write(&client, &method, 400, (0, 0));
                 Ok(resp)
             }
 
             Some(addr) => {
+                client.handshake_done();
+                let server = match tunnel_addr {
+                    Some(a) => connect_tunnel(&dialer, &a, &addr, &mut client).await,
+                    None => connect(&dialer, &addr, &mut client).await,
+                };
+
+                let server = match server {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("connect error: {:?}", e);
+                        client.metrics.failed.inc();
+                        let resp = error_response(&e);
+                        access_log::write(&client, &method, resp.status().as_u16(), (0, 0));
+                        return Ok(resp);
+                    }
+                };
+
+                // The upgraded connection outlives the http connection it came from.
+                let guard = shutdown.track();
+                let m = client.metrics;
                 tokio::task::spawn(async move {
-                    match hyper::upgrade::on(req).await {
+                    let _guard = guard;
+                    let _active = m.active.track();
+                    let bytes = match hyper::upgrade::on(req).await {
                         Ok(upgraded) => {
-                            if let Err(e) = tunnel(upgraded, addr, tunnel_addr).await {
-                                error!("tunnel error: {}", e);
-                            };
+                            // The bytes through `upgraded` are already counted by the http
+                            // connection it came from
+                            let r = relay_streams(&mut client, upgraded, server).await;
+                            r.record(&client);
+                            info!("{} - {r}", client.addr);
+                            (r.up, r.down)
                         }
-                        Err(e) => error!("upgrade error: {}", e),
-                    }
+                        Err(e) => {
+                            m.failed.inc();
+                            error!("upgrade error: {}", e);
+                            (0, 0)
+                        }
+                    };
+                    access_log::write(&client, &method, 200, bytes);
                 });
                 Ok(Response::new(empty()))
             }
@@ -85,24 +290,157 @@ async fn proxy(
         let port = req.uri().port_u16().unwrap_or(80);
         let addr = format!("{}:{}", host, port);
 
-        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = match connect(&dialer, &addr, &mut client).await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("connect error: {:?}", e);
+                client.metrics.failed.inc();
+                let resp = error_response(&e);
+                access_log::write(&client, &method, resp.status().as_u16(), (0, 0));
+                return Ok(resp);
+            }
+        };
 
-        let (mut sender, conn) = client::conn::http1::Builder::new()
+        // Not relayed, so metered here, the requests and responses both ways
+        let stream = quota::metered(stream, &client);
+        let (mut sender, conn) = match client::conn::http1::Builder::new()
             .preserve_header_case(true)
             .title_case_headers(true)
             .handshake(stream)
-            .await?;
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                access_log::write(&client, &method, 502, (0, 0));
+                return Err(e);
+            }
+        };
         tokio::task::spawn(async move {
             if let Err(err) = conn.await {
                 error!("Connection failed: {:?}", err);
             }
         });
 
-        let resp = sender.send_request(req).await?;
-        Ok(resp.map(|b| b.boxed()))
+        let up = Arc::new(AtomicU64::new(0));
+        let counter = up.clone();
+        let m = client.metrics;
+        let req = req.map(|b| {
+            b.map_frame(move |f| {
+                if let Some(d) = f.data_ref() {
+                    counter.fetch_add(d.len() as u64, Ordering::Relaxed);
+                    m.bytes_up.add(d.len() as u64);
+                }
+                f
+            })
+        });
+
+        let resp = match sender.send_request(req).await {
+            Ok(r) => r,
+            Err(e) => {
+                let bytes = (up.load(Ordering::Relaxed), 0);
+                access_log::write(&client, &method, 502, bytes);
+                return Err(e);
+            }
+        };
+
+        let mut log = RequestLog {
+            client,
+            method,
+            status: resp.status().as_u16(),
+            up,
+            down: 0,
+        };
+        Ok(resp.map(|b| {
+            b.map_frame(move |f| {
+                if let Some(d) = f.data_ref() {
+                    log.received(d.len());
+                }
+                f
+            })
+            .boxed()
+        }))
+    }
+}
+
+/// Logs a plain http request once its response body is done with, whether fully sent or not.
+struct RequestLog {
+    client: Client,
+    method: String,
+    status: u16,
+    /// The bytes of the request body, counted as it's sent.
+    up: Arc<AtomicU64>,
+    /// The bytes of the response body.
+    down: u64,
+}
+
+impl RequestLog {
+    fn received(&mut self, n: usize) {
+        self.down += n as u64;
+        self.client.metrics.bytes_down.add(n as u64);
+    }
+}
+
+impl Drop for RequestLog {
+    fn drop(&mut self) {
+        let bytes = (self.up.load(Ordering::Relaxed), self.down);
+        access_log::write(&self.client, &self.method, self.status, bytes);
     }
 }
 
+/// The Proxy-Authorization header that last authenticated the client on a connection, and the
+/// user it did as, to spare the authenticator the requests that follow.
+type Authorized = Mutex<Option<(HeaderValue, String)>>;
+
+/// Authenticates the client with the Proxy-Authorization header of `req`, see
+/// https://www.rfc-editor.org/rfc/rfc7617
+async fn authorize(
+    req: &Request<body::Incoming>,
+    auth: &dyn Authenticator,
+    authorized: &Authorized,
+    client: &mut Client,
+) -> anyhow::Result<()> {
+    let Some(value) = req.headers().get(PROXY_AUTHORIZATION) else {
+        return Err(auth::missing(client));
+    };
+
+    if let Some((v, user)) = &*authorized.lock().unwrap() {
+        if v == value {
+            client.set_user(Some(user.clone()));
+            return Ok(());
+        }
+    }
+
+    let Some(credentials) = basic_credentials(value) else {
+        return Err(auth::missing(client));
+    };
+    auth::check(auth, client, &credentials).await?;
+
+    let user = client.user.clone().unwrap_or_default();
+    *authorized.lock().unwrap() = Some((value.clone(), user));
+    Ok(())
+}
+
+/// Parses `Basic <base64 of USER:PASSWORD>`.
+fn basic_credentials(value: &HeaderValue) -> Option<Credentials> {
+    let (scheme, token) = value.to_str().ok()?.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = STANDARD.decode(token.trim()).ok()?;
+    String::from_utf8(decoded).ok()?.parse().ok()
+}
+
+fn proxy_auth_required() -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(empty());
+    *resp.status_mut() = http::StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(
+        PROXY_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"bubble\""),
+    );
+    resp
+}
+
 fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
@@ -115,22 +453,33 @@ fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
-async fn tunnel(
-    mut upgraded: Upgraded,
-    addr: String,
-    tunnel_addr: Option<String>,
-) -> std::io::Result<()> {
-    let mut server = match tunnel_addr {
-        Some(a) => {
-            let mut s = TcpStream::connect(a).await?;
-            s.write_u16(addr.len() as u16).await?;
-            s.write_all(addr.as_bytes()).await?;
-            s
-        }
-        None => TcpStream::connect(addr).await?,
+fn error_response(e: &anyhow::Error) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(empty());
+    *resp.status_mut() = if dial::is_denied(e) {
+        http::StatusCode::FORBIDDEN
+    } else if dial::is_limited(e) {
+        http::StatusCode::TOO_MANY_REQUESTS
+    } else if dial::is_timed_out(e) {
+        http::StatusCode::GATEWAY_TIMEOUT
+    } else {
+        http::StatusCode::BAD_GATEWAY
     };
+    resp
+}
 
-    tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+async fn connect(dialer: &Dialer, addr: &str, client: &mut Client) -> anyhow::Result<TcpStream> {
+    let target = addr.parse::<Target>()?;
+    dialer.connect(&target, client).await
+}
 
-    Ok(())
+async fn connect_tunnel(
+    dialer: &Dialer,
+    tunnel_addr: &str,
+    addr: &str,
+    client: &mut Client,
+) -> anyhow::Result<TcpStream> {
+    let target = addr.parse::<Target>()?;
+    dialer
+        .connect_with(&Action::Tunnel(tunnel_addr.to_string()), &target, client)
+        .await
 }
@@ -0,0 +1,84 @@
+use std::{future::Future, io, net::SocketAddr, sync::Arc};
+
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::warn;
+
+use super::tcp::TcpOptions;
+
+/// How a server listens for connections.
+#[derive(Clone, Copy, Debug)]
+pub struct ListenOptions {
+    /// The number of accept loops, each on a socket of its own bound to the same address with
+    /// SO_REUSEPORT.
+    pub acceptors: u16,
+    /// The maximum number of connections waiting to be accepted on each socket.
+    pub backlog: u32,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        ListenOptions {
+            acceptors: 1,
+            backlog: 1024,
+        }
+    }
+}
+
+/// Binds `c.acceptors` sockets to `addr`, tuned with `tcp`, and runs `accept` on each of them,
+/// each in a task of its own so that accepting scales across cores, until they all return.
+///
+/// With more than one acceptor, the kernel spreads the new connections among the sockets, see
+/// SO_REUSEPORT in https://man7.org/linux/man-pages/man7/socket.7.html
+pub async fn run<F, Fut>(addr: SocketAddr, c: &ListenOptions, tcp: TcpOptions, accept: F)
+where
+    F: Fn(Listener) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let tcp = Arc::new(tcp);
+    let tasks = (0..c.acceptors)
+        .map(|_| bind(addr, c.backlog, &tcp).expect("TcpListener::bind"))
+        .map(|l| {
+            tokio::spawn(accept(Listener {
+                inner: l,
+                tcp: tcp.clone(),
+            }))
+        })
+        .collect::<Vec<_>>();
+
+    for t in tasks {
+        let _ = t.await;
+    }
+}
+
+/// A [`TcpListener`] tuning the connections it accepts.
+pub struct Listener {
+    inner: TcpListener,
+    tcp: Arc<TcpOptions>,
+}
+
+impl Listener {
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (s, peer) = self.inner.accept().await?;
+        if let Err(e) = self.tcp.apply_accepted(&s) {
+            warn!("{peer} - apply_accepted: {e}");
+        }
+        Ok((s, peer))
+    }
+}
+
+fn bind(addr: SocketAddr, backlog: u32, tcp: &TcpOptions) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    #[cfg(unix)]
+    {
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+    }
+    tcp.apply_listener(&socket)?;
+
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
@@ -0,0 +1,179 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use tokio::{net::TcpStream, time};
+use tracing::{error, info, warn};
+
+use crate::{
+    acl::Acl,
+    auth::Authenticator,
+    hooks, limit, metrics,
+    registry::Conn,
+    server::Settings,
+    shutdown::Shutdown,
+    timeout::{Stage, Timeouts},
+};
+
+use super::{
+    dial::Dialer,
+    http,
+    listen::{self, ListenOptions, Listener},
+    socks5,
+    tcp::TcpOptions,
+    tls,
+};
+
+/// Serves socks4, socks5, http and tls clients on a single port, telling them apart by the
+/// first byte they send.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn start<A>(
+    addr: A,
+    opts: ListenOptions,
+    tcp: TcpOptions,
+    tunnel_addr: Option<String>,
+    acl: Acl,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    shutdown: Shutdown,
+) where
+    A: Into<SocketAddr>,
+{
+    let acl = Arc::new(acl);
+    listen::run(addr.into(), &opts, tcp, |l| {
+        accept(
+            l,
+            tunnel_addr.clone(),
+            acl.clone(),
+            auth.clone(),
+            dialer.clone(),
+            settings.clone(),
+            shutdown.clone(),
+        )
+    })
+    .await;
+
+    info!("mixed proxy server stopped accepting new connections");
+}
+
+async fn accept(
+    l: Listener,
+    tunnel_addr: Option<String>,
+    acl: Arc<Acl>,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    mut shutdown: Shutdown,
+) {
+    let m = metrics::listener("mixed");
+
+    loop {
+        let r = tokio::select! {
+            r = l.accept() => r,
+            _ = shutdown.recv() => break,
+        };
+
+        match r {
+            Err(e) => {
+                error!("An error occurred while calling listener.accept: {}", e);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok((s, peer)) => {
+                if !acl.is_allowed(peer.ip()) {
+                    warn!("{peer} - denied by acl");
+                    continue;
+                }
+                let conn = match limit::accept(&settings, m, peer) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("{peer} - {e}");
+                        tokio::spawn(refuse(s, m, settings.timeouts));
+                        continue;
+                    }
+                };
+                if let Err(e) = hooks::accept(&settings, m.name, peer) {
+                    warn!("{peer} - {e}");
+                    continue;
+                }
+
+                m.accepted.inc();
+                let guard = shutdown.track();
+                let tunnel_addr = tunnel_addr.clone();
+                let auth = auth.clone();
+                let dialer = dialer.clone();
+                let settings = settings.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    let _active = m.active.track();
+                    handle_socket(s, conn, tunnel_addr, auth, dialer, settings, shutdown, m).await;
+                    drop(guard);
+                });
+            }
+        }
+    }
+}
+
+/// Tells a client over the connection limits that it is, once it has sent its first byte, in
+/// the protocol it speaks. TLS clients are closed on, as that would take a server certificate.
+async fn refuse(s: TcpStream, m: &'static metrics::Listener, timeouts: Timeouts) {
+    let mut b = [0; 1];
+    let peek = async { s.peek(&mut b).await.context("TcpStream.peek") };
+
+    match timeouts.within(Stage::Handshake, m, peek).await {
+        Ok(1) if matches!(b[0], 0x04 | 0x05) => socks5::connection::refuse(s, m, timeouts).await,
+        Ok(1) if b[0].is_ascii_uppercase() => {
+            let _ = s.try_write(http::TOO_MANY_REQUESTS);
+        }
+        _ => {}
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_socket(
+    s: TcpStream,
+    conn: Arc<Conn>,
+    tunnel_addr: Option<String>,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    shutdown: Shutdown,
+    m: &'static metrics::Listener,
+) {
+    let mut b = [0; 1];
+    let peek = async { s.peek(&mut b).await.context("TcpStream.peek") };
+
+    match settings.timeouts.within(Stage::Handshake, m, peek).await {
+        Err(e) => {
+            m.failed.inc();
+            warn!("mixed handshake error: {e:#}");
+        }
+        Ok(0) => {}
+        Ok(_) => match b[0] {
+            0x04 | 0x05 => {
+                socks5::connection::process(s, conn, &dialer, auth.as_deref(), m, settings).await
+            }
+            // The ClientHello is passed through as is, with no room for credentials
+            0x16 if auth.is_some() => {
+                m.auth_failures.inc();
+                warn!("{:?} - tls clients can't authenticate", s.peer_addr());
+            }
+            0x16 => {
+                if let Err(e) = tls::handle_socket(s, conn, &dialer, m, settings).await {
+                    m.failed.inc();
+                    warn!("tls error: {:?}", e);
+                }
+            }
+            b'A'..=b'Z' => {
+                http::handle_socket(s, conn, tunnel_addr, auth, dialer, settings, shutdown, m).await
+            }
+            v => {
+                m.failed.inc();
+                warn!(
+                    "{:?} - unknown protocol, first byte: {:#04x}",
+                    s.peer_addr(),
+                    v
+                )
+            }
+        },
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::dial::Client;
+use crate::{limit, quota, registry::Counted};
+
+#[cfg(target_os = "linux")]
+use super::splice;
+
+/// How many bytes to read from either side before writing them to the other.
+const BUF: usize = 16 * 1024;
+
+/// Why a relay ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Close {
+    /// The client was done sending first, then the destination.
+    ClientEof,
+    /// The destination was done sending first, then the client.
+    ServerEof,
+    /// Either side reset the connection.
+    Reset,
+    /// No bytes went either way for too long, or the system gave up on the peer.
+    Timeout,
+    /// The connection was closed through the admin api.
+    Killed,
+    /// Any other error.
+    Error,
+}
+
+impl fmt::Display for Close {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Close::ClientEof => "client_eof",
+            Close::ServerEof => "server_eof",
+            Close::Reset => "reset",
+            Close::Timeout => "timeout",
+            Close::Killed => "killed",
+            Close::Error => "error",
+        })
+    }
+}
+
+impl Close {
+    fn of(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Close::Reset,
+            io::ErrorKind::TimedOut => Close::Timeout,
+            _ => Close::Error,
+        }
+    }
+}
+
+/// What a relay did, however it ended.
+#[derive(Debug)]
+pub struct Relayed {
+    /// The bytes sent up from the client to the destination.
+    pub up: u64,
+    /// The bytes sent down from the destination to the client.
+    pub down: u64,
+    pub close: Close,
+    /// The error that ended the relay, if any.
+    pub error: Option<io::Error>,
+}
+
+impl Relayed {
+    /// Counts the connection as failed in the metrics of the listener of `client` if the relay
+    /// ended in an error. Its bytes were added to them as they went.
+    pub fn record(&self, client: &Client) {
+        if self.error.is_some() {
+            client.metrics.failed.inc();
+        }
+    }
+}
+
+impl fmt::Display for Relayed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent: {}, received: {}, close: {}",
+            self.up, self.down, self.close
+        )?;
+        if let Some(e) = &self.error {
+            write!(f, " ({e})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies data both ways between the client at `a` and the destination at `b`, within the
+/// bandwidth limits, until both sides are done, the connection is closed through the registry
+/// or it's been idle for too long.
+///
+/// When one side is done sending, the write half of the other is shut down and bytes keep
+/// going the other way, so half-closed connections work as they would without the proxy. With
+/// splice enabled, the bytes of clients with no bandwidth limit or quota skip userspace.
+pub async fn relay(client: &mut Client, a: &mut TcpStream, b: &mut TcpStream) -> Relayed {
+    #[cfg(target_os = "linux")]
+    if splice::enabled() && !limit::applies(client) && !quota::applies(client) {
+        let (up, down) = (AtomicU64::new(0), AtomicU64::new(0));
+        let (conn, m) = (client.conn.clone(), client.metrics);
+        let r = run(
+            client,
+            splice::copy(a, b, &[&up, &conn.up, m.bytes_up.as_atomic()]),
+            splice::copy(b, a, &[&down, &conn.down, m.bytes_down.as_atomic()]),
+        )
+        .await;
+        return finish(client, up, down, r);
+    }
+
+    let conn = client.conn.clone();
+    relay_streams(client, Counted::new(a, conn), b).await
+}
+
+/// Like [`relay`], for any kind of stream. The bytes through `a` aren't added to the registry,
+/// which is left to the caller.
+pub async fn relay_streams<A, B>(client: &mut Client, a: A, b: B) -> Relayed
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let a = limit::limit(quota::metered(a, client), client);
+    let (mut ar, mut aw) = io::split(a);
+    let (mut br, mut bw) = io::split(b);
+
+    let (up, down) = (AtomicU64::new(0), AtomicU64::new(0));
+    let m = client.metrics;
+    let r = run(
+        client,
+        copy(&mut ar, &mut bw, &[&up, m.bytes_up.as_atomic()]),
+        copy(&mut br, &mut aw, &[&down, m.bytes_down.as_atomic()]),
+    )
+    .await;
+    finish(client, up, down, r)
+}
+
+/// Waits for both directions to be done, or for whatever ends the relay first, returning why.
+async fn run(
+    client: &Client,
+    up: impl Future<Output = io::Result<()>>,
+    down: impl Future<Output = io::Result<()>>,
+) -> (Close, Option<io::Error>) {
+    let closed = client.conn.closed();
+    let idle = client.timeouts().idle(&client.conn, client.metrics);
+    tokio::pin!(up, down, closed, idle);
+
+    let (mut up_done, mut down_done) = (false, false);
+    let mut close = None;
+
+    while !(up_done && down_done) {
+        let r = tokio::select! {
+            r = &mut up, if !up_done => {
+                up_done = true;
+                close.get_or_insert(Close::ClientEof);
+                r
+            }
+            r = &mut down, if !down_done => {
+                down_done = true;
+                close.get_or_insert(Close::ServerEof);
+                r
+            }
+            _ = &mut closed => {
+                let e = io::Error::new(io::ErrorKind::ConnectionAborted, "closed through the admin api");
+                return (Close::Killed, Some(e));
+            }
+            e = &mut idle => return (Close::Timeout, Some(e.into())),
+        };
+
+        if let Err(e) = r {
+            return (Close::of(&e), Some(e));
+        }
+    }
+
+    (close.unwrap_or(Close::ClientEof), None)
+}
+
+fn finish(
+    client: &mut Client,
+    up: AtomicU64,
+    down: AtomicU64,
+    (close, error): (Close, Option<io::Error>),
+) -> Relayed {
+    client.close = Some(close);
+    Relayed {
+        up: up.into_inner(),
+        down: down.into_inner(),
+        close,
+        error,
+    }
+}
+
+/// Copies data from `r` to `w`, adding it to each of `counters` as it goes, until `r` is done,
+/// then shuts down the write half of `w`.
+async fn copy<R, W>(r: &mut R, w: &mut W, counters: &[&AtomicU64]) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUF];
+
+    loop {
+        let n = r.read(&mut buf).await?;
+        if n == 0 {
+            return match w.shutdown().await {
+                // The peer may be gone already
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+                r => r,
+            };
+        }
+
+        w.write_all(&buf[..n]).await?;
+        w.flush().await?;
+        for c in counters {
+            c.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
@@ -1,37 +1,105 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use tokio::{net::TcpSocket, time};
-use tracing::error;
+use tokio::time;
+use tracing::{error, info, warn};
 
-mod connection;
+use super::{
+    dial::Dialer,
+    listen::{self, ListenOptions, Listener},
+    tcp::TcpOptions,
+};
+use crate::{
+    acl::Acl, auth::Authenticator, hooks, limit, metrics, server::Settings, shutdown::Shutdown,
+};
+
+pub mod connection;
 mod util;
 
 // https://www.rfc-editor.org/rfc/rfc1928
 // https://www.rfc-editor.org/rfc/rfc1929
+// https://www.openssh.com/txt/socks4.protocol
 
-pub async fn start<A>(addr: A)
-where
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn start<A>(
+    addr: A,
+    opts: ListenOptions,
+    tcp: TcpOptions,
+    acl: Acl,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    shutdown: Shutdown,
+) where
     A: Into<SocketAddr>,
 {
-    let socket = TcpSocket::new_v4().expect("TcpSocket::new_v4");
+    let acl = Arc::new(acl);
+    listen::run(addr.into(), &opts, tcp, |l| {
+        accept(
+            l,
+            acl.clone(),
+            auth.clone(),
+            dialer.clone(),
+            settings.clone(),
+            shutdown.clone(),
+        )
+    })
+    .await;
 
-    #[cfg(unix)]
-    {
-        socket.set_reuseaddr(true).expect("socket.set_reuseaddr");
-        socket.set_reuseport(true).expect("socket.set_reuseport");
-    }
+    info!("socks5 proxy server stopped accepting new connections");
+}
 
-    socket.bind(addr.into()).expect("socket.bind");
-    let listener = socket.listen(1024).expect("socket.listen");
+async fn accept(
+    listener: Listener,
+    acl: Arc<Acl>,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    mut shutdown: Shutdown,
+) {
+    let m = metrics::listener("socks5");
 
     loop {
-        match listener.accept().await {
+        let r = tokio::select! {
+            r = listener.accept() => r,
+            _ = shutdown.recv() => break,
+        };
+
+        match r {
             Err(e) => {
                 error!("listener.accept: {:?}", e);
                 time::sleep(Duration::from_secs(1)).await;
             }
-            Ok((socket, _)) => {
-                tokio::spawn(connection::process(socket));
+            Ok((socket, peer)) => {
+                // Denied clients are closed on rather than replied to, as socks4 and socks5 ones
+                // can't be told apart before they send anything
+                if !acl.is_allowed(peer.ip()) {
+                    warn!("{peer} - denied by acl");
+                    continue;
+                }
+                let conn = match limit::accept(&settings, m, peer) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("{peer} - {e}");
+                        tokio::spawn(connection::refuse(socket, m, settings.timeouts));
+                        continue;
+                    }
+                };
+                if let Err(e) = hooks::accept(&settings, m.name, peer) {
+                    warn!("{peer} - {e}");
+                    continue;
+                }
+
+                m.accepted.inc();
+                let guard = shutdown.track();
+                let auth = auth.clone();
+                let dialer = dialer.clone();
+                let settings = settings.clone();
+                tokio::spawn(async move {
+                    let _active = m.active.track();
+                    let auth = auth.as_deref();
+                    connection::process(socket, conn, &dialer, auth, m, settings).await;
+                    drop(guard);
+                });
             }
         }
     }
@@ -1,42 +1,196 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
-use anyhow::{anyhow, bail, ensure, Context};
+use anyhow::{bail, ensure, Context};
 
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    net::{self, TcpSocket, TcpStream},
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
 };
 use tracing::{debug, info, warn};
 
 use super::util;
+use crate::{
+    access_log,
+    auth::{self, Authenticator, Credentials},
+    metrics,
+    proxy::{
+        dial::{self, Client, Dialer, Target},
+        relay::{relay, Relayed},
+    },
+    registry::Conn,
+    server::Settings,
+    timeout::{Stage, Timeouts},
+};
 
 const VERSION: u8 = 0x05;
+const VERSION_4: u8 = 0x04;
+
+const REQUEST_GRANTED: u8 = 90;
+const REQUEST_REJECTED: u8 = 91;
+
+const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const REP_NOT_ALLOWED: u8 = 0x02;
 
-pub async fn process(mut socket: TcpStream) {
+pub(crate) async fn process(
+    mut socket: TcpStream,
+    conn: Arc<Conn>,
+    dialer: &Dialer,
+    auth: Option<&dyn Authenticator>,
+    m: &'static metrics::Listener,
+    settings: Arc<Settings>,
+) {
     let addrs = util::tcp_stream_addrs(&socket, false);
     // debug!("{}: new connection", addrs);
 
-    match handle(&mut socket).await {
-        Err(e) => warn!("{addrs} - error: {e:?}"),
-        Ok((tx, rx)) => info!("{} - sent: {tx}, received: {rx}", addrs),
+    let mut client = Client::new(conn, "socks5", m, settings);
+
+    let r = handle(&mut socket, dialer, auth, &mut client).await;
+    let status = access_log::status(&client, &r);
+
+    match r {
+        Err(e) => {
+            m.failed.inc();
+            warn!("{addrs} - error: {e:?}");
+            access_log::write(&client, "CONNECT", status, (0, 0));
+        }
+        Ok(r) => {
+            r.record(&client);
+            info!("{addrs} - {r}");
+            access_log::write(&client, "CONNECT", status, (r.up, r.down));
+        }
     }
 }
 
-async fn handle(socket: &mut TcpStream) -> anyhow::Result<(u64, u64)> {
-    authenticate(socket).await?;
-    let mut socket2 = connect(socket).await?;
+/// Tells a client refused once accepted, e.g. for going over the connection limits, that it
+/// was in the version of the protocol it speaks: a socks4 client gets its request rejected, and
+/// a socks5 one its request not allowed, past the method negotiation but not authenticated.
+pub(crate) async fn refuse(mut socket: TcpStream, m: &metrics::Listener, timeouts: Timeouts) {
+    let reply = async {
+        let mut ver = [0; 1];
+        socket
+            .peek(&mut ver)
+            .await
+            .context("refuse: peek version")?;
 
-    let r = io::copy_bidirectional(socket, &mut socket2)
-        .await
-        .context("io::copy_bidirectional")?;
+        if ver[0] == VERSION_4 {
+            request_v4(&mut socket).await?;
+            socket
+                .write_all(&[0, REQUEST_REJECTED, 0, 0, 0, 0, 0, 0])
+                .await
+                .context("refuse: write reply")?;
+            return Ok(());
+        }
+
+        let mut buf = [0; 255];
+        socket
+            .read_exact(&mut buf[..2])
+            .await
+            .context("refuse: read ver/nmethods")?;
+        ensure!(buf[0] == VERSION, "refuse: invalid version: {}", buf[0]);
+
+        let nmethods = buf[1] as usize;
+        let methods = &mut buf[..nmethods];
+        socket
+            .read_exact(methods)
+            .await
+            .context("refuse: read methods")?;
+        if !methods.contains(&NO_AUTHENTICATION_REQUIRED) {
+            socket
+                .write_all(&[VERSION, NO_ACCEPTABLE_METHODS])
+                .await
+                .context("refuse: write NO_ACCEPTABLE_METHODS")?;
+            return Ok(());
+        }
+
+        socket
+            .write_all(&[VERSION, NO_AUTHENTICATION_REQUIRED])
+            .await
+            .context("refuse: write method")?;
+        request(&mut socket).await?;
+        socket
+            .write_all(&[
+                VERSION,
+                REP_NOT_ALLOWED,
+                0x00,
+                ATYP_IP_V4_ADDR,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ])
+            .await
+            .context("refuse: write reply")
+    };
 
-    Ok(r)
+    if let Err(e) = timeouts.within(Stage::Handshake, m, reply).await {
+        debug!("{:?} - {e:#}", socket.peer_addr());
+    }
 }
 
-async fn authenticate(socket: &mut TcpStream) -> anyhow::Result<()> {
-    const NO_AUTHENTICATION_REQUIRED: u8 = 0x00;
-    // const USERNAME_PASSWORD: u8 = 0x02;
-    const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+async fn handle(
+    socket: &mut TcpStream,
+    dialer: &Dialer,
+    auth: Option<&dyn Authenticator>,
+    client: &mut Client,
+) -> anyhow::Result<Relayed> {
+    let m = client.metrics;
+    let (ver, target) = client
+        .timeouts()
+        .within(Stage::Handshake, m, handshake(socket, auth, client))
+        .await?;
+    client.handshake_done();
+
+    let mut socket2 = if ver == VERSION_4 {
+        connect_v4(socket, dialer, client, target).await?
+    } else {
+        connect(socket, dialer, client, target).await?
+    };
+
+    Ok(relay(client, socket, &mut socket2).await)
+}
+
+/// Reads what the client sends up to the destination it asks for, authenticating it on the way
+/// if `auth` is given, returning the version of the protocol it speaks along with the
+/// destination.
+async fn handshake(
+    socket: &mut TcpStream,
+    auth: Option<&dyn Authenticator>,
+    client: &mut Client,
+) -> anyhow::Result<(u8, Target)> {
+    let mut ver = [0; 1];
+    socket
+        .peek(&mut ver)
+        .await
+        .context("handshake: peek version")?;
+
+    if ver[0] == VERSION_4 {
+        client.set_protocol("socks4");
+        let target = request_v4(socket).await?;
+
+        // Socks4 has no passwords, only a USERID anyone could claim
+        if auth.is_some() {
+            let _ = socket
+                .write_all(&[0, REQUEST_REJECTED, 0, 0, 0, 0, 0, 0])
+                .await;
+            return Err(auth::missing(client));
+        }
+        Ok((VERSION_4, target))
+    } else {
+        authenticate(socket, auth, client).await?;
+        Ok((VERSION, request(socket).await?))
+    }
+}
+
+async fn authenticate(
+    socket: &mut TcpStream,
+    auth: Option<&dyn Authenticator>,
+    client: &mut Client,
+) -> anyhow::Result<()> {
+    const USERNAME_PASSWORD: u8 = 0x02;
 
     // +----+----------+----------+
     // |VER | NMETHODS | METHODS  |
@@ -79,28 +233,44 @@ async fn authenticate(socket: &mut TcpStream) -> anyhow::Result<()> {
     // | 1  |   1    |
     // +----+--------+
 
-    if !methods.contains(&NO_AUTHENTICATION_REQUIRED) {
-        // TODO
+    let method = if auth.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTHENTICATION_REQUIRED
+    };
+
+    if !methods.contains(&method) {
         socket
             .write_all(&[VERSION, NO_ACCEPTABLE_METHODS])
             .await
             .context("authenticate write NO_ACCEPTABLE_METHODS")?;
+        if auth.is_some() {
+            return Err(auth::missing(client));
+        }
+        client.metrics.auth_failures.inc();
         bail!("invalid authentication method");
     }
 
     socket
-        .write_all(&[VERSION, NO_AUTHENTICATION_REQUIRED])
+        .write_all(&[VERSION, method])
         .await
-        .context("authenticate write NO_ACCEPTABLE_METHODS")?;
+        .context("authenticate write method")?;
 
-    // authenticate_using_username_password(socket).await?;
+    if let Some(auth) = auth {
+        authenticate_using_username_password(socket, auth, client).await?;
+    }
 
     Ok(())
 }
 
-#[allow(dead_code)] // TODO
-async fn authenticate_using_username_password(socket: &mut TcpStream) -> anyhow::Result<()> {
+async fn authenticate_using_username_password(
+    socket: &mut TcpStream,
+    auth: &dyn Authenticator,
+    client: &mut Client,
+) -> anyhow::Result<()> {
     const VER: u8 = 1;
+    const SUCCESS: u8 = 0;
+    const FAILURE: u8 = 1;
 
     // +----+------+----------+------+----------+
     // |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
@@ -138,11 +308,11 @@ async fn authenticate_using_username_password(socket: &mut TcpStream) -> anyhow:
         .await
         .context("authenticate_using_username_password: read password")?;
 
-    debug!(
-        "username: {}, password: {}",
-        std::str::from_utf8(uname).unwrap(),
-        std::str::from_utf8(passwd.as_slice()).unwrap()
-    );
+    let credentials = Credentials {
+        username: String::from_utf8_lossy(uname).into_owned(),
+        password: String::from_utf8_lossy(&passwd).into_owned(),
+    };
+    let r = auth::check(auth, client, &credentials).await;
 
     // +----+--------+
     // |VER | STATUS |
@@ -150,23 +320,23 @@ async fn authenticate_using_username_password(socket: &mut TcpStream) -> anyhow:
     // | 1  |   1    |
     // +----+--------+
 
-    let status = if uname == passwd.as_slice() { 0 } else { 1 };
+    let status = if r.is_ok() { SUCCESS } else { FAILURE };
     socket
         .write_all(&[VER, status])
         .await
-        .context("authenticate_using_username_password: write replay")?;
+        .context("authenticate_using_username_password: write reply")?;
 
-    Ok(())
+    r
 }
 
-async fn connect(socket: &mut TcpStream) -> anyhow::Result<TcpStream> {
+const ATYP_IP_V4_ADDR: u8 = 0x01;
+const ATYP_IP_V6_ADDR: u8 = 0x04;
+const ATYP_DOMAINNAME: u8 = 0x03;
+
+async fn request(socket: &mut TcpStream) -> anyhow::Result<Target> {
     // TODO minimize the number of system calls
     const CMD_CONNECT: u8 = 0x01;
 
-    const ATYP_IP_V4_ADDR: u8 = 0x01;
-    const ATYP_IP_V6_ADDR: u8 = 0x04;
-    const ATYP_DOMAINNAME: u8 = 0x03;
-
     // +----+-----+-------+------+----------+----------+
     // |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
     // +----+-----+-------+------+----------+----------+
@@ -178,28 +348,28 @@ async fn connect(socket: &mut TcpStream) -> anyhow::Result<TcpStream> {
     socket
         .read_exact(&mut buf[..4])
         .await
-        .context("connect: read basics")?;
+        .context("request: read basics")?;
 
-    ensure!(buf[0] == VERSION, "connect: invalid VERSION: {}", buf[0]);
-    ensure!(buf[1] == CMD_CONNECT, "connect: invalid CMD: {}", buf[1]);
-    ensure!(buf[2] == 0x00, "connect: invalid RSV: {}", buf[2]);
+    ensure!(buf[0] == VERSION, "request: invalid VERSION: {}", buf[0]);
+    ensure!(buf[1] == CMD_CONNECT, "request: invalid CMD: {}", buf[1]);
+    ensure!(buf[2] == 0x00, "request: invalid RSV: {}", buf[2]);
 
-    let dst_addr = match buf[3] {
+    let target = match buf[3] {
         ATYP_IP_V4_ADDR => {
             let mut ip = [0; 4];
             socket
                 .read_exact(&mut ip)
                 .await
-                .context("connect: read ipv4 addr")?;
+                .context("request: read ipv4 addr")?;
 
-            let port = socket.read_u16().await.context("connect: read ipv4 port")?;
+            let port = socket.read_u16().await.context("request: read ipv4 port")?;
             let addr = (ip, port).into();
             debug!(
                 "{} - connect to: {}",
                 util::tcp_stream_addrs(socket, false),
                 addr
             );
-            addr
+            Target::Addr(addr)
         }
 
         ATYP_IP_V6_ADDR => {
@@ -207,38 +377,38 @@ async fn connect(socket: &mut TcpStream) -> anyhow::Result<TcpStream> {
             socket
                 .read_exact(&mut ip)
                 .await
-                .context("connect: read ipv6 addr")?;
+                .context("request: read ipv6 addr")?;
 
-            let port = socket.read_u16().await.context("connect: read ipv6 port")?;
+            let port = socket.read_u16().await.context("request: read ipv6 port")?;
             let addr = (ip, port).into();
             debug!(
                 "{} - connect to: {}",
                 util::tcp_stream_addrs(socket, false),
                 addr
             );
-            addr
+            Target::Addr(addr)
         }
 
         ATYP_DOMAINNAME => {
             let n = socket
                 .read_u8()
                 .await
-                .context("connect: read domainname length")? as usize;
+                .context("request: read domainname length")? as usize;
 
             let domain_name = &mut buf[..n];
 
             socket
                 .read_exact(domain_name)
                 .await
-                .context("connect: read domainname")?;
+                .context("request: read domainname")?;
 
             let domain_name =
-                std::str::from_utf8(domain_name).context("connect: str::from_utf8(domain_name)")?;
+                std::str::from_utf8(domain_name).context("request: str::from_utf8(domain_name)")?;
 
             let port = socket
                 .read_u16()
                 .await
-                .context("connect: read domain_name port")?;
+                .context("request: read domain_name port")?;
 
             debug!(
                 "{} - connect to: {}:{}",
@@ -247,42 +417,43 @@ async fn connect(socket: &mut TcpStream) -> anyhow::Result<TcpStream> {
                 port
             );
 
-            let iter = net::lookup_host((domain_name, port))
-                .await
-                .context("connect: lookup_host")?;
-
-            let mut addr = None;
-            for a in iter {
-                addr = Some(a);
-                if a.is_ipv4() {
-                    break;
-                }
-            }
-            addr.ok_or_else(|| anyhow!("connect: lookup_host: empty: {}:{}", domain_name, port))?
+            Target::Domain(domain_name.to_string(), port)
         }
 
-        _ => bail!("connect: invalid ATYP: {}", buf[3]),
+        _ => bail!("request: invalid ATYP: {}", buf[3]),
     };
 
-    let socket2 = match dst_addr {
-        SocketAddr::V4(_) => TcpSocket::new_v4().context("connect: TcpSocket::new_v4")?,
-        SocketAddr::V6(_) => TcpSocket::new_v6().context("connect: TcpSocket::new_v6")?,
-    };
+    Ok(target)
+}
 
-    {
-        // TODO
-        // let mut local_addr = socket.local_addr().context("connect: socket.local_addr")?;
-        // if dst_addr.is_ipv4() && local_addr.is_ipv4() || dst_addr.is_ipv6() && local_addr.is_ipv6()
-        // {
-        //     local_addr.set_port(0);
-        //     socket2.bind(local_addr).context("connect: socket2.bind")?;
-        // }
-    }
+async fn connect(
+    socket: &mut TcpStream,
+    dialer: &Dialer,
+    client: &mut Client,
+    target: Target,
+) -> anyhow::Result<TcpStream> {
+    const REP_SUCCEEDED: u8 = 0x00;
+    const REP_GENERAL_FAILURE: u8 = 0x01;
+    const REP_HOST_UNREACHABLE: u8 = 0x04;
+
+    let socket2 = match dialer.connect(&target, client).await {
+        Ok(s) => s,
+        Err(e) => {
+            let rep = if dial::is_denied(&e) || dial::is_limited(&e) {
+                REP_NOT_ALLOWED
+            } else if dial::is_timed_out(&e) {
+                REP_HOST_UNREACHABLE
+            } else {
+                REP_GENERAL_FAILURE
+            };
 
-    let socket2 = socket2
-        .connect(dst_addr)
-        .await
-        .context("connect: socket2.connect")?;
+            socket
+                .write_all(&[VERSION, rep, 0x00, ATYP_IP_V4_ADDR, 0, 0, 0, 0, 0, 0])
+                .await
+                .context("connect: write failure reply")?;
+            return Err(e.context(format!("connect: {target}")));
+        }
+    };
 
     // +----+-----+-------+------+----------+----------+
     // |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
@@ -290,8 +461,9 @@ async fn connect(socket: &mut TcpStream) -> anyhow::Result<TcpStream> {
     // | 1  |  1  | X'00' |  1   | Variable |    2     |
     // +----+-----+-------+------+----------+----------+
 
+    let mut buf = [0; 22];
     buf[0] = VERSION;
-    buf[1] = 0x00;
+    buf[1] = REP_SUCCEEDED;
     buf[2] = 0x00;
 
     let local_addr = socket2
@@ -333,3 +505,110 @@ async fn connect(socket: &mut TcpStream) -> anyhow::Result<TcpStream> {
 
     Ok(socket2)
 }
+
+async fn request_v4(socket: &mut TcpStream) -> anyhow::Result<Target> {
+    // https://www.openssh.com/txt/socks4.protocol
+    // https://www.openssh.com/txt/socks4a.protocol
+    const CMD_CONNECT: u8 = 0x01;
+
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+    // | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+    // | 1  | 1  |    2    |         4         | variable     | 1  |
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+
+    let mut buf = [0; 8];
+
+    socket
+        .read_exact(&mut buf)
+        .await
+        .context("request_v4: read basics")?;
+
+    ensure!(buf[0] == VERSION_4, "request_v4: invalid VN: {}", buf[0]);
+    ensure!(buf[1] == CMD_CONNECT, "request_v4: invalid CD: {}", buf[1]);
+
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = [buf[4], buf[5], buf[6], buf[7]];
+
+    read_null_terminated(socket)
+        .await
+        .context("request_v4: read USERID")?;
+
+    // SOCKS4a: DSTIP 0.0.0.x (x != 0) means the domain name follows the USERID
+    let target = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+        let domain_name = read_null_terminated(socket)
+            .await
+            .context("request_v4: read domainname")?;
+
+        let domain_name =
+            std::str::from_utf8(&domain_name).context("request_v4: str::from_utf8(domain_name)")?;
+
+        debug!(
+            "{} - connect to: {}:{}",
+            util::tcp_stream_addrs(socket, false),
+            domain_name,
+            port
+        );
+
+        Target::Domain(domain_name.to_string(), port)
+    } else {
+        let addr = (ip, port).into();
+        debug!(
+            "{} - connect to: {}",
+            util::tcp_stream_addrs(socket, false),
+            addr
+        );
+        Target::Addr(addr)
+    };
+
+    Ok(target)
+}
+
+async fn connect_v4(
+    socket: &mut TcpStream,
+    dialer: &Dialer,
+    client: &mut Client,
+    target: Target,
+) -> anyhow::Result<TcpStream> {
+    let r = dialer.connect(&target, client).await;
+
+    // +----+----+----+----+----+----+----+----+
+    // | VN | CD | DSTPORT |      DSTIP        |
+    // +----+----+----+----+----+----+----+----+
+    // | 1  | 1  |    2    |         4         |
+    // +----+----+----+----+----+----+----+----+
+
+    let cd = if r.is_ok() {
+        REQUEST_GRANTED
+    } else {
+        REQUEST_REJECTED
+    };
+
+    socket
+        .write_all(&[0, cd, 0, 0, 0, 0, 0, 0])
+        .await
+        .context("connect_v4: write reply")?;
+
+    let socket2 = r.with_context(|| format!("connect_v4: {target}"))?;
+
+    debug!(
+        "{} - {}",
+        util::tcp_stream_addrs(socket, false),
+        util::tcp_stream_addrs(&socket2, true)
+    );
+
+    Ok(socket2)
+}
+
+async fn read_null_terminated(socket: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut v = Vec::new();
+    loop {
+        match socket.read_u8().await? {
+            0 => return Ok(v),
+            b => {
+                ensure!(v.len() < 255, "read_null_terminated: too long");
+                v.push(b);
+            }
+        }
+    }
+}
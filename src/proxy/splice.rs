@@ -0,0 +1,109 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use tokio::{io::Interest, net::TcpStream};
+
+// https://man7.org/linux/man-pages/man2/splice.2.html
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// How many bytes to move with each call to splice, which is the default capacity of a pipe.
+const CHUNK: usize = 64 * 1024;
+
+/// Makes [`super::relay::relay`] move bytes between plain TCP connections with splice.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Copies data from `r` to `w`, like [`super::relay::relay`] does with buffers in userspace but through
+/// a pipe in the kernel, adding it to each of `counters` as it goes, until `r` is done, then
+/// shuts down the write half of `w`.
+pub async fn copy(r: &TcpStream, w: &TcpStream, counters: &[&AtomicU64]) -> io::Result<()> {
+    let pipe = Pipe::new()?;
+
+    loop {
+        let n = r
+            .async_io(Interest::READABLE, || {
+                splice(r.as_raw_fd(), pipe.w.as_raw_fd(), CHUNK)
+            })
+            .await?;
+
+        if n == 0 {
+            return shutdown(w);
+        }
+
+        // The pipe must be drained before it's filled again, or the bytes would be reordered
+        let mut left = n;
+        while left > 0 {
+            left -= w
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.r.as_raw_fd(), w.as_raw_fd(), left)
+                })
+                .await?;
+        }
+
+        for c in counters {
+            c.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+struct Pipe {
+    r: OwnedFd,
+    w: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: pipe2 has just opened both, and nothing else owns them
+        unsafe {
+            Ok(Pipe {
+                r: OwnedFd::from_raw_fd(fds[0]),
+                w: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+/// Moves up to `len` bytes from `from` to `to`, one of which must be a pipe, without blocking.
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+fn shutdown(s: &TcpStream) -> io::Result<()> {
+    match unsafe { libc::shutdown(s.as_raw_fd(), libc::SHUT_WR) } {
+        0 => Ok(()),
+        _ => match io::Error::last_os_error() {
+            // The peer may be gone already
+            e if e.raw_os_error() == Some(libc::ENOTCONN) => Ok(()),
+            e => Err(e),
+        },
+    }
+}
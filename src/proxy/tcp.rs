@@ -0,0 +1,258 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use tokio::{
+    io,
+    net::{TcpSocket, TcpStream},
+};
+
+use crate::limit::parse_bytes;
+
+// https://man7.org/linux/man-pages/man7/tcp.7.html
+// https://man7.org/linux/man-pages/man7/socket.7.html
+
+/// How to tune TCP sockets, e.g. `nodelay,keepalive=60/10/5,congestion=bbr`.
+#[derive(Clone, Debug, Default)]
+pub struct TcpOptions {
+    nodelay: bool,
+    keepalive: Option<Keepalive>,
+    send_buffer: Option<u32>,
+    recv_buffer: Option<u32>,
+    /// The length of the queue of pending fast open requests for listening sockets, anything for
+    /// connecting ones.
+    fastopen: Option<u32>,
+    user_timeout: Option<Duration>,
+    congestion: Option<String>,
+    /// Whether listening sockets accept connections to any address, as TPROXY redirects them,
+    /// see IP_TRANSPARENT in https://man7.org/linux/man-pages/man7/ip.7.html
+    transparent: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Keepalive {
+    /// How long a connection is idle before probes are sent, then between them, and how many
+    /// go unanswered before it's dropped. The system defaults apply to those not given.
+    idle: Duration,
+    interval: Option<Duration>,
+    count: Option<u32>,
+}
+
+impl FromStr for TcpOptions {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of `nodelay`, `keepalive=<IDLE>[/<INTERVAL>[/<COUNT>]]`,
+    /// `send-buffer=<SIZE>`, `recv-buffer=<SIZE>`, `fastopen[=<QUEUE>]`,
+    /// `user-timeout=<SECS>` and `congestion=<ALGORITHM>`, with times in seconds.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut o = TcpOptions::default();
+
+        for opt in s.split(',').filter(|o| !o.is_empty()) {
+            let (key, value) = match opt.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (opt, None),
+            };
+            let value = || value.ok_or_else(|| anyhow!("missing value: {opt}"));
+            let secs = |v: &str| {
+                v.parse()
+                    .map(Duration::from_secs)
+                    .with_context(|| format!("invalid seconds: {opt}"))
+            };
+            let size = |v: &str| {
+                u32::try_from(parse_bytes(v)?).with_context(|| format!("invalid size: {opt}"))
+            };
+
+            match key {
+                "nodelay" => o.nodelay = true,
+                "send-buffer" => o.send_buffer = Some(size(value()?)?),
+                "recv-buffer" => o.recv_buffer = Some(size(value()?)?),
+                _ if !cfg!(target_os = "linux") => bail!("unsupported tcp option: {opt}"),
+
+                "keepalive" => {
+                    let mut v = value()?.split('/');
+                    o.keepalive = Some(Keepalive {
+                        idle: secs(v.next().unwrap_or_default())?,
+                        interval: v.next().map(secs).transpose()?,
+                        count: v
+                            .next()
+                            .map(|c| c.parse().with_context(|| format!("invalid count: {opt}")))
+                            .transpose()?,
+                    });
+                }
+                "fastopen" => {
+                    let queue = value().map_or(Ok(256), |v| {
+                        v.parse().with_context(|| format!("invalid queue: {opt}"))
+                    })?;
+                    o.fastopen = Some(queue);
+                }
+                "user-timeout" => o.user_timeout = Some(secs(value()?)?),
+                "congestion" => o.congestion = Some(value()?.to_string()),
+                _ => bail!("unknown tcp option: {opt}"),
+            }
+        }
+
+        Ok(o)
+    }
+}
+
+impl TcpOptions {
+    /// Makes listening sockets transparent, which only the transparent proxy server needs.
+    #[cfg(target_os = "linux")]
+    pub fn with_transparent(mut self) -> Self {
+        self.transparent = true;
+        self
+    }
+
+    /// Tunes a socket about to listen. Accepted connections inherit the buffer sizes and the
+    /// congestion control algorithm from it.
+    pub fn apply_listener(&self, s: &TcpSocket) -> io::Result<()> {
+        self.apply_buffers(s)?;
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(queue) = self.fastopen {
+                sys::setsockopt(
+                    s,
+                    libc::IPPROTO_TCP,
+                    libc::TCP_FASTOPEN,
+                    queue as libc::c_int,
+                )?;
+            }
+            self.apply_congestion(s)?;
+
+            if self.transparent {
+                use std::net::SocketAddr;
+
+                match s.local_addr()? {
+                    SocketAddr::V4(_) => sys::setsockopt(s, libc::SOL_IP, libc::IP_TRANSPARENT, 1)?,
+                    SocketAddr::V6(_) => {
+                        sys::setsockopt(s, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1)?
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tunes an accepted connection.
+    pub fn apply_accepted(&self, s: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            s.set_nodelay(true)?;
+        }
+
+        #[cfg(target_os = "linux")]
+        self.apply_timers(s)?;
+
+        Ok(())
+    }
+
+    /// Tunes a socket about to connect. Call [`TcpOptions::apply_connected`] once it has.
+    pub fn apply_connecting(&self, s: &TcpSocket) -> io::Result<()> {
+        self.apply_buffers(s)?;
+
+        #[cfg(target_os = "linux")]
+        {
+            if self.fastopen.is_some() {
+                sys::setsockopt(s, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)?;
+            }
+            self.apply_congestion(s)?;
+            self.apply_timers(s)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_connected(&self, s: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            s.set_nodelay(true)?;
+        }
+        Ok(())
+    }
+
+    fn apply_buffers(&self, s: &TcpSocket) -> io::Result<()> {
+        if let Some(n) = self.send_buffer {
+            s.set_send_buffer_size(n)?;
+        }
+        if let Some(n) = self.recv_buffer {
+            s.set_recv_buffer_size(n)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_congestion(&self, s: &impl std::os::fd::AsRawFd) -> io::Result<()> {
+        if let Some(c) = &self.congestion {
+            sys::setsockopt_bytes(s, libc::IPPROTO_TCP, libc::TCP_CONGESTION, c.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Sets the keepalive probes and the user timeout.
+    #[cfg(target_os = "linux")]
+    fn apply_timers(&self, s: &impl std::os::fd::AsRawFd) -> io::Result<()> {
+        use libc::{IPPROTO_TCP, SOL_SOCKET};
+
+        if let Some(k) = self.keepalive {
+            sys::setsockopt(s, SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            sys::setsockopt(s, IPPROTO_TCP, libc::TCP_KEEPIDLE, secs(k.idle))?;
+            if let Some(i) = k.interval {
+                sys::setsockopt(s, IPPROTO_TCP, libc::TCP_KEEPINTVL, secs(i))?;
+            }
+            if let Some(c) = k.count {
+                sys::setsockopt(s, IPPROTO_TCP, libc::TCP_KEEPCNT, c as libc::c_int)?;
+            }
+        }
+
+        if let Some(t) = self.user_timeout {
+            let ms = t.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            sys::setsockopt(s, IPPROTO_TCP, libc::TCP_USER_TIMEOUT, ms)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn secs(d: Duration) -> libc::c_int {
+    d.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{io, mem, os::fd::AsRawFd};
+
+    pub fn setsockopt(
+        s: &impl AsRawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let p = &value as *const libc::c_int as *const libc::c_void;
+        set(s, level, name, p, mem::size_of::<libc::c_int>())
+    }
+
+    pub fn setsockopt_bytes(
+        s: &impl AsRawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: &[u8],
+    ) -> io::Result<()> {
+        set(s, level, name, value.as_ptr().cast(), value.len())
+    }
+
+    fn set(
+        s: &impl AsRawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: *const libc::c_void,
+        len: usize,
+    ) -> io::Result<()> {
+        let r =
+            unsafe { libc::setsockopt(s.as_raw_fd(), level, name, value, len as libc::socklen_t) };
+
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, ensure, Context};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{debug, info, instrument};
+
+use super::{
+    dial::{Client, Dialer, Target},
+    relay::{relay, Relayed},
+};
+use crate::{access_log, metrics, registry::Conn, server::Settings, timeout::Stage};
+
+// https://www.rfc-editor.org/rfc/rfc8446#section-4.1.2
+// https://www.rfc-editor.org/rfc/rfc6066#section-3
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// The largest ClientHello accepted, far larger than any sent in practice.
+const MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+/// Forwards a TLS connection, without terminating it, to port 443 of the host named in the
+/// server_name extension of its ClientHello.
+#[instrument(skip(s, conn, dialer, m, settings), fields(
+    peer_addr = %s.peer_addr().unwrap(),
+    local_addr = %s.local_addr().unwrap()
+))]
+pub(crate) async fn handle_socket(
+    mut s: TcpStream,
+    conn: Arc<Conn>,
+    dialer: &Dialer,
+    m: &'static metrics::Listener,
+    settings: Arc<Settings>,
+) -> anyhow::Result<()> {
+    let mut client = Client::new(conn, "tls", m, settings);
+
+    let r = handle(&mut s, dialer, &mut client).await;
+    let status = access_log::status(&client, &r);
+    let bytes = r.as_ref().map_or((0, 0), |r| (r.up, r.down));
+    access_log::write(&client, "CONNECT", status, bytes);
+
+    let r = r?;
+    r.record(&client);
+    info!("{r}");
+
+    Ok(())
+}
+
+async fn handle(
+    s: &mut TcpStream,
+    dialer: &Dialer,
+    client: &mut Client,
+) -> anyhow::Result<Relayed> {
+    let (records, hello) = client
+        .timeouts()
+        .within(Stage::Handshake, client.metrics, read_client_hello(s))
+        .await?;
+    let host = server_name(&hello).context("server_name")?;
+    debug!("connect to: {}:443", host);
+
+    client.handshake_done();
+    let mut server = dialer
+        .connect(&Target::Domain(host, 443), client)
+        .await
+        .context("connect")?;
+
+    // The ClientHello has been consumed, replay its records to the server first
+    server.write_all(&records).await.context("write records")?;
+    client.metrics.bytes_up.add(records.len() as u64);
+    let mut r = relay(client, s, &mut server).await;
+    r.up += records.len() as u64;
+
+    Ok(r)
+}
+
+/// Reads the records carrying the ClientHello, which may be fragmented across several of them,
+/// returning the records as read, headers included, and the ClientHello reassembled from their
+/// fragments, https://www.rfc-editor.org/rfc/rfc8446#section-5.1
+async fn read_client_hello<S>(s: &mut S) -> anyhow::Result<(Vec<u8>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let (mut records, mut hello) = (Vec::new(), Vec::new());
+
+    loop {
+        // +------+--------+---------+
+        // | TYPE | LENGTH | MESSAGE |
+        // +------+--------+---------+
+        // |  1   |   3    | LENGTH  |
+        // +------+--------+---------+
+        let len = match hello[..] {
+            [_, a, b, c, ..] => 4 + u32::from_be_bytes([0, a, b, c]) as usize,
+            _ => 4,
+        };
+        if hello.len() >= len {
+            return Ok((records, hello));
+        }
+        ensure!(
+            len <= MAX_CLIENT_HELLO,
+            "ClientHello too large: {len} bytes"
+        );
+
+        // +------+---------+--------+----------+
+        // | TYPE | VERSION | LENGTH | FRAGMENT |
+        // +------+---------+--------+----------+
+        // |  1   |    2    |   2    | variable |
+        // +------+---------+--------+----------+
+        let start = records.len();
+        records.resize(start + 5, 0);
+        s.read_exact(&mut records[start..])
+            .await
+            .context("read record header")?;
+
+        let header = &records[start..];
+        ensure!(
+            header[0] == CONTENT_TYPE_HANDSHAKE,
+            "invalid content type: {}",
+            header[0]
+        );
+
+        let n = u16::from_be_bytes([header[3], header[4]]) as usize;
+        ensure!(n > 0, "empty handshake record");
+        records.resize(start + 5 + n, 0);
+        s.read_exact(&mut records[start + 5..])
+            .await
+            .context("read record fragment")?;
+        hello.extend_from_slice(&records[start + 5..]);
+    }
+}
+
+fn server_name(mut b: &[u8]) -> anyhow::Result<String> {
+    let msg_type = take(&mut b, 1)?[0];
+    ensure!(
+        msg_type == HANDSHAKE_TYPE_CLIENT_HELLO,
+        "invalid handshake type: {}",
+        msg_type
+    );
+
+    take(&mut b, 3)?; // length
+    take(&mut b, 2)?; // legacy_version
+    take(&mut b, 32)?; // random
+
+    let n = take(&mut b, 1)?[0] as usize;
+    take(&mut b, n)?; // legacy_session_id
+
+    let n = take_u16(&mut b)? as usize;
+    take(&mut b, n)?; // cipher_suites
+
+    let n = take(&mut b, 1)?[0] as usize;
+    take(&mut b, n)?; // legacy_compression_methods
+
+    let n = take_u16(&mut b)? as usize;
+    let mut extensions = take(&mut b, n)?;
+
+    while !extensions.is_empty() {
+        let ty = take_u16(&mut extensions)?;
+        let n = take_u16(&mut extensions)? as usize;
+        let mut data = take(&mut extensions, n)?;
+
+        if ty != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let n = take_u16(&mut data)? as usize;
+        let mut list = take(&mut data, n)?;
+
+        while !list.is_empty() {
+            let name_type = take(&mut list, 1)?[0];
+            let n = take_u16(&mut list)? as usize;
+            let name = take(&mut list, n)?;
+
+            if name_type == NAME_TYPE_HOST_NAME {
+                return Ok(std::str::from_utf8(name)
+                    .context("str::from_utf8(host_name)")?
+                    .to_string());
+            }
+        }
+    }
+
+    Err(anyhow!("missing server_name extension"))
+}
+
+fn take<'a>(b: &mut &'a [u8], n: usize) -> anyhow::Result<&'a [u8]> {
+    ensure!(b.len() >= n, "truncated ClientHello");
+    let (head, tail) = b.split_at(n);
+    *b = tail;
+    Ok(head)
+}
+
+fn take_u16(b: &mut &[u8]) -> anyhow::Result<u16> {
+    let v = take(b, 2)?;
+    Ok(u16::from_be_bytes([v[0], v[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_client_hello, server_name, MAX_CLIENT_HELLO};
+
+    /// Returns a ClientHello handshake message, with a server_name extension for `sni` among
+    /// others, https://www.rfc-editor.org/rfc/rfc8446#section-4.1.2
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // supported_versions, TLS 1.3
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(name) = sni {
+            let n = name.len() as u16;
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&(n + 5).to_be_bytes());
+            extensions.extend_from_slice(&(n + 3).to_be_bytes());
+            extensions.push(0x00);
+            extensions.extend_from_slice(&n.to_be_bytes());
+            extensions.extend_from_slice(name.as_bytes());
+        }
+        // padding
+        extensions.extend_from_slice(&[0x00, 0x15, 0x00, 0x02, 0x00, 0x00]);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0xaa; 32]);
+        body.extend_from_slice(&[0x20]);
+        body.extend_from_slice(&[0xbb; 32]);
+        body.extend_from_slice(&[0x00, 0x04, 0x13, 0x01, 0x13, 0x02]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut b = vec![0x01];
+        b.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        b.extend_from_slice(&body);
+        b
+    }
+
+    /// Returns `hello` split into handshake records of at most `size` bytes each.
+    fn records(hello: &[u8], size: usize) -> Vec<u8> {
+        let mut b = Vec::new();
+        for fragment in hello.chunks(size) {
+            b.extend_from_slice(&[0x16, 0x03, 0x01]);
+            b.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            b.extend_from_slice(fragment);
+        }
+        b
+    }
+
+    #[test]
+    fn sni() {
+        for name in ["example.com", "a", &"x".repeat(253)] {
+            assert_eq!(server_name(&client_hello(Some(name))).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn sni_invalid() {
+        let err = server_name(&client_hello(None)).unwrap_err();
+        assert_eq!(err.to_string(), "missing server_name extension");
+
+        let hello = client_hello(Some("example.com"));
+        for n in 0..hello.len() {
+            assert!(server_name(&hello[..n]).is_err(), "{n}");
+        }
+
+        // A ServerHello
+        let mut b = hello.clone();
+        b[0] = 0x02;
+        assert!(server_name(&b).is_err());
+
+        // A host name that isn't UTF-8, its last byte is right before the padding
+        let mut b = hello.clone();
+        let i = b.len() - 7;
+        b[i] = 0xff;
+        assert!(server_name(&b).is_err());
+    }
+
+    #[tokio::test]
+    async fn read() {
+        let hello = client_hello(Some("example.com"));
+
+        // Fragments split across the handshake header too, and data after the ClientHello
+        for size in [1, 2, 3, 5, 64, hello.len()] {
+            let mut b = records(&hello, size);
+            let n = b.len();
+            b.extend_from_slice(b"\x17\x03\x03\x00\x01\x00");
+
+            let (recs, h) = read_client_hello(&mut &b[..]).await.unwrap();
+            assert_eq!(recs, b[..n], "{size}");
+            assert_eq!(h, hello, "{size}");
+            assert_eq!(server_name(&h).unwrap(), "example.com");
+        }
+    }
+
+    #[tokio::test]
+    async fn read_invalid() {
+        let hello = client_hello(Some("example.com"));
+
+        let b = records(&hello, 64);
+        for n in 0..b.len() {
+            assert!(read_client_hello(&mut &b[..n]).await.is_err(), "{n}");
+        }
+
+        // Application data, and an empty handshake record
+        for b in [&b"\x17\x03\x03\x00\x01\x00"[..], b"\x16\x03\x01\x00\x00"] {
+            assert!(read_client_hello(&mut &b[..]).await.is_err(), "{b:?}");
+        }
+
+        // Refused from its header, before reading the rest
+        let mut header = (MAX_CLIENT_HELLO as u32).to_be_bytes();
+        header[0] = 0x01;
+        let b = records(&header, 4);
+        let err = read_client_hello(&mut &b[..]).await.unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+    }
+}
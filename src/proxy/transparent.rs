@@ -0,0 +1,216 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{ensure, Context};
+use tokio::{net::TcpStream, time};
+use tracing::{error, info, instrument, warn};
+
+use super::{
+    dial::{Client, Dialer, Target},
+    listen::{self, ListenOptions, Listener},
+    relay::{relay, Relayed},
+    tcp::TcpOptions,
+};
+use crate::{access_log, acl::Acl, hooks, limit, metrics, server::Settings, shutdown::Shutdown};
+
+// https://docs.kernel.org/networking/tproxy.html
+// https://man7.org/linux/man-pages/man8/iptables-extensions.8.html
+
+/// Accepts the connections netfilter redirects to `addr`, e.g. those of whole hosts or
+/// containers, and routes them to the destinations they were headed for, which their clients
+/// know nothing of.
+///
+/// The destinations are recovered with SO_ORIGINAL_DST from the connections of REDIRECT rules,
+/// e.g. `iptables -t nat -A PREROUTING -p tcp -j REDIRECT --to-ports 1084`, or are the local
+/// addresses of those of TPROXY rules, which need `tproxy`. The connections of the server
+/// itself mustn't be redirected back to it, e.g. by running it as a user that
+/// `-m owner ! --uid-owner` excludes from the OUTPUT rules.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn start<A>(
+    addr: A,
+    opts: ListenOptions,
+    tcp: TcpOptions,
+    tproxy: bool,
+    acl: Acl,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    shutdown: Shutdown,
+) where
+    A: Into<SocketAddr>,
+{
+    let addr = addr.into();
+    let tcp = if tproxy { tcp.with_transparent() } else { tcp };
+    let acl = Arc::new(acl);
+    listen::run(addr, &opts, tcp, |l| {
+        accept(
+            l,
+            addr,
+            tproxy,
+            acl.clone(),
+            dialer.clone(),
+            settings.clone(),
+            shutdown.clone(),
+        )
+    })
+    .await;
+}
+
+async fn accept(
+    l: Listener,
+    addr: SocketAddr,
+    tproxy: bool,
+    acl: Arc<Acl>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    mut shutdown: Shutdown,
+) {
+    let m = metrics::listener("transparent");
+
+    loop {
+        let r = tokio::select! {
+            r = l.accept() => r,
+            _ = shutdown.recv() => break,
+        };
+
+        match r {
+            Err(e) => {
+                error!("An error occurred while calling listener.accept: {}", e);
+                time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok((s, peer)) => {
+                if !acl.is_allowed(peer.ip()) {
+                    warn!("{peer} - denied by acl");
+                    continue;
+                }
+                let conn = match limit::accept(&settings, m, peer) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("{peer} - {e}");
+                        // The clients speak to their destinations, not to the proxy
+                        continue;
+                    }
+                };
+                if let Err(e) = hooks::accept(&settings, m.name, peer) {
+                    warn!("{peer} - {e}");
+                    continue;
+                }
+
+                m.accepted.inc();
+                let guard = shutdown.track();
+                let dialer = dialer.clone();
+                let settings = settings.clone();
+                tokio::spawn(async move {
+                    let _active = m.active.track();
+                    let mut client = Client::new(conn, "transparent", m, settings);
+                    let r = handle_socket(s, addr, tproxy, &dialer, &mut client).await;
+                    let status = access_log::status(&client, &r);
+
+                    let bytes = match r {
+                        Ok(r) => {
+                            r.record(&client);
+                            info!("{peer} - {r}");
+                            (r.up, r.down)
+                        }
+                        Err(e) => {
+                            m.failed.inc();
+                            warn!("{peer} - error: {e:?}");
+                            (0, 0)
+                        }
+                    };
+                    access_log::write(&client, "CONNECT", status, bytes);
+                    drop(guard);
+                });
+            }
+        }
+    }
+}
+
+#[instrument(skip(s, dialer, client), fields(
+    peer_addr = %s.peer_addr().unwrap(),
+    local_addr = %s.local_addr().unwrap()
+))]
+async fn handle_socket(
+    mut s: TcpStream,
+    addr: SocketAddr,
+    tproxy: bool,
+    dialer: &Dialer,
+    client: &mut Client,
+) -> anyhow::Result<Relayed> {
+    let dst = original_dst(&s, addr, tproxy).context("original_dst")?;
+    client.handshake_done();
+
+    let mut server = dialer
+        .connect(&Target::Addr(dst), client)
+        .await
+        .context("connect")?;
+    Ok(relay(client, &mut s, &mut server).await)
+}
+
+/// Returns where the connection `s`, accepted by the server listening on `addr`, was headed
+/// before netfilter redirected it.
+fn original_dst(s: &TcpStream, addr: SocketAddr, tproxy: bool) -> anyhow::Result<SocketAddr> {
+    // Dual-stack listeners see IPv4 connections from v4-mapped addresses, which conntrack and
+    // the policy know nothing of
+    let local = s.local_addr().context("local_addr")?;
+    let local = SocketAddr::new(local.ip().to_canonical(), local.port());
+
+    // Connecting to those made to the server itself, rather than redirected to it, would loop
+    let dst = if tproxy {
+        ensure!(
+            local.port() != addr.port()
+                || !addr.ip().is_unspecified() && local.ip() != addr.ip().to_canonical(),
+            "not redirected by TPROXY: {local}"
+        );
+        local
+    } else {
+        let dst = sys::original_dst(s, local.is_ipv6()).context("getsockopt")?;
+        ensure!(dst != local, "not redirected by REDIRECT: {dst}");
+        dst
+    };
+
+    Ok(dst)
+}
+
+mod sys {
+    use std::{
+        io, mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+        os::fd::AsRawFd,
+    };
+
+    /// Returns the destination of `s` before NAT, as conntrack remembers it.
+    pub fn original_dst(s: &impl AsRawFd, ipv6: bool) -> io::Result<SocketAddr> {
+        if ipv6 {
+            let a: libc::sockaddr_in6 = get(s, libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)?;
+            Ok(SocketAddr::new(
+                Ipv6Addr::from(a.sin6_addr.s6_addr).into(),
+                u16::from_be(a.sin6_port),
+            ))
+        } else {
+            let a: libc::sockaddr_in = get(s, libc::SOL_IP, libc::SO_ORIGINAL_DST)?;
+            Ok(SocketAddr::new(
+                Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)).into(),
+                u16::from_be(a.sin_port),
+            ))
+        }
+    }
+
+    fn get<T>(s: &impl AsRawFd, level: libc::c_int, name: libc::c_int) -> io::Result<T> {
+        let mut value = mem::MaybeUninit::<T>::zeroed();
+        let mut len = mem::size_of::<T>() as libc::socklen_t;
+        let r = unsafe {
+            libc::getsockopt(
+                s.as_raw_fd(),
+                level,
+                name,
+                value.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The options read are plain C structs, for which zeroes are valid too
+        Ok(unsafe { value.assume_init() })
+    }
+}
@@ -1,50 +1,166 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::Context;
 
-use tokio::io::{self, AsyncReadExt};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    time,
+use tokio::io::AsyncReadExt;
+use tokio::{net::TcpStream, time};
+use tracing::{error, info, instrument, warn};
+
+use super::{
+    dial::{Client, Dialer, Target},
+    listen::{self, ListenOptions, Listener},
+    relay::{relay, Relayed},
+    tcp::TcpOptions,
+};
+use crate::{
+    access_log,
+    acl::Acl,
+    auth::{self, Authenticator, Credentials},
+    hooks, limit, metrics,
+    route::Action,
+    server::Settings,
+    shutdown::Shutdown,
+    timeout::Stage,
 };
-use tracing::{error, instrument};
 
-pub async fn start<A>(addr: A)
-where
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn start<A>(
+    addr: A,
+    opts: ListenOptions,
+    tcp: TcpOptions,
+    acl: Acl,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    shutdown: Shutdown,
+) where
     A: Into<SocketAddr>,
 {
-    let l = TcpListener::bind(addr.into())
-        .await
-        .expect("TcpListener::bind");
+    let acl = Arc::new(acl);
+    listen::run(addr.into(), &opts, tcp, |l| {
+        accept(
+            l,
+            acl.clone(),
+            auth.clone(),
+            dialer.clone(),
+            settings.clone(),
+            shutdown.clone(),
+        )
+    })
+    .await;
+
+    info!("tunnel server stopped accepting new connections");
+}
+
+async fn accept(
+    l: Listener,
+    acl: Arc<Acl>,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    settings: Arc<Settings>,
+    mut shutdown: Shutdown,
+) {
+    let m = metrics::listener("tunnel");
 
     loop {
-        match l.accept().await {
+        let r = tokio::select! {
+            r = l.accept() => r,
+            _ = shutdown.recv() => break,
+        };
+
+        match r {
             Err(e) => {
                 error!("An error occurred while calling listener.accept: {}", e);
                 time::sleep(Duration::from_secs(1)).await;
             }
-            Ok((s, _)) => {
-                tokio::spawn(handle_socket(s));
+            Ok((s, peer)) => {
+                if !acl.is_allowed(peer.ip()) {
+                    warn!("{peer} - denied by acl");
+                    continue;
+                }
+                let conn = match limit::accept(&settings, m, peer) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("{peer} - {e}");
+                        // Tunnel clients expect no reply, only the bytes of their destination
+                        continue;
+                    }
+                };
+                if let Err(e) = hooks::accept(&settings, m.name, peer) {
+                    warn!("{peer} - {e}");
+                    continue;
+                }
+
+                m.accepted.inc();
+                let guard = shutdown.track();
+                let auth = auth.clone();
+                let dialer = dialer.clone();
+                let settings = settings.clone();
+                tokio::spawn(async move {
+                    let _active = m.active.track();
+                    let mut client = Client::new(conn, "tunnel", m, settings);
+                    let r = handle_socket(s, &dialer, auth.as_deref(), &mut client).await;
+                    let status = access_log::status(&client, &r);
+
+                    let bytes = match r {
+                        Ok(r) => {
+                            r.record(&client);
+                            info!("{peer} - {r}");
+                            (r.up, r.down)
+                        }
+                        Err(e) => {
+                            m.failed.inc();
+                            warn!("{peer} - error: {e:?}");
+                            (0, 0)
+                        }
+                    };
+                    access_log::write(&client, "CONNECT", status, bytes);
+                    drop(guard);
+                });
             }
         }
     }
 }
 
-#[instrument(skip(s), fields(
+#[instrument(skip(s, dialer, auth, client), fields(
     peer_addr = %s.peer_addr().unwrap(),
     local_addr = %s.local_addr().unwrap()
 ))]
-async fn handle_socket(mut s: TcpStream) -> anyhow::Result<()> {
-    let len = s.read_u16().await.context("s.read_u16")? as usize;
-    let mut addr = vec![0; len];
+async fn handle_socket(
+    mut s: TcpStream,
+    dialer: &Dialer,
+    auth: Option<&dyn Authenticator>,
+    client: &mut Client,
+) -> anyhow::Result<Relayed> {
+    let timeouts = client.timeouts();
+    let target = timeouts
+        .within(Stage::Handshake, client.metrics, async {
+            let len = s.read_u16().await.context("s.read_u16")? as usize;
+            let mut addr = vec![0; len];
 
-    s.read_exact(&mut addr).await.context("s.read_exact")?;
-    let addr = std::str::from_utf8(&addr).context("from_utf8")?;
+            s.read_exact(&mut addr).await.context("s.read_exact")?;
+            let addr = std::str::from_utf8(&addr).context("from_utf8")?;
 
-    let mut server = TcpStream::connect(addr).await.context("connect")?;
-    io::copy_bidirectional(&mut s, &mut server)
-        .await
-        .context("io::copy_bidirectional")?;
+            // Clients that authenticate send <USER>:<PASSWORD>@<ADDR>, and no host has an @ in it
+            let (credentials, addr) = match addr.rsplit_once('@') {
+                Some((c, a)) => (Some(c.parse::<Credentials>()?), a),
+                None => (None, addr),
+            };
+            match (auth, credentials) {
+                (Some(auth), Some(c)) => auth::check(auth, client, &c).await?,
+                (Some(_), None) => return Err(auth::missing(client)),
+                (None, _) => {}
+            }
+
+            addr.parse::<Target>().context("parse")
+        })
+        .await?;
+    client.handshake_done();
 
-    Ok(())
+    // This is the far end of a route, so don't route the connection again
+    let mut server = dialer
+        .connect_with(&Action::Direct, &target, client)
+        .await
+        .context("connect")?;
+    Ok(relay(client, &mut s, &mut server).await)
 }
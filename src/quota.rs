@@ -0,0 +1,387 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    date::{civil, now},
+    limit::OverLimit,
+    proxy::dial::Client,
+    shutdown::Shutdown,
+};
+
+/// How often the usage is saved and checked for the start of a new period.
+const TICK: Duration = Duration::from_secs(10);
+
+/// When the usage of every user starts over, at midnight UTC.
+#[derive(Clone, Copy, Debug)]
+pub enum Reset {
+    Daily,
+    /// On the first day of every month.
+    Monthly,
+}
+
+impl Reset {
+    /// Names the period `timestamp` falls in, e.g. `2023-06-01` or `2023-06`.
+    fn period(self, timestamp: u64) -> String {
+        let (year, month, day, _) = civil(timestamp);
+        match self {
+            Reset::Daily => format!("{year:04}-{month:02}-{day:02}"),
+            Reset::Monthly => format!("{year:04}-{month:02}"),
+        }
+    }
+}
+
+impl FromStr for Reset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Reset::Daily),
+            "monthly" => Ok(Reset::Monthly),
+            _ => bail!("invalid reset schedule: {s}, expected daily or monthly"),
+        }
+    }
+}
+
+/// The quotas of the users, of which there are none by default.
+#[derive(Clone, Debug)]
+pub struct QuotaOptions {
+    /// In bytes, up and down together, by user, with `*` for the users not listed.
+    pub quotas: Vec<(String, u64)>,
+    pub reset: Reset,
+    /// The file to save the usage to, so that it survives restarts.
+    pub file: Option<PathBuf>,
+    /// The percentages of a quota past which to warn.
+    pub warn: Vec<u8>,
+    /// Whether to close the connections of a user once its quota is exhausted, rather than only
+    /// refusing new ones.
+    pub cut: bool,
+}
+
+impl Default for QuotaOptions {
+    fn default() -> Self {
+        QuotaOptions {
+            quotas: Vec::new(),
+            reset: Reset::Monthly,
+            file: None,
+            warn: vec![80, 90],
+            cut: false,
+        }
+    }
+}
+
+/// The usage of the users and their quotas, see [`QuotaOptions`].
+pub struct Quotas {
+    /// In bytes, up and down together, by user, with `*` for the users not listed.
+    quotas: HashMap<String, u64>,
+    reset: Reset,
+    /// The percentages of a quota past which to warn.
+    warn: Vec<u8>,
+    cut: bool,
+    file: Option<PathBuf>,
+    period: Mutex<String>,
+    usage: Mutex<HashMap<String, Arc<Usage>>>,
+    dirty: AtomicBool,
+}
+
+/// The bytes a user sent and received in the current period.
+struct Usage {
+    user: String,
+    quota: Option<u64>,
+    bytes: AtomicU64,
+    /// The number of warning thresholds already logged.
+    warned: AtomicUsize,
+}
+
+/// Saves the usage of `quotas` every few seconds and starts it over with each new period, until
+/// the shutdown.
+pub async fn run(quotas: Arc<Quotas>, mut shutdown: Shutdown) {
+    let mut interval = time::interval(TICK);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.recv() => break,
+        }
+
+        quotas.roll();
+        quotas.save();
+    }
+}
+
+/// Fails if the user of `client` has exhausted its quota.
+pub fn check(client: &Client) -> Result<(), OverLimit> {
+    match (&client.settings.quotas, &client.user) {
+        (Some(q), Some(user)) => {
+            let u = q.usage(user);
+            if u.exhausted() {
+                return Err(OverLimit(format!("quota exhausted: {user}")));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns whether the bytes of `client` are accounted to its user.
+pub fn applies(client: &Client) -> bool {
+    client.settings.quotas.is_some() && client.user.is_some()
+}
+
+/// Accounts the bytes through `s`, a stream from `client` or to its destination, to the user of
+/// `client`, cutting it off once the quota of the user is exhausted if so configured.
+pub fn metered<S>(s: S, client: &Client) -> Metered<S> {
+    let usage = match (&client.settings.quotas, &client.user) {
+        (Some(q), Some(user)) => Some((q.clone(), q.usage(user))),
+        _ => None,
+    };
+
+    Metered { inner: s, usage }
+}
+
+impl Quotas {
+    /// Sets up the quotas and loads the usage saved in the current period.
+    pub fn new(c: &QuotaOptions) -> anyhow::Result<Self> {
+        let mut warn = c.warn.clone();
+        warn.sort_unstable();
+
+        let quotas = Quotas {
+            quotas: c.quotas.iter().cloned().collect(),
+            reset: c.reset,
+            warn,
+            cut: c.cut,
+            file: c.file.clone(),
+            period: Mutex::new(c.reset.period(now())),
+            usage: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+        };
+
+        if let Some(path) = &quotas.file {
+            quotas.load(path).context("new: load")?;
+        }
+        Ok(quotas)
+    }
+
+    fn usage(&self, user: &str) -> Arc<Usage> {
+        self.usage
+            .lock()
+            .unwrap()
+            .entry(user.to_string())
+            .or_insert_with(|| Arc::new(self.new_usage(user, 0)))
+            .clone()
+    }
+
+    fn new_usage(&self, user: &str, bytes: u64) -> Usage {
+        let quota = self
+            .quotas
+            .get(user)
+            .or_else(|| self.quotas.get("*"))
+            .copied();
+
+        // Don't warn again about thresholds passed before a restart
+        let warned = quota.map_or(0, |q| {
+            self.warn
+                .iter()
+                .filter(|&&p| bytes >= q / 100 * p as u64)
+                .count()
+        });
+
+        Usage {
+            user: user.to_string(),
+            quota,
+            bytes: AtomicU64::new(bytes),
+            warned: AtomicUsize::new(warned),
+        }
+    }
+
+    /// Starts the usage over if a new period has started.
+    fn roll(&self) {
+        let period = self.reset.period(now());
+        let mut current = self.period.lock().unwrap();
+        if *current == period {
+            return;
+        }
+
+        info!("quota period {period} started, resetting usage");
+        *current = period;
+
+        // Reset in place, as the connections still metered hold on to the usage of their user,
+        // and forget the users with none
+        self.usage.lock().unwrap().retain(|_, u| {
+            u.bytes.store(0, Ordering::Relaxed);
+            u.warned.store(0, Ordering::Relaxed);
+            Arc::strong_count(u) > 1
+        });
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Saves the usage, if it has changed since it was last saved.
+    pub fn save(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let mut s = format!("period {}\n", self.period.lock().unwrap());
+        for (user, u) in self.usage.lock().unwrap().iter() {
+            s.push_str(&format!("{user} {}\n", u.bytes.load(Ordering::Relaxed)));
+        }
+
+        // Replace the file at once, so that a crash never leaves half of it
+        let tmp = path.with_extension("tmp");
+        let r = fs::write(&tmp, s).and_then(|_| fs::rename(&tmp, path));
+        if let Err(e) = r {
+            self.dirty.store(true, Ordering::Relaxed);
+            error!("failed to save the quota usage to {}: {e}", path.display());
+        }
+    }
+
+    /// Loads the usage saved by [`Quotas::save`], unless it's from an earlier period.
+    fn load(&self, path: &Path) -> anyhow::Result<()> {
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("load: {}", path.display())),
+        };
+
+        let mut lines = s.lines();
+        let period = lines.next().and_then(|l| l.strip_prefix("period "));
+        if period != Some(self.period.lock().unwrap().as_str()) {
+            info!(
+                "quota usage in {} is from an earlier period",
+                path.display()
+            );
+            return Ok(());
+        }
+
+        let mut usage = self.usage.lock().unwrap();
+        for line in lines {
+            let Some((user, bytes)) = line.rsplit_once(' ') else {
+                continue;
+            };
+            if let Ok(bytes) = bytes.parse() {
+                usage.insert(user.to_string(), Arc::new(self.new_usage(user, bytes)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Usage {
+    fn exhausted(&self) -> bool {
+        self.quota
+            .is_some_and(|q| self.bytes.load(Ordering::Relaxed) >= q)
+    }
+
+    fn add(&self, q: &Quotas, n: usize) {
+        let bytes = self.bytes.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        q.dirty.store(true, Ordering::Relaxed);
+        self.warn(q, bytes);
+    }
+
+    /// Logs the thresholds `bytes` went past for the first time.
+    fn warn(&self, q: &Quotas, bytes: u64) {
+        let Some(quota) = self.quota else {
+            return;
+        };
+
+        let warned = self.warned.load(Ordering::Relaxed);
+        let passed = q
+            .warn
+            .iter()
+            .filter(|&&p| bytes >= quota / 100 * p as u64)
+            .count();
+
+        if passed > warned
+            && self
+                .warned
+                .compare_exchange(warned, passed, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            warn!(
+                "user {} used {}% of its quota: {bytes} of {quota} bytes",
+                self.user,
+                q.warn[passed - 1]
+            );
+        }
+    }
+}
+
+/// A stream whose bytes count towards the quota of a user, see [`metered`].
+pub struct Metered<S> {
+    inner: S,
+    usage: Option<(Arc<Quotas>, Arc<Usage>)>,
+}
+
+impl<S> Metered<S> {
+    fn check(&self) -> io::Result<()> {
+        match &self.usage {
+            Some((q, u)) if q.cut && u.exhausted() => {
+                Err(io::Error::other(format!("quota exhausted: {}", u.user)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn add(&self, n: usize) {
+        if let Some((q, u)) = &self.usage {
+            u.add(q, n);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.check()?;
+
+        let n = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.add(buf.filled().len() - n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check()?;
+
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.add(n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
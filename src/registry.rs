@@ -0,0 +1,282 @@
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::Infallible,
+    hash::Hash,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+};
+
+use crate::policy::Denied;
+
+static CONNS: Mutex<Conns> = Mutex::new(Conns {
+    by_id: BTreeMap::new(),
+    ips: None,
+    users: None,
+});
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The users that may not connect to any destination.
+static DISABLED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// The connections being served by all the proxy servers.
+struct Conns {
+    by_id: BTreeMap<u64, Weak<Conn>>,
+    /// The number of connections from each client address and of each user.
+    ips: Option<HashMap<IpAddr, usize>>,
+    users: Option<HashMap<String, usize>>,
+}
+
+/// A connection being served, registered until the last reference to it is dropped.
+pub struct Conn {
+    pub id: u64,
+    pub listener: &'static str,
+    pub addr: SocketAddr,
+    pub started: Instant,
+    info: Mutex<Info>,
+    /// The bytes received from and sent to the client so far.
+    pub up: AtomicU64,
+    pub down: AtomicU64,
+    close: watch::Sender<bool>,
+}
+
+/// What is learned about a connection as its handshake goes on.
+#[derive(Clone, Default)]
+pub struct Info {
+    pub protocol: &'static str,
+    pub user: Option<String>,
+    pub target: Option<String>,
+}
+
+pub fn register(listener: &'static str, protocol: &'static str, addr: SocketAddr) -> Arc<Conn> {
+    let r = register_if(listener, protocol, addr, |_, _| Ok::<_, Infallible>(()));
+    r.unwrap_or_else(|e| match e {})
+}
+
+/// Registers a connection like [`register`], unless `admit` fails given the numbers of
+/// connections in total and from the address of the client so far. Both are done at once, so
+/// connections accepted together can't all be admitted on the same count.
+pub fn register_if<E>(
+    listener: &'static str,
+    protocol: &'static str,
+    addr: SocketAddr,
+    admit: impl FnOnce(usize, usize) -> Result<(), E>,
+) -> Result<Arc<Conn>, E> {
+    let mut guard = CONNS.lock().unwrap();
+    let conns = &mut *guard;
+    let ips = conns.ips.get_or_insert_with(HashMap::new);
+    admit(
+        conns.by_id.len(),
+        ips.get(&addr.ip()).copied().unwrap_or_default(),
+    )?;
+
+    let conn = Arc::new(Conn {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        listener,
+        addr,
+        started: Instant::now(),
+        info: Mutex::new(Info {
+            protocol,
+            ..Default::default()
+        }),
+        up: AtomicU64::new(0),
+        down: AtomicU64::new(0),
+        close: watch::channel(false).0,
+    });
+
+    *ips.entry(addr.ip()).or_default() += 1;
+    conns.by_id.insert(conn.id, Arc::downgrade(&conn));
+    Ok(conn)
+}
+
+/// Returns the number of connections in total, from the client address `ip` and of `user`.
+pub fn count(ip: IpAddr, user: Option<&str>) -> (usize, usize, usize) {
+    let conns = CONNS.lock().unwrap();
+    let ip = conns.ips.as_ref().and_then(|m| m.get(&ip)).copied();
+    let user = user.and_then(|u| conns.users.as_ref()?.get(u)).copied();
+
+    (
+        conns.by_id.len(),
+        ip.unwrap_or_default(),
+        user.unwrap_or_default(),
+    )
+}
+
+/// Returns the connections being served, oldest first.
+pub fn list() -> Vec<Arc<Conn>> {
+    CONNS
+        .lock()
+        .unwrap()
+        .by_id
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+/// Closes the connection with the given id, returning whether there was one.
+pub fn close(id: u64) -> bool {
+    let conn = CONNS.lock().unwrap().by_id.get(&id).and_then(Weak::upgrade);
+    conn.map(|c| c.close()).is_some()
+}
+
+/// Keeps `user` from connecting to any destination and closes its connections, returning how
+/// many were closed.
+pub fn disable_user(user: &str) -> usize {
+    DISABLED
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(user.to_string());
+
+    list()
+        .into_iter()
+        .filter(|c| c.info().user.as_deref() == Some(user))
+        .map(|c| c.close())
+        .count()
+}
+
+/// Lets `user` connect again, returning whether it was disabled.
+pub fn enable_user(user: &str) -> bool {
+    DISABLED
+        .lock()
+        .unwrap()
+        .as_mut()
+        .is_some_and(|d| d.remove(user))
+}
+
+/// Fails if `user` has been disabled.
+pub fn check_user(user: Option<&str>) -> Result<(), Denied> {
+    let disabled = DISABLED.lock().unwrap();
+    match user {
+        Some(u) if disabled.as_ref().is_some_and(|d| d.contains(u)) => {
+            Err(Denied(format!("user disabled: {u}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Conn {
+    pub fn info(&self) -> Info {
+        self.info.lock().unwrap().clone()
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut Info)) {
+        f(&mut self.info.lock().unwrap());
+    }
+
+    /// Sets the user the client authenticated as.
+    pub fn set_user(&self, user: Option<String>) {
+        let mut conns = CONNS.lock().unwrap();
+        let mut info = self.info.lock().unwrap();
+        let users = conns.users.get_or_insert_with(HashMap::new);
+
+        if let Some(u) = info.user.take() {
+            decrement(users, &u);
+        }
+        if let Some(u) = &user {
+            *users.entry(u.clone()).or_default() += 1;
+        }
+        info.user = user;
+    }
+
+    pub fn close(&self) {
+        self.close.send_replace(true);
+    }
+
+    /// Completes once the connection has been closed through [`close`] or [`Conn::close`].
+    pub async fn closed(&self) {
+        let mut rx = self.close.subscribe();
+        while !*rx.borrow_and_update() {
+            // The sender lives as long as self
+            let _ = rx.changed().await;
+        }
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        let mut conns = CONNS.lock().unwrap();
+        conns.by_id.remove(&self.id);
+
+        if let Some(ips) = &mut conns.ips {
+            decrement(ips, &self.addr.ip());
+        }
+        if let (Some(users), Some(u)) = (&mut conns.users, &self.info.lock().unwrap().user) {
+            decrement(users, u);
+        }
+    }
+}
+
+fn decrement<K, Q>(m: &mut HashMap<K, usize>, k: &Q)
+where
+    K: Borrow<Q> + Hash + Eq,
+    Q: Hash + Eq + ?Sized,
+{
+    if let Some(n) = m.get_mut(k) {
+        *n -= 1;
+        if *n == 0 {
+            m.remove(k);
+        }
+    }
+}
+
+/// A stream from a client that counts the bytes going through it into its connection.
+pub struct Counted<S> {
+    inner: S,
+    conn: Arc<Conn>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, conn: Arc<Conn>) -> Self {
+        Counted { inner, conn }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - n) as u64;
+        self.conn.up.fetch_add(read, Ordering::Relaxed);
+        r
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = r {
+            self.conn.down.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        r
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
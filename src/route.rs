@@ -0,0 +1,425 @@
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use regex::Regex;
+
+use crate::{auth::Credentials, cidr::Cidr, policy::PortRange, ruleset::RuleSet};
+
+/// What to do with a connection.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Connect to the destination directly.
+    Direct,
+    /// Connect to the destination through the tunnel server at the given address.
+    Tunnel(String),
+    /// Connect to the destination through an upstream http proxy server, with CONNECT.
+    Http(Upstream),
+    /// Connect to the destination through an upstream socks5 proxy server.
+    Socks5(Upstream),
+    /// Refuse the connection.
+    Reject,
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "direct" => Ok(Action::Direct),
+            None if s == "reject" => Ok(Action::Reject),
+            Some(("tunnel", addr)) if !addr.is_empty() => Ok(Action::Tunnel(addr.to_string())),
+            Some(("http", u)) if !u.is_empty() => Ok(Action::Http(u.parse()?)),
+            Some(("socks5", u)) if !u.is_empty() => Ok(Action::Socks5(u.parse()?)),
+            _ => bail!(
+                "invalid action: {s}, expected direct, reject, tunnel:<addr>, http:<addr> or socks5:<addr>"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Direct => write!(f, "direct"),
+            Action::Tunnel(addr) => write!(f, "tunnel:{addr}"),
+            Action::Http(u) => write!(f, "http:{u}"),
+            Action::Socks5(u) => write!(f, "socks5:{u}"),
+            Action::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+/// An upstream proxy server, written as `[<user>:<password>@]<addr>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Upstream {
+    pub addr: String,
+    pub credentials: Option<Credentials>,
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Passwords may have an @ in them, addresses don't
+        let (credentials, addr) = match s.rsplit_once('@') {
+            Some((c, addr)) => (Some(c.parse()?), addr),
+            None => (None, s),
+        };
+        if addr.is_empty() {
+            bail!("invalid upstream: {s}, expected [<user>:<password>@]<addr>");
+        }
+
+        Ok(Upstream {
+            addr: addr.to_string(),
+            credentials,
+        })
+    }
+}
+
+/// Leaves out the credentials, as the routes end up in logs.
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)
+    }
+}
+
+/// A routing rule: the action to take for the connections matching all of its conditions.
+///
+/// Rules are written as `<condition>[&<condition>...]=<action>`, e.g.
+/// `domain-suffix:example.com&port:443=tunnel:10.0.0.1:1082`.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    src: String,
+    conditions: Vec<Condition>,
+    action: Action,
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    DomainRegex(Regex),
+    Cidr(Cidr),
+    Port(PortRange),
+    Client(Cidr),
+    User(String),
+    /// Bound to the rule set with the given name when the router is created.
+    RuleSet(String, Option<Arc<RuleSet>>),
+}
+
+/// The connection being routed. The domain is expected in lowercase without a trailing dot.
+pub struct Connection<'a> {
+    pub domain: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+    pub port: u16,
+    pub client: SocketAddr,
+    pub user: Option<&'a str>,
+}
+
+impl Rule {
+    fn new(src: &str, conditions: Vec<Condition>, action: Action) -> Self {
+        Rule {
+            // Without the credentials of upstream proxy servers
+            src: format!("{src}={action}"),
+            conditions,
+            action,
+        }
+    }
+
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
+    /// Whether the rule can only be matched once the destination has been resolved.
+    pub fn needs_ip(&self) -> bool {
+        self.conditions.iter().any(|c| match c {
+            Condition::Cidr(_) => true,
+            Condition::RuleSet(_, set) => set.as_ref().is_some_and(|s| s.has_cidrs()),
+            _ => false,
+        })
+    }
+
+    pub fn matches(&self, c: &Connection) -> bool {
+        self.conditions.iter().all(|cond| cond.matches(c))
+    }
+}
+
+impl Condition {
+    fn matches(&self, c: &Connection) -> bool {
+        let domain = c.domain;
+
+        match self {
+            Condition::Domain(v) => domain.is_some_and(|d| d == v),
+            Condition::DomainSuffix(v) => domain.is_some_and(|d| {
+                d == v || d.strip_suffix(v.as_str()).is_some_and(|s| s.ends_with('.'))
+            }),
+            Condition::DomainKeyword(v) => domain.is_some_and(|d| d.contains(v.as_str())),
+            Condition::DomainRegex(r) => domain.is_some_and(|d| r.is_match(d)),
+            Condition::Cidr(v) => c.ip.is_some_and(|ip| v.contains(ip)),
+            Condition::Port(v) => v.contains(c.port),
+            Condition::Client(v) => v.contains(c.client.ip()),
+            Condition::User(v) => c.user == Some(v.as_str()),
+            Condition::RuleSet(_, set) => set.as_ref().is_some_and(|s| s.matches(domain, c.ip)),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Both sides may have an = in them, e.g. a regex or the password of an upstream proxy,
+        // so split at the first one with valid conditions before it and a valid action after
+        let (mut conditions_err, mut action_err) = (None, None);
+        for (i, _) in s.match_indices('=') {
+            let (src, action) = (&s[..i], &s[i + 1..]);
+            let conditions = src
+                .split('&')
+                .map(|c| c.parse::<Condition>())
+                .collect::<Result<Vec<_>, _>>();
+
+            match (conditions, action.parse::<Action>()) {
+                (Ok(conditions), Ok(action)) => return Ok(Rule::new(src, conditions, action)),
+                (Ok(_), Err(e)) => {
+                    action_err.get_or_insert(e);
+                }
+                (Err(e), _) => {
+                    conditions_err.get_or_insert(e);
+                }
+            }
+        }
+
+        // The first action after valid conditions is likely the one meant
+        match action_err.or(conditions_err) {
+            Some(e) => Err(e.context(format!("invalid rule: {s}"))),
+            None => bail!("invalid rule: {s}, expected <condition>=<action>"),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.src)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, v) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid condition: {s}, expected <kind>:<value>"))?;
+
+        let domain = || v.trim_end_matches('.').to_ascii_lowercase();
+
+        Ok(match kind {
+            "domain" => Condition::Domain(domain()),
+            "domain-suffix" => Condition::DomainSuffix(domain()),
+            "domain-keyword" => Condition::DomainKeyword(domain()),
+            "domain-regex" => Condition::DomainRegex(v.parse()?),
+            "cidr" => Condition::Cidr(v.parse()?),
+            "port" => Condition::Port(v.parse()?),
+            "client" => Condition::Client(v.parse()?),
+            "user" => Condition::User(v.to_string()),
+            "rule-set" => Condition::RuleSet(v.to_string(), None),
+            _ => bail!("invalid condition kind: {kind}"),
+        })
+    }
+}
+
+/// Decides, with the first matching rule, how each connection reaches its destination.
+#[derive(Debug)]
+pub struct Router {
+    rules: Vec<Rule>,
+    default: Action,
+    sets: Vec<Arc<RuleSet>>,
+}
+
+impl Router {
+    /// Returns a router with `rules`, and the `default` action for the connections matching
+    /// none of them, loading the rule sets they refer to by name from `rule_sets`.
+    pub fn new(
+        mut rules: Vec<Rule>,
+        default: Action,
+        rule_sets: &[(String, PathBuf)],
+    ) -> anyhow::Result<Self> {
+        let sets = rule_sets
+            .iter()
+            .map(|(name, path)| RuleSet::load(name.clone(), path.clone()).map(Arc::new))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .context("Router::new")?;
+
+        for rule in &mut rules {
+            for c in &mut rule.conditions {
+                if let Condition::RuleSet(name, set) = c {
+                    let s = sets.iter().find(|s| s.name() == name).ok_or_else(|| {
+                        anyhow!("invalid rule: {}, unknown rule set: {name}", rule.src)
+                    })?;
+                    *set = Some(s.clone());
+                }
+            }
+        }
+
+        Ok(Router {
+            rules,
+            default,
+            sets,
+        })
+    }
+
+    /// Checks the files of the rule sets every `interval` and reloads those that changed.
+    pub fn watch(&self, interval: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let sets = self.sets.clone();
+        async move {
+            let watches = sets.into_iter().map(|s| tokio::spawn(s.watch(interval)));
+            for w in watches.collect::<Vec<_>>() {
+                let _ = w.await;
+            }
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn default_action(&self) -> &Action {
+        &self.default
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{Action, Connection, Rule};
+
+    fn conn<'a>(domain: Option<&'a str>, ip: Option<&str>, port: u16) -> Connection<'a> {
+        Connection {
+            domain,
+            ip: ip.map(|ip| ip.parse().unwrap()),
+            port,
+            client: "192.168.1.10:50000".parse::<SocketAddr>().unwrap(),
+            user: None,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let cases = [
+            ("domain:example.com=direct", "domain:example.com=direct"),
+            (
+                "domain-suffix:example.com&port:443=tunnel:10.0.0.1:1082",
+                "domain-suffix:example.com&port:443=tunnel:10.0.0.1:1082",
+            ),
+            ("cidr:10.0.0.0/8=reject", "cidr:10.0.0.0/8=reject"),
+            // The credentials of upstream proxy servers are left out, = and @ included
+            (
+                "user:alice=socks5:alice:p=w@rd@10.0.0.1:1080",
+                "user:alice=socks5:10.0.0.1:1080",
+            ),
+            ("port:80=http:u:p@proxy:3128", "port:80=http:proxy:3128"),
+            // Regexes may have an = in them too
+            ("domain-regex:^a=b\\.=direct", "domain-regex:^a=b\\.=direct"),
+        ];
+
+        for (s, expected) in cases {
+            let r = s.parse::<Rule>().unwrap();
+            assert_eq!(r.to_string(), expected, "{s}");
+        }
+
+        let r = "user:alice=socks5:alice:p=w@rd@10.0.0.1:1080"
+            .parse::<Rule>()
+            .unwrap();
+        let Action::Socks5(u) = r.action() else {
+            panic!("{:?}", r.action());
+        };
+        let c = u.credentials.as_ref().unwrap();
+        assert_eq!(
+            (c.username.as_str(), c.password.as_str()),
+            ("alice", "p=w@rd")
+        );
+        assert_eq!(u.addr, "10.0.0.1:1080");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let cases = [
+            ("", "expected <condition>=<action>"),
+            ("domain:example.com", "expected <condition>=<action>"),
+            ("domain:example.com=", "invalid action"),
+            ("domain:example.com=forward", "invalid action: forward"),
+            ("domain:example.com=tunnel:", "invalid action"),
+            ("host:example.com=direct", "invalid condition kind: host"),
+            ("example.com=direct", "invalid condition: example.com"),
+            ("cidr:10.0.0.0/33=direct", "invalid rule"),
+            ("port:443-80=direct", "invalid port range"),
+            ("domain-regex:(=direct", "invalid rule"),
+            ("domain:a.com&=direct", "invalid condition"),
+        ];
+
+        for (s, expected) in cases {
+            let err = s.parse::<Rule>().unwrap_err();
+            assert!(format!("{err:#}").contains(expected), "{s}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let rule = |s: &str| s.parse::<Rule>().unwrap();
+
+        let r = rule("domain:Example.COM.=direct");
+        assert!(r.matches(&conn(Some("example.com"), None, 80)));
+        assert!(!r.matches(&conn(Some("www.example.com"), None, 80)));
+        assert!(!r.matches(&conn(None, Some("93.184.216.34"), 80)));
+
+        let r = rule("domain-suffix:example.com=direct");
+        assert!(r.matches(&conn(Some("example.com"), None, 80)));
+        assert!(r.matches(&conn(Some("a.b.example.com"), None, 80)));
+        assert!(!r.matches(&conn(Some("badexample.com"), None, 80)));
+
+        let r = rule("domain-keyword:ads=reject");
+        assert!(r.matches(&conn(Some("ads.example.com"), None, 80)));
+        assert!(r.matches(&conn(Some("myadserver.net"), None, 80)));
+        assert!(!r.matches(&conn(Some("example.com"), None, 80)));
+
+        let r = rule("domain-regex:^(www|api)\\.example\\.com$=direct");
+        assert!(r.matches(&conn(Some("api.example.com"), None, 80)));
+        assert!(!r.matches(&conn(Some("cdn.example.com"), None, 80)));
+
+        let r = rule("cidr:10.0.0.0/8=direct");
+        assert!(r.needs_ip());
+        assert!(r.matches(&conn(None, Some("10.1.2.3"), 80)));
+        assert!(r.matches(&conn(Some("a.com"), Some("::ffff:10.1.2.3"), 80)));
+        assert!(!r.matches(&conn(Some("a.com"), None, 80)));
+
+        let r = rule("client:192.168.1.0/24=direct");
+        assert!(!r.needs_ip());
+        assert!(r.matches(&conn(None, None, 80)));
+
+        let mut c = conn(Some("example.com"), None, 8443);
+        let r = rule("user:alice&domain-suffix:example.com&port:8000-9000=reject");
+        assert!(!r.matches(&c));
+        c.user = Some("alice");
+        assert!(r.matches(&c));
+        c.port = 443;
+        assert!(!r.matches(&c));
+    }
+
+    #[test]
+    fn rule_set_unbound() {
+        // Until a router binds it, a rule set matches nothing
+        let r = "rule-set:ads=reject".parse::<Rule>().unwrap();
+        assert!(!r.needs_ip());
+        assert!(!r.matches(&conn(Some("ads.example.com"), Some("10.0.0.1"), 80)));
+    }
+}
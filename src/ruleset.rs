@@ -0,0 +1,360 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use tokio::time;
+use tracing::{error, info};
+
+use crate::cidr::{Cidr, CidrSet};
+
+/// Host names found in hosts files that must never be matched.
+const HOSTS_IGNORED: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+/// A named list of domains and networks loaded from a file, and reloaded whenever the file
+/// changes.
+///
+/// Each line of the file holds one entry, blank lines and `#` comments are ignored:
+///
+/// - `0.0.0.0 ads.example.com ...`, hosts format, matches exactly the listed domains
+/// - `example.com`, `.example.com`, `*.example.com` or `||example.com^`, matches the domain and
+///   all of its subdomains
+/// - `10.0.0.0/8` or `10.1.2.3`, matches the addresses in the network
+#[derive(Debug)]
+pub struct RuleSet {
+    name: String,
+    path: PathBuf,
+    inner: RwLock<Arc<Lists>>,
+}
+
+#[derive(Default, Debug)]
+struct Lists {
+    modified: Option<SystemTime>,
+    domains: DomainTrie,
+    cidrs: CidrSet,
+}
+
+impl RuleSet {
+    pub fn load(name: String, path: PathBuf) -> anyhow::Result<Self> {
+        let lists = Lists::load(&path)?;
+        info!(
+            "rule set {}: loaded {} domains from {}",
+            name,
+            lists.domains.len,
+            path.display()
+        );
+
+        Ok(RuleSet {
+            name,
+            path,
+            inner: RwLock::new(Arc::new(lists)),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn has_cidrs(&self) -> bool {
+        !self.lists().cidrs.is_empty()
+    }
+
+    /// Matches `domain`, expected in lowercase without a trailing dot, or `ip`.
+    pub fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>) -> bool {
+        let lists = self.lists();
+        domain.is_some_and(|d| lists.domains.matches(d))
+            || ip.is_some_and(|a| lists.cidrs.contains(a))
+    }
+
+    /// Checks the file every `interval` and reloads it when its modification time changes.
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut ticker = time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+            if modified == self.lists().modified {
+                continue;
+            }
+
+            match Lists::load(&self.path) {
+                Ok(lists) => {
+                    info!(
+                        "rule set {}: reloaded {} domains from {}",
+                        self.name,
+                        lists.domains.len,
+                        self.path.display()
+                    );
+                    *self.inner.write().unwrap() = Arc::new(lists);
+                }
+                Err(e) => error!("rule set {}: reload: {:?}", self.name, e),
+            }
+        }
+    }
+
+    fn lists(&self) -> Arc<Lists> {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+impl Lists {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let s = fs::read_to_string(path)
+            .with_context(|| format!("fs::read_to_string: {}", path.display()))?;
+
+        let mut lists = Lists {
+            modified,
+            ..Default::default()
+        };
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            lists
+                .add(line)
+                .map_err(|e| anyhow!("{}:{}: {e}", path.display(), i + 1))?;
+        }
+
+        Ok(lists)
+    }
+
+    fn add(&mut self, line: &str) -> anyhow::Result<()> {
+        let mut fields = line.split_whitespace();
+        let first = fields.next().unwrap_or_default();
+
+        let mut fields = fields.peekable();
+        if fields.peek().is_some() && first.parse::<IpAddr>().is_ok() {
+            for h in fields.filter(|h| !HOSTS_IGNORED.contains(h)) {
+                self.domains.insert(h, false);
+            }
+            return Ok(());
+        }
+
+        if let Ok(cidr) = Cidr::from_str(first) {
+            self.cidrs.insert(cidr);
+            return Ok(());
+        }
+
+        let domain = first
+            .trim_start_matches("||")
+            .trim_end_matches('^')
+            .trim_start_matches('*')
+            .trim_start_matches('.');
+
+        if domain.is_empty() || domain.contains(['/', '*', ':']) {
+            return Err(anyhow!("invalid entry: {line}"));
+        }
+
+        self.domains.insert(domain, true);
+        Ok(())
+    }
+}
+
+/// Domains stored label by label from the top-level domain down, so matching a domain against
+/// any number of entries only walks its own labels.
+#[derive(Default, Debug)]
+struct DomainTrie {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Default, Debug)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    exact: bool,
+    suffix: bool,
+}
+
+impl DomainTrie {
+    /// Adds `domain`, matching its subdomains too if `suffix` is true.
+    fn insert(&mut self, domain: &str, suffix: bool) {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+
+        if !node.exact && !node.suffix {
+            self.len += 1;
+        }
+
+        if suffix {
+            node.suffix = true;
+        } else {
+            node.exact = true;
+        }
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        let mut node = &self.root;
+        let mut labels = domain.rsplit('.').peekable();
+
+        while let Some(label) = labels.next() {
+            node = match node.children.get(label) {
+                Some(n) => n,
+                None => return false,
+            };
+
+            if node.suffix || labels.peek().is_none() && node.exact {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::{Lists, RuleSet};
+
+    fn lists(lines: &[&str]) -> Lists {
+        let mut lists = Lists::default();
+        for line in lines {
+            lists.add(line).unwrap();
+        }
+        lists
+    }
+
+    #[test]
+    fn domains() {
+        let l = lists(&[
+            "example.com",
+            ".dot.example.org",
+            "*.glob.example.net",
+            "||adblock.example^",
+            "Upper.Example.",
+        ]);
+
+        for d in [
+            "example.com",
+            "www.example.com",
+            "a.b.example.com",
+            "dot.example.org",
+            "x.dot.example.org",
+            "glob.example.net",
+            "x.glob.example.net",
+            "adblock.example",
+            "ads.adblock.example",
+            "upper.example",
+        ] {
+            assert!(l.domains.matches(d), "{d}");
+        }
+        for d in [
+            "com",
+            "badexample.com",
+            "example.org",
+            "example.net",
+            "example",
+            "example.com.evil",
+        ] {
+            assert!(!l.domains.matches(d), "{d}");
+        }
+        assert_eq!(l.domains.len, 5);
+    }
+
+    #[test]
+    fn hosts() {
+        let l = lists(&[
+            "0.0.0.0 ads.example.com tracker.example.com",
+            "127.0.0.1 localhost localhost.localdomain",
+            "::1 ip6-localhost ip6-loopback",
+            "0.0.0.0 0.0.0.0",
+            // Exact matches may become suffix matches, never the other way around
+            "0.0.0.0 example.org",
+            "example.org",
+            "example.net",
+            "0.0.0.0 example.net",
+        ]);
+
+        assert!(l.domains.matches("ads.example.com"));
+        assert!(l.domains.matches("tracker.example.com"));
+        assert!(!l.domains.matches("x.ads.example.com"));
+        assert!(!l.domains.matches("example.com"));
+        assert!(!l.domains.matches("localhost"));
+        assert!(!l.domains.matches("ip6-loopback"));
+        assert!(l.domains.matches("www.example.org"));
+        assert!(l.domains.matches("www.example.net"));
+        assert!(l.cidrs.is_empty());
+        assert_eq!(l.domains.len, 4);
+    }
+
+    #[test]
+    fn cidrs() {
+        let l = lists(&["10.0.0.0/8", "192.0.2.7", "fd00::/8"]);
+
+        assert_eq!(l.domains.len, 0);
+        assert!(l.cidrs.contains("10.1.2.3".parse().unwrap()));
+        assert!(l.cidrs.contains("192.0.2.7".parse().unwrap()));
+        assert!(!l.cidrs.contains("192.0.2.8".parse().unwrap()));
+        assert!(l.cidrs.contains("fd00::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid() {
+        for line in [
+            "*",
+            "||^",
+            "example.com/path",
+            "*.ex*ample.com",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "http://example.com",
+        ] {
+            assert!(Lists::default().add(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn load() {
+        let path = env::temp_dir().join(format!("bubble-ruleset-{}", process::id()));
+        fs::write(
+            &path,
+            "# ads\n0.0.0.0 ads.example.com # hosts format\n\nexample.org\n  10.0.0.0/8  \n",
+        )
+        .unwrap();
+
+        let set = RuleSet::load("ads".to_string(), path.clone());
+        fs::write(&path, "example.org\nexample.com/\n").unwrap();
+        let err = RuleSet::load("bad".to_string(), path.clone()).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        let set = set.unwrap();
+        assert_eq!(set.name(), "ads");
+        assert!(set.has_cidrs());
+        assert!(set.matches(Some("ads.example.com"), None));
+        assert!(set.matches(Some("www.example.org"), None));
+        assert!(set.matches(None, Some("10.1.2.3".parse().unwrap())));
+        assert!(set.matches(Some("example.net"), Some("10.1.2.3".parse().unwrap())));
+        assert!(!set.matches(Some("example.net"), Some("11.0.0.1".parse().unwrap())));
+        assert!(!set.matches(None, None));
+        // Errors point at the offending line
+        assert!(
+            err.to_string().ends_with(":2: invalid entry: example.com/"),
+            "{err}"
+        );
+    }
+}
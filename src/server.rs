@@ -0,0 +1,282 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    access_log::AccessLog,
+    acl::Acl,
+    auth::Authenticator,
+    hooks::Hooks,
+    limit::Limits,
+    proxy::{self, dial::Dialer, tcp::TcpOptions, ListenOptions},
+    quota::Quotas,
+    shutdown::Shutdown,
+    timeout::Timeouts,
+};
+
+/// A proxy server to embed in other programs, configured with its setters then run with
+/// `serve`, e.g.
+///
+/// ```no_run
+/// # async fn run(dialer: std::sync::Arc<bubble::Dialer>) {
+/// let shutdown = bubble::Shutdown::new();
+/// let server = bubble::Socks5Server::new(([127, 0, 0, 1], 1080), dialer)
+///     .shutdown(shutdown.clone())
+///     .serve();
+/// tokio::spawn(server);
+/// # }
+/// ```
+///
+/// The limits, quotas, access log and hooks may be shared by several servers by setting the same
+/// ones on each.
+pub struct Server<P> {
+    addr: SocketAddr,
+    listen: ListenOptions,
+    tcp: TcpOptions,
+    acl: Acl,
+    auth: Option<Arc<dyn Authenticator>>,
+    dialer: Arc<Dialer>,
+    shutdown: Shutdown,
+    settings: Settings,
+    protocol: P,
+}
+
+/// What a server shares with the connections it serves.
+#[derive(Default)]
+pub(crate) struct Settings {
+    pub hooks: Option<Arc<dyn Hooks>>,
+    pub limits: Option<Arc<Limits>>,
+    pub quotas: Option<Arc<Quotas>>,
+    pub timeouts: Timeouts,
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+/// A socks5 proxy server, which serves socks4 clients too.
+pub type Socks5Server = Server<Socks5>;
+/// An http proxy server, for both CONNECT and plain http requests.
+pub type HttpProxyServer = Server<Http>;
+/// A tunnel server, the far end of the `tunnel:<ADDR>` routes of other servers.
+pub type TunnelServer = Server<Tunnel>;
+/// A server for socks4, socks5, http and tls clients on a single port.
+pub type MixedServer = Server<Mixed>;
+/// A transparent proxy server, for the connections netfilter redirects to it.
+#[cfg(target_os = "linux")]
+pub type TransparentServer = Server<Transparent>;
+
+pub struct Socks5;
+
+pub struct Http {
+    tunnel_addr: Option<String>,
+}
+
+pub struct Tunnel;
+
+pub struct Mixed {
+    tunnel_addr: Option<String>,
+}
+
+#[cfg(target_os = "linux")]
+pub struct Transparent {
+    tproxy: bool,
+}
+
+impl<P> Server<P> {
+    fn with(addr: impl Into<SocketAddr>, dialer: Arc<Dialer>, protocol: P) -> Self {
+        Server {
+            addr: addr.into(),
+            listen: ListenOptions::default(),
+            tcp: TcpOptions::default(),
+            acl: Acl::default(),
+            auth: None,
+            dialer,
+            shutdown: Shutdown::new(),
+            settings: Settings::default(),
+            protocol,
+        }
+    }
+
+    /// Sets the number of accept loops and the backlog of their sockets.
+    pub fn listen(mut self, listen: ListenOptions) -> Self {
+        self.listen = listen;
+        self
+    }
+
+    /// Sets how to tune the connections accepted.
+    pub fn tcp(mut self, tcp: TcpOptions) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    /// Sets which clients may connect, all by default.
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Requires clients to authenticate with `auth`, which none have to by default.
+    pub fn auth(mut self, auth: Arc<dyn Authenticator>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Sets what stops the server from accepting new connections and keeps track of those
+    /// still active, a shutdown of its own by default.
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Calls `hooks` along the way of every connection, which no hooks are by default.
+    pub fn hooks(mut self, hooks: Arc<dyn Hooks>) -> Self {
+        self.settings.hooks = Some(hooks);
+        self
+    }
+
+    /// Enforces the bandwidth and connection limits of `limits`, none by default.
+    pub fn limits(mut self, limits: Arc<Limits>) -> Self {
+        self.settings.limits = Some(limits);
+        self
+    }
+
+    /// Accounts the bytes of the authenticated users to `quotas` and enforces them, none by default.
+    pub fn quotas(mut self, quotas: Arc<Quotas>) -> Self {
+        self.settings.quotas = Some(quotas);
+        self
+    }
+
+    /// Sets the timeouts of the stages of the connections, [`Timeouts::default`] by default.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.settings.timeouts = timeouts;
+        self
+    }
+
+    /// Logs every connection and http request to `log`, which none are by default.
+    pub fn access_log(mut self, log: Arc<AccessLog>) -> Self {
+        self.settings.access_log = Some(log);
+        self
+    }
+}
+
+impl Server<Socks5> {
+    pub fn new(addr: impl Into<SocketAddr>, dialer: Arc<Dialer>) -> Self {
+        Server::with(addr, dialer, Socks5)
+    }
+
+    /// Accepts connections until shut down.
+    pub async fn serve(self) {
+        proxy::socks5::start(
+            self.addr,
+            self.listen,
+            self.tcp,
+            self.acl,
+            self.auth,
+            self.dialer,
+            Arc::new(self.settings),
+            self.shutdown,
+        )
+        .await
+    }
+}
+
+impl Server<Http> {
+    pub fn new(addr: impl Into<SocketAddr>, dialer: Arc<Dialer>) -> Self {
+        Server::with(addr, dialer, Http { tunnel_addr: None })
+    }
+
+    /// Forwards all requests to the tunnel server at `addr` rather than routing them.
+    pub fn tunnel_addr(mut self, addr: impl Into<String>) -> Self {
+        self.protocol.tunnel_addr = Some(addr.into());
+        self
+    }
+
+    /// Accepts connections until shut down.
+    pub async fn serve(self) {
+        proxy::http::start(
+            self.addr,
+            self.listen,
+            self.tcp,
+            self.protocol.tunnel_addr,
+            self.acl,
+            self.auth,
+            self.dialer,
+            Arc::new(self.settings),
+            self.shutdown,
+        )
+        .await
+    }
+}
+
+impl Server<Tunnel> {
+    pub fn new(addr: impl Into<SocketAddr>, dialer: Arc<Dialer>) -> Self {
+        Server::with(addr, dialer, Tunnel)
+    }
+
+    /// Accepts connections until shut down.
+    pub async fn serve(self) {
+        proxy::tunnel::start(
+            self.addr,
+            self.listen,
+            self.tcp,
+            self.acl,
+            self.auth,
+            self.dialer,
+            Arc::new(self.settings),
+            self.shutdown,
+        )
+        .await
+    }
+}
+
+impl Server<Mixed> {
+    pub fn new(addr: impl Into<SocketAddr>, dialer: Arc<Dialer>) -> Self {
+        Server::with(addr, dialer, Mixed { tunnel_addr: None })
+    }
+
+    /// Forwards the http requests to the tunnel server at `addr` rather than routing them.
+    pub fn tunnel_addr(mut self, addr: impl Into<String>) -> Self {
+        self.protocol.tunnel_addr = Some(addr.into());
+        self
+    }
+
+    /// Accepts connections until shut down.
+    pub async fn serve(self) {
+        proxy::mixed::start(
+            self.addr,
+            self.listen,
+            self.tcp,
+            self.protocol.tunnel_addr,
+            self.acl,
+            self.auth,
+            self.dialer,
+            Arc::new(self.settings),
+            self.shutdown,
+        )
+        .await
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Server<Transparent> {
+    pub fn new(addr: impl Into<SocketAddr>, dialer: Arc<Dialer>) -> Self {
+        Server::with(addr, dialer, Transparent { tproxy: false })
+    }
+
+    /// Accepts the connections of TPROXY rules rather than REDIRECT ones.
+    pub fn tproxy(mut self, tproxy: bool) -> Self {
+        self.protocol.tproxy = tproxy;
+        self
+    }
+
+    /// Accepts connections until shut down. Clients don't authenticate to this server.
+    pub async fn serve(self) {
+        proxy::transparent::start(
+            self.addr,
+            self.listen,
+            self.tcp,
+            self.protocol.tproxy,
+            self.acl,
+            self.dialer,
+            Arc::new(self.settings),
+            self.shutdown,
+        )
+        .await
+    }
+}
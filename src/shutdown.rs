@@ -0,0 +1,120 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    signal,
+    sync::{watch, Notify},
+    time::{self, Instant},
+};
+
+/// Coordinates the graceful shutdown of the listeners and the connections they accepted.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+    rx: watch::Receiver<bool>,
+}
+
+struct Inner {
+    tx: watch::Sender<bool>,
+    active: AtomicUsize,
+    idle: Notify,
+}
+
+/// Keeps a connection accounted as active until it is dropped.
+pub struct Guard {
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Shutdown {
+            inner: Arc::new(Inner {
+                tx,
+                active: AtomicUsize::new(0),
+                idle: Notify::new(),
+            }),
+            rx,
+        }
+    }
+
+    /// Tells every listener to stop accepting new connections.
+    pub fn trigger(&self) {
+        self.inner.tx.send_replace(true);
+    }
+
+    /// Completes once the shutdown has been triggered.
+    pub async fn recv(&mut self) {
+        while !*self.rx.borrow_and_update() {
+            if self.rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn track(&self) -> Guard {
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        Guard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    /// Waits up to `timeout` for the active connections to finish and returns the number of
+    /// connections that are still active afterwards.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let idle = self.inner.idle.notified();
+
+            let n = self.active();
+            if n == 0 {
+                return 0;
+            }
+
+            if time::timeout_at(deadline, idle).await.is_err() {
+                return self.active();
+            }
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.inner.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/// Completes when the process receives either SIGINT or SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("signal::unix::signal");
+
+        tokio::select! {
+            r = signal::ctrl_c() => r.expect("signal::ctrl_c"),
+            _ = term.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.expect("signal::ctrl_c");
+}
@@ -0,0 +1,160 @@
+use std::{
+    error::Error,
+    fmt,
+    future::{pending, Future},
+    io,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use tokio::time;
+
+use crate::{
+    metrics::{self, Counter},
+    registry::Conn,
+};
+
+/// The stages of a connection that may time out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// From accepting a connection to learning its destination.
+    Handshake,
+    /// Resolving the destination.
+    Dns,
+    /// Connecting to the destination.
+    Connect,
+    /// Relaying, with no bytes going either way.
+    Idle,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Handshake, Stage::Dns, Stage::Connect, Stage::Idle];
+
+    /// Returns the counter of the timeouts of this stage among the metrics of a listener.
+    pub fn counter(self, m: &metrics::Listener) -> &Counter {
+        match self {
+            Stage::Handshake => &m.handshake_timeouts,
+            Stage::Dns => &m.dns_timeouts,
+            Stage::Connect => &m.connect_timeouts,
+            Stage::Idle => &m.idle_timeouts,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Handshake => "handshake",
+            Stage::Dns => "dns",
+            Stage::Connect => "connect",
+            Stage::Idle => "idle",
+        })
+    }
+}
+
+/// The error of a stage that didn't complete in time.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub stage: Stage,
+    pub after: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timed out after {:?}", self.stage, self.after)
+    }
+}
+
+impl Error for TimedOut {}
+
+impl From<TimedOut> for io::Error {
+    fn from(e: TimedOut) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
+
+/// The timeouts of the stages of the connections, `None` for none.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    pub handshake: Option<Duration>,
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            handshake: Some(Duration::from_secs(10)),
+            dns: Some(Duration::from_secs(5)),
+            connect: Some(Duration::from_secs(10)),
+            idle: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Returns the timeout of `stage`, if there's one.
+    pub fn get(&self, stage: Stage) -> Option<Duration> {
+        match stage {
+            Stage::Handshake => self.handshake,
+            Stage::Dns => self.dns,
+            Stage::Connect => self.connect,
+            Stage::Idle => self.idle,
+        }
+    }
+
+    /// Completes once the timeout of `stage` has elapsed, or never if there's none.
+    pub async fn elapsed(self, stage: Stage) -> TimedOut {
+        match self.get(stage) {
+            Some(after) => {
+                time::sleep(after).await;
+                TimedOut { stage, after }
+            }
+            None => pending().await,
+        }
+    }
+
+    /// Runs `f` for at most the timeout of `stage`, failing with a [`TimedOut`] error counted
+    /// in the metrics `m` once it's elapsed.
+    pub async fn within<F, T>(self, stage: Stage, m: &metrics::Listener, f: F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        tokio::select! {
+            r = f => r,
+            e = self.elapsed(stage) => {
+                stage.counter(m).inc();
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Completes once no bytes have gone either way through `conn` for the idle timeout, or
+    /// never if there's none, counting the timeout in the metrics `m`.
+    pub(crate) async fn idle(self, conn: &Conn, m: &metrics::Listener) -> TimedOut {
+        let Some(after) = self.idle else {
+            return pending().await;
+        };
+
+        let bytes = || conn.up.load(Ordering::Relaxed) + conn.down.load(Ordering::Relaxed);
+        let (mut last, mut since) = (bytes(), Instant::now());
+
+        // Check a few times per timeout rather than on every read and write
+        let mut interval = time::interval(after / 4);
+        loop {
+            interval.tick().await;
+
+            let n = bytes();
+            if n != last {
+                (last, since) = (n, Instant::now());
+            } else if since.elapsed() >= after {
+                Stage::Idle.counter(m).inc();
+                return TimedOut {
+                    stage: Stage::Idle,
+                    after,
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Runs proxy servers with connection limits in-process through the library, with each test
+//! connecting from an address of its own, as the connections are counted across the process.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpListener as StdListener},
+    sync::Arc,
+    time::Duration,
+};
+
+use bubble::{
+    dns::{Resolver, ResolverOptions},
+    limit::{LimitOptions, Limits},
+    policy::{Policy, PolicyOptions},
+    proxy::tcp::TcpOptions,
+    route::{Action, Router},
+    Dialer, MixedServer, Shutdown, Socks5Server,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    time,
+};
+
+const MAX_PER_CLIENT: usize = 2;
+
+#[tokio::test]
+async fn socks5() {
+    let origin = origin().await;
+    let shutdown = Shutdown::new();
+    let proxy = start(|addr, dialer, limits| {
+        tokio::spawn(
+            Socks5Server::new(addr, dialer)
+                .limits(limits)
+                .shutdown(shutdown.clone())
+                .serve(),
+        );
+    })
+    .await;
+
+    let SocketAddr::V4(o) = origin else {
+        unreachable!()
+    };
+    let mut req = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01];
+    req.extend_from_slice(&o.ip().octets());
+    req.extend_from_slice(&o.port().to_be_bytes());
+
+    // The connections are all opened before any of them is served
+    let replies = burst(Ipv4Addr::new(127, 0, 0, 2), proxy, &req, 12).await;
+    let mut granted = 0;
+    for r in &replies {
+        match r[..] {
+            [0x05, 0x00, 0x05, 0x00, ..] => granted += 1,
+            // Connection not allowed, past the method negotiation
+            [0x05, 0x00, 0x05, 0x02, ..] => {}
+            _ => panic!("{r:?}"),
+        }
+    }
+    assert_eq!(granted, MAX_PER_CLIENT);
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn socks4() {
+    let origin = origin().await;
+    let shutdown = Shutdown::new();
+    let proxy = start(|addr, dialer, limits| {
+        tokio::spawn(
+            Socks5Server::new(addr, dialer)
+                .limits(limits)
+                .shutdown(shutdown.clone())
+                .serve(),
+        );
+    })
+    .await;
+
+    let SocketAddr::V4(o) = origin else {
+        unreachable!()
+    };
+    let mut req = vec![0x04, 0x01];
+    req.extend_from_slice(&o.port().to_be_bytes());
+    req.extend_from_slice(&o.ip().octets());
+    req.push(0x00);
+
+    let replies = burst(Ipv4Addr::new(127, 0, 0, 3), proxy, &req, 8).await;
+    let mut granted = 0;
+    for r in &replies {
+        match r[..] {
+            [0x00, 90, ..] => granted += 1,
+            [0x00, 91, ..] => {}
+            _ => panic!("{r:?}"),
+        }
+    }
+    assert_eq!(granted, MAX_PER_CLIENT);
+
+    shutdown.trigger();
+}
+
+#[tokio::test]
+async fn mixed_http() {
+    let origin = origin().await;
+    let shutdown = Shutdown::new();
+    let proxy = start(|addr, dialer, limits| {
+        tokio::spawn(
+            MixedServer::new(addr, dialer)
+                .limits(limits)
+                .shutdown(shutdown.clone())
+                .serve(),
+        );
+    })
+    .await;
+
+    let req = format!("GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\n\r\n");
+    let replies = burst(Ipv4Addr::new(127, 0, 0, 4), proxy, req.as_bytes(), 12).await;
+    let mut granted = 0;
+    for r in &replies {
+        if r.starts_with(b"HTTP/1.1 200") {
+            granted += 1;
+        } else {
+            assert!(
+                r.starts_with(b"HTTP/1.1 429"),
+                "{}",
+                String::from_utf8_lossy(r)
+            );
+        }
+    }
+    assert_eq!(granted, MAX_PER_CLIENT);
+
+    shutdown.trigger();
+}
+
+/// Starts a server that answers every connection with a short http response once it has sent
+/// something.
+async fn origin() -> SocketAddr {
+    let l = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = l.local_addr().expect("local_addr");
+
+    tokio::spawn(async move {
+        while let Ok((mut s, _)) = l.accept().await {
+            tokio::spawn(async move {
+                let mut b = [0; 1024];
+                if let Ok(n) = s.read(&mut b).await {
+                    if n > 0 {
+                        let resp = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                        let _ = s.write_all(resp.as_bytes()).await;
+                    }
+                }
+                // Keep the connection open for the proxy to keep counting it
+                let _ = s.read(&mut b).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Starts a proxy server with `serve`, allowing [`MAX_PER_CLIENT`] connections from each
+/// client, returning its address once it accepts connections.
+async fn start<F>(serve: F) -> SocketAddr
+where
+    F: FnOnce(SocketAddr, Arc<Dialer>, Arc<Limits>),
+{
+    let addr = StdListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("bind");
+
+    let dialer = Dialer::new(
+        Policy::new(&PolicyOptions {
+            allow_private_dst: true,
+            ..Default::default()
+        }),
+        Router::new(Vec::new(), Action::Direct, &[]).expect("router"),
+        Resolver::new(&ResolverOptions::default()).expect("resolver"),
+        TcpOptions::default(),
+        None,
+    );
+    let limits = Limits::new(&LimitOptions {
+        max_connections_per_client: MAX_PER_CLIENT,
+        ..Default::default()
+    });
+    serve(addr, Arc::new(dialer), Arc::new(limits));
+
+    // From 127.0.0.1, which none of the tests connect from
+    for _ in 0..50 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return addr;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("proxy not listening");
+}
+
+/// Opens [`MAX_PER_CLIENT`] + 1 connections from `ip` to `proxy` back to back, then sends
+/// `req` on each, returning the first `n` bytes, at most, each of them got back.
+async fn burst(ip: Ipv4Addr, proxy: SocketAddr, req: &[u8], n: usize) -> Vec<Vec<u8>> {
+    let mut conns = Vec::new();
+    for _ in 0..=MAX_PER_CLIENT {
+        let socket = TcpSocket::new_v4().expect("socket");
+        socket.bind((ip, 0).into()).expect("bind");
+        conns.push(socket.connect(proxy).await.expect("connect"));
+    }
+
+    let mut replies = Vec::new();
+    for mut s in conns {
+        s.write_all(req).await.expect("write");
+
+        let mut b = vec![0; n];
+        let mut read = 0;
+        while read < n {
+            match time::timeout(Duration::from_secs(5), s.read(&mut b[read..])).await {
+                Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+                Ok(Ok(r)) => read += r,
+            }
+        }
+        b.truncate(read);
+        replies.push(b);
+    }
+    replies
+}
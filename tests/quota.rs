@@ -0,0 +1,154 @@
+//! Runs an http proxy server with the quotas of its users cut off once exhausted, in-process
+//! through the library.
+
+use std::{
+    net::{SocketAddr, TcpListener as StdListener},
+    sync::Arc,
+    time::Duration,
+};
+
+use bubble::{
+    auth::{Credentials, StaticUsers},
+    dns::{Resolver, ResolverOptions},
+    policy::{Policy, PolicyOptions},
+    proxy::tcp::TcpOptions,
+    quota::{QuotaOptions, Quotas},
+    route::{Action, Router},
+    Dialer, HttpProxyServer, Shutdown,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+/// The body of every response of the origin server, sent in chunks of 1000 bytes.
+const BODY: usize = 10_000;
+
+#[tokio::test]
+async fn plain_http() {
+    let origin = origin().await;
+    let quotas = Quotas::new(&QuotaOptions {
+        quotas: vec![("alice".to_string(), 15_000)],
+        cut: true,
+        ..Default::default()
+    })
+    .expect("quotas");
+    let shutdown = Shutdown::new();
+    let proxy = proxy(Arc::new(quotas), shutdown.clone()).await;
+
+    // The requests and responses count towards the quota, not only those of CONNECT tunnels
+    let r = fetch(proxy, origin).await;
+    assert!(
+        r.starts_with(b"HTTP/1.1 200"),
+        "{}",
+        String::from_utf8_lossy(&r)
+    );
+    assert_eq!(body(&r), BODY);
+
+    // Cut off halfway through the response once the quota is exhausted
+    let r = fetch(proxy, origin).await;
+    assert!(
+        r.starts_with(b"HTTP/1.1 200"),
+        "{}",
+        String::from_utf8_lossy(&r)
+    );
+    assert!(body(&r) < BODY, "{}", body(&r));
+
+    let r = fetch(proxy, origin).await;
+    assert!(
+        r.starts_with(b"HTTP/1.1 429"),
+        "{}",
+        String::from_utf8_lossy(&r)
+    );
+
+    shutdown.trigger();
+}
+
+/// Starts a server that answers every request with [`BODY`] bytes, slowly enough for the
+/// quota to be checked along the way.
+async fn origin() -> SocketAddr {
+    let l = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+    let addr = l.local_addr().expect("local_addr");
+
+    tokio::spawn(async move {
+        while let Ok((mut s, _)) = l.accept().await {
+            tokio::spawn(async move {
+                let mut req = Vec::new();
+                while !req.ends_with(b"\r\n\r\n") {
+                    match s.read_u8().await {
+                        Ok(b) => req.push(b),
+                        Err(_) => return,
+                    }
+                }
+
+                let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {BODY}\r\n\r\n");
+                let _ = s.write_all(header.as_bytes()).await;
+                for _ in 0..BODY / 1000 {
+                    if s.write_all(&[b'x'; 1000]).await.is_err() {
+                        return;
+                    }
+                    time::sleep(Duration::from_millis(5)).await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Starts an http proxy server for the user alice with `quotas`, returning its address.
+async fn proxy(quotas: Arc<Quotas>, shutdown: Shutdown) -> SocketAddr {
+    let addr = StdListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .expect("bind");
+
+    let dialer = Dialer::new(
+        Policy::new(&PolicyOptions {
+            allow_private_dst: true,
+            ..Default::default()
+        }),
+        Router::new(Vec::new(), Action::Direct, &[]).expect("router"),
+        Resolver::new(&ResolverOptions::default()).expect("resolver"),
+        TcpOptions::default(),
+        None,
+    );
+    let auth = StaticUsers::new(["alice:secret".parse::<Credentials>().expect("credentials")]);
+    let server = HttpProxyServer::new(addr, Arc::new(dialer))
+        .auth(Arc::new(auth))
+        .quotas(quotas)
+        .shutdown(shutdown)
+        .serve();
+    tokio::spawn(server);
+
+    for _ in 0..50 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return addr;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("proxy not listening");
+}
+
+/// Requests the origin server at `origin` through the proxy at `proxy` as alice, returning
+/// whatever came back before the connection closed.
+async fn fetch(proxy: SocketAddr, origin: SocketAddr) -> Vec<u8> {
+    let mut s = TcpStream::connect(proxy).await.expect("connect");
+    let req = format!(
+        "GET http://{origin}/ HTTP/1.1\r\nHost: {origin}\r\n\
+         Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\nConnection: close\r\n\r\n"
+    );
+    s.write_all(req.as_bytes()).await.expect("write");
+
+    let mut b = Vec::new();
+    let _ = s.read_to_end(&mut b).await;
+    b
+}
+
+/// Returns the length of the body of the response `r`.
+fn body(r: &[u8]) -> usize {
+    let end = r
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("headers");
+    r.len() - end - 4
+}
@@ -0,0 +1,175 @@
+//! Runs the transparent proxy server behind real iptables rules, which takes root, iptables and
+//! the REDIRECT and TPROXY targets, so the tests are ignored by default.
+//!
+//! Run with `sudo -E cargo test --test transparent -- --ignored --test-threads 1`.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, TcpListener as StdListener, TcpStream as StdStream},
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket},
+};
+
+/// The address the clients connect from, which the rules match on so that the connections of
+/// the proxy itself aren't redirected back to it.
+const CLIENT: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 9);
+
+#[tokio::test]
+#[ignore = "needs root and iptables"]
+async fn redirect() {
+    // Dual-stack listeners see the redirected IPv4 connections from v4-mapped addresses
+    let proxy = Proxy::start("::", false).expect("proxy");
+    let dst = server(Ipv4Addr::new(127, 0, 0, 3)).await;
+    let _rule = Rule::add(
+        "nat",
+        &format!(
+            "OUTPUT -p tcp -s {CLIENT} -d {} --dport {} -j REDIRECT --to-ports {}",
+            dst.ip(),
+            dst.port(),
+            proxy.port
+        ),
+    )
+    .expect("iptables");
+
+    assert_eq!(fetch(dst).await.expect("fetch"), dst.to_string());
+}
+
+#[tokio::test]
+#[ignore = "needs root and iptables"]
+async fn tproxy() {
+    let proxy = Proxy::start("127.0.0.1", true).expect("proxy");
+    let dst = server(Ipv4Addr::new(127, 0, 0, 4)).await;
+    let _rule = Rule::add(
+        "mangle",
+        &format!(
+            "PREROUTING -p tcp -s {CLIENT} -d {} --dport {} -j TPROXY --on-ip 127.0.0.1 \
+             --on-port {}",
+            dst.ip(),
+            dst.port(),
+            proxy.port
+        ),
+    )
+    .expect("iptables");
+
+    assert_eq!(fetch(dst).await.expect("fetch"), dst.to_string());
+}
+
+/// Starts a server on `ip` that sends its own address to every client, returning the address.
+async fn server(ip: Ipv4Addr) -> SocketAddr {
+    let l = TcpListener::bind((ip, 0)).await.expect("bind");
+    let addr = l.local_addr().expect("local_addr");
+
+    tokio::spawn(async move {
+        while let Ok((mut s, _)) = l.accept().await {
+            let _ = s.write_all(addr.to_string().as_bytes()).await;
+        }
+    });
+    addr
+}
+
+/// Connects to `dst` from [`CLIENT`], returning what the server there sends.
+async fn fetch(dst: SocketAddr) -> io::Result<String> {
+    let socket = TcpSocket::new_v4()?;
+    socket.bind((CLIENT, 0).into())?;
+    let mut s = socket.connect(dst).await?;
+
+    let mut b = String::new();
+    s.read_to_string(&mut b).await?;
+    Ok(b)
+}
+
+/// A transparent proxy server in a child process.
+struct Proxy {
+    child: Child,
+    port: u16,
+}
+
+impl Proxy {
+    fn start(ip: &str, tproxy: bool) -> io::Result<Self> {
+        let port = StdListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_bubble"));
+        cmd.args([
+            "--transparent",
+            "--transparent-ip",
+            ip,
+            "--transparent-port",
+        ])
+        .arg(port.to_string())
+        .arg("--allow-private-dst")
+        .env("RUST_LOG", "error")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+        if tproxy {
+            cmd.arg("--transparent-tproxy");
+        }
+
+        let proxy = Proxy {
+            child: cmd.spawn()?,
+            port,
+        };
+
+        for _ in 0..50 {
+            if StdStream::connect(("127.0.0.1", port)).is_ok() {
+                return Ok(proxy);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "proxy not listening",
+        ))
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// An iptables rule, deleted once dropped.
+struct Rule {
+    table: &'static str,
+    spec: String,
+}
+
+impl Rule {
+    fn add(table: &'static str, spec: &str) -> io::Result<Self> {
+        let rule = Rule {
+            table,
+            spec: spec.to_string(),
+        };
+        rule.iptables("-A")?;
+        Ok(rule)
+    }
+
+    fn iptables(&self, op: &str) -> io::Result<()> {
+        let status = Command::new("iptables")
+            .args(["-t", self.table, op])
+            .args(self.spec.split(' '))
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "iptables {op} {}: {status}",
+                self.spec
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Rule {
+    fn drop(&mut self) {
+        let _ = self.iptables("-D");
+    }
+}